@@ -0,0 +1,264 @@
+// Run the proxy binary against a local S3 stub, so nothing needs real AWS credentials
+mod s3_stub;
+
+use bytes::Bytes;
+use http_body_util::{BodyExt, Empty};
+use hyper::{Request, Response, StatusCode};
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use s3_stub::{S3Stub, BUCKET};
+use std::net::TcpListener;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// A proxy process, killed when dropped
+struct Proxy {
+    child: Child,
+    port: u16,
+    admin_port: u16,
+}
+
+impl Proxy {
+    async fn start(stub: &S3Stub, vars: &[(&str, &str)]) -> Self {
+        let port = free_port();
+        let port_var = port.to_string();
+        let vars = [&[("PORT", port_var.as_str())], vars].concat();
+        Self::spawn(stub, Command::new(env!("CARGO_BIN_EXE_naru-pub-proxy")), port, &vars).await
+    }
+
+    // Run `command`, which starts the proxy with the environment set here, and wait until it answers
+    async fn spawn(stub: &S3Stub, mut command: Command, port: u16, vars: &[(&str, &str)]) -> Self {
+        let stub_addr = stub.start().await;
+        let admin_port = free_port();
+        let child = command
+            // Nothing from the developer's environment, like real credentials, leaks into the test
+            .env_clear()
+            .env("R2_BUCKET_NAME", BUCKET)
+            .env("S3_ENDPOINT_URL", format!("http://{}", stub_addr))
+            .env("S3_FORCE_PATH_STYLE", "true")
+            .env("AWS_ACCESS_KEY_ID", "test")
+            .env("AWS_SECRET_ACCESS_KEY", "test")
+            .env("AWS_EC2_METADATA_DISABLED", "true")
+            .env("ADMIN_PORT", admin_port.to_string())
+            .envs(vars.iter().copied())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("the proxy binary should start");
+        let proxy = Proxy {
+            child,
+            port,
+            admin_port,
+        };
+
+        let started = tokio::time::Instant::now();
+        loop {
+            let health = Request::get(proxy.admin_url("/_health")).body(Empty::new()).unwrap();
+            if client().request(health).await.is_ok_and(|r| r.status() == StatusCode::OK) {
+                return proxy;
+            }
+            assert!(started.elapsed() < STARTUP_TIMEOUT, "the proxy did not become healthy");
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("http://localhost:{}{}", self.port, path)
+    }
+
+    fn admin_url(&self, path: &str) -> String {
+        format!("http://localhost:{}{}", self.admin_port, path)
+    }
+
+    async fn get(&self, host: &str, path: &str, headers: &[(&str, &str)]) -> Response<Bytes> {
+        let mut request = Request::get(self.url(path)).header("host", host);
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let response = tokio::time::timeout(REQUEST_TIMEOUT, client().request(request.body(Empty::new()).unwrap()))
+            .await
+            .expect("the proxy did not answer")
+            .unwrap();
+        let (parts, body) = response.into_parts();
+        Response::from_parts(parts, body.collect().await.unwrap().to_bytes())
+    }
+}
+
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn client() -> Client<hyper_util::client::legacy::connect::HttpConnector, Empty<Bytes>> {
+    Client::builder(TokioExecutor::new()).build_http()
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+#[tokio::test]
+async fn serves_objects_from_the_bucket() {
+    let stub = S3Stub::default();
+    stub.insert("alice/index.html", "text/html", "<h1>Alice</h1>");
+    let proxy = Proxy::start(&stub, &[]).await;
+
+    let response = proxy.get("alice.naru.pub", "/", &[]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.body(), "<h1>Alice</h1>");
+    assert_eq!(response.headers()["etag"], S3Stub::etag("<h1>Alice</h1>"));
+}
+
+#[tokio::test]
+async fn builds_keys_from_the_host_and_path() {
+    let stub = S3Stub::default();
+    let proxy = Proxy::start(&stub, &[("KEY_PREFIX", "sites")]).await;
+
+    let cases = [
+        ("alice.naru.pub", "/", "sites/alice/index.html"),
+        ("alice.naru.pub:8080", "/blog/", "sites/alice/blog/index.html"),
+        ("bob.naru.pub", "/app.js", "sites/bob/app.js"),
+        ("bob.naru.pub", "/docs/a%20b.json", "sites/bob/docs/a b.json"),
+        ("localhost", "/data.json", "sites/data.json"),
+    ];
+    for (host, path, key) in cases {
+        stub.insert(key, "text/plain", key);
+        let response = proxy.get(host, path, &[]).await;
+        assert_eq!(response.status(), StatusCode::OK, "{}{}", host, path);
+        assert_eq!(response.body(), key);
+    }
+    let expected: Vec<&str> = cases.iter().map(|(_, _, key)| *key).collect();
+    assert_eq!(stub.requests(), expected);
+}
+
+#[tokio::test]
+async fn missing_objects_fall_back_then_404() {
+    let stub = S3Stub::default();
+    stub.insert("alice/legacy/index.htm", "text/html", "legacy");
+    let proxy = Proxy::start(&stub, &[]).await;
+
+    let response = proxy.get("alice.naru.pub", "/legacy/", &[]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.body(), "legacy");
+
+    let response = proxy.get("alice.naru.pub", "/missing/", &[]).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(
+        stub.requests(),
+        [
+            "alice/legacy/index.html",
+            "alice/legacy/index.htm",
+            "alice/missing/index.html",
+            "alice/missing/index.htm",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn forwards_the_stored_content_type() {
+    let stub = S3Stub::default();
+    stub.insert("alice/data.json", "application/json; charset=utf-8", "{}");
+    stub.insert("alice/app.js", "text/javascript", "1");
+    let proxy = Proxy::start(&stub, &[]).await;
+
+    let response = proxy.get("alice.naru.pub", "/data.json", &[]).await;
+    assert_eq!(response.headers()["content-type"], "application/json; charset=utf-8");
+    let response = proxy.get("alice.naru.pub", "/app.js", &[]).await;
+    assert_eq!(response.headers()["content-type"], "text/javascript");
+}
+
+#[tokio::test]
+async fn matching_etags_get_304() {
+    let stub = S3Stub::default();
+    stub.insert("alice/index.html", "text/html", "<h1>Alice</h1>");
+    let proxy = Proxy::start(&stub, &[]).await;
+    let etag = S3Stub::etag("<h1>Alice</h1>");
+
+    let response = proxy.get("alice.naru.pub", "/", &[("if-none-match", &etag)]).await;
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(response.headers()["etag"], etag.as_str());
+    assert!(response.body().is_empty());
+
+    let response = proxy.get("alice.naru.pub", "/", &[("if-none-match", "\"stale\"")]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn serves_byte_ranges() {
+    let stub = S3Stub::default();
+    stub.insert("alice/data.json", "application/json", "0123456789");
+    let proxy = Proxy::start(&stub, &[]).await;
+
+    let response = proxy.get("alice.naru.pub", "/data.json", &[("range", "bytes=2-5")]).await;
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(response.headers()["content-range"], "bytes 2-5/10");
+    assert_eq!(response.body(), "2345");
+
+    let response = proxy.get("alice.naru.pub", "/data.json", &[("range", "bytes=7-")]).await;
+    assert_eq!(response.headers()["content-range"], "bytes 7-9/10");
+    assert_eq!(response.body(), "789");
+}
+
+#[tokio::test]
+async fn answers_health_checks() {
+    let proxy = Proxy::start(&S3Stub::default(), &[]).await;
+    let response = client()
+        .request(Request::get(proxy.admin_url("/_health")).body(Empty::new()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.into_body().collect().await.unwrap().to_bytes(), "OK");
+}
+
+// Start the proxy the way systemd does under socket activation: with the listener as fd 3, and
+// LISTEN_PID naming the process, or another one for `foreign_pid`. Returns the proxy and PORT.
+#[cfg(unix)]
+async fn start_socket_activated(stub: &S3Stub, foreign_pid: bool) -> (Proxy, u16) {
+    use std::os::fd::AsRawFd;
+
+    let socket = socket2::Socket::from(TcpListener::bind("127.0.0.1:0").unwrap());
+    let port = socket.local_addr().unwrap().as_socket().unwrap().port();
+    // Inherited by the shell, which moves it to fd 3 and then becomes the proxy, keeping its PID
+    socket.set_cloexec(false).unwrap();
+    let script = r#"exec 3<&"$1"; export LISTEN_PID=${2:-$$} LISTEN_FDS=1; exec "$0""#;
+    let mut command = Command::new("/bin/sh");
+    command
+        .args(["-c", script, env!("CARGO_BIN_EXE_naru-pub-proxy")])
+        .arg(socket.as_raw_fd().to_string())
+        .arg(if foreign_pid { "1" } else { "" });
+    let bound_port = free_port();
+    let port_var = bound_port.to_string();
+    let proxy_port = if foreign_pid { bound_port } else { port };
+    let proxy = Proxy::spawn(stub, command, proxy_port, &[("PORT", &port_var)]).await;
+    drop(socket);
+    (proxy, bound_port)
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn serves_on_a_socket_from_systemd() {
+    let stub = S3Stub::default();
+    stub.insert("alice/index.html", "text/html", "<h1>Alice</h1>");
+    let (proxy, bound_port) = start_socket_activated(&stub, false).await;
+    // Only the passed socket is listened on
+    assert!(std::net::TcpStream::connect(("127.0.0.1", bound_port)).is_err());
+    let response = proxy.get("alice.naru.pub", "/", &[]).await;
+    assert_eq!(response.body(), "<h1>Alice</h1>");
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn ignores_sockets_meant_for_another_process() {
+    let stub = S3Stub::default();
+    stub.insert("alice/index.html", "text/html", "<h1>Alice</h1>");
+    // The proxy binds PORT itself instead
+    let (proxy, _) = start_socket_activated(&stub, true).await;
+    let response = proxy.get("alice.naru.pub", "/", &[]).await;
+    assert_eq!(response.body(), "<h1>Alice</h1>");
+}
+
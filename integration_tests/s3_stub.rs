@@ -0,0 +1,133 @@
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use percent_encoding::percent_decode_str;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+
+pub const BUCKET: &str = "test-bucket";
+const LAST_MODIFIED: &str = "Wed, 01 Jan 2025 00:00:00 GMT";
+
+struct Object {
+    content_type: String,
+    body: Bytes,
+}
+
+// Just enough of the S3 API for the proxy, addressed path-style: `HeadBucket`, and `GetObject`
+// and `HeadObject` with single ranges
+#[derive(Clone, Default)]
+pub struct S3Stub {
+    objects: Arc<Mutex<HashMap<String, Object>>>,
+    // Keys of every object request, in order
+    requests: Arc<Mutex<Vec<String>>>,
+}
+
+impl S3Stub {
+    pub fn insert(&self, key: &str, content_type: &str, body: &str) {
+        let object = Object {
+            content_type: content_type.to_string(),
+            body: Bytes::from(body.to_string()),
+        };
+        self.objects.lock().unwrap().insert(key.to_string(), object);
+    }
+
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    pub fn etag(body: &str) -> String {
+        format!("\"{:032x}\"", body.len())
+    }
+
+    // Serve on a free port, returning its address
+    pub async fn start(&self) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stub = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let stub = stub.clone();
+                tokio::spawn(async move {
+                    let service = service_fn(move |req| {
+                        let stub = stub.clone();
+                        async move { Ok::<_, Infallible>(stub.handle(req)) }
+                    });
+                    let _ = http1::Builder::new().serve_connection(TokioIo::new(stream), service).await;
+                });
+            }
+        });
+        addr
+    }
+
+    fn handle(&self, req: Request<hyper::body::Incoming>) -> Response<Full<Bytes>> {
+        let path = percent_decode_str(req.uri().path()).decode_utf8_lossy().into_owned();
+        let Some(key) = path.strip_prefix(&format!("/{}/", BUCKET)) else {
+            return error(StatusCode::NOT_FOUND, "NoSuchBucket");
+        };
+        // `HeadBucket`, checked once at startup
+        if key.is_empty() {
+            return Response::new(Full::new(Bytes::new()));
+        }
+        self.requests.lock().unwrap().push(key.to_string());
+
+        let objects = self.objects.lock().unwrap();
+        let Some(object) = objects.get(key) else {
+            return match *req.method() {
+                Method::HEAD => empty(StatusCode::NOT_FOUND),
+                _ => error(StatusCode::NOT_FOUND, "NoSuchKey"),
+            };
+        };
+        let builder = Response::builder()
+            .header("content-type", &object.content_type)
+            .header("etag", Self::etag(std::str::from_utf8(&object.body).unwrap()))
+            .header("last-modified", LAST_MODIFIED)
+            .header("accept-ranges", "bytes");
+        if req.method() == Method::HEAD {
+            return builder
+                .header("content-length", object.body.len())
+                .body(Full::new(Bytes::new()))
+                .unwrap();
+        }
+
+        let range = req.headers().get("range").and_then(|r| r.to_str().ok());
+        let Some((start, end)) = range.and_then(|range| parse_range(range, object.body.len())) else {
+            return builder.body(Full::new(object.body.clone())).unwrap();
+        };
+        builder
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header("content-range", format!("bytes {}-{}/{}", start, end, object.body.len()))
+            .body(Full::new(object.body.slice(start..=end)))
+            .unwrap()
+    }
+}
+
+// `bytes=<start>-<end>` or `bytes=<start>-`, within the object
+fn parse_range(range: &str, len: usize) -> Option<(usize, usize)> {
+    let (start, end) = range.strip_prefix("bytes=")?.split_once('-')?;
+    let start: usize = start.parse().ok()?;
+    let end = match end {
+        "" => len.checked_sub(1)?,
+        end => end.parse::<usize>().ok()?.min(len.checked_sub(1)?),
+    };
+    (start <= end).then_some((start, end))
+}
+
+fn error(status: StatusCode, code: &str) -> Response<Full<Bytes>> {
+    let body = format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?><Error><Code>{}</Code></Error>", code);
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/xml")
+        .body(Full::new(Bytes::from(body)))
+        .unwrap()
+}
+
+fn empty(status: StatusCode) -> Response<Full<Bytes>> {
+    Response::builder().status(status).body(Full::new(Bytes::new())).unwrap()
+}
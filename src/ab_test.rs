@@ -0,0 +1,68 @@
+use crate::config::AbTestConfig;
+use hyper::HeaderMap;
+use std::net::IpAddr;
+
+pub const COOKIE_NAME: &str = "__ab_variant";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Variant {
+    A,
+    B,
+}
+
+impl Variant {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Variant::A => "A",
+            Variant::B => "B",
+        }
+    }
+
+    pub fn prefix(self, config: &AbTestConfig) -> &str {
+        match self {
+            Variant::A => &config.prefix_a,
+            Variant::B => &config.prefix_b,
+        }
+    }
+
+    // `Set-Cookie` value pinning the client to this variant
+    pub fn cookie(self) -> String {
+        format!("{}={}; Path=/; SameSite=Lax", COOKIE_NAME, self.as_str())
+    }
+}
+
+// Pick a variant, preferring the one pinned by cookie over the client IP hash
+pub fn select_variant(config: &AbTestConfig, headers: &HeaderMap, client_ip: IpAddr) -> Variant {
+    if let Some(variant) = variant_from_cookie(headers) {
+        return variant;
+    }
+
+    let bucket = fnv1a(client_ip.to_string().as_bytes()) % 100;
+    if bucket < u64::from(config.percentage_b) {
+        Variant::B
+    } else {
+        Variant::A
+    }
+}
+
+fn variant_from_cookie(headers: &HeaderMap) -> Option<Variant> {
+    headers
+        .get_all("cookie")
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(name, _)| *name == COOKIE_NAME)
+        .and_then(|(_, value)| match value {
+            "A" => Some(Variant::A),
+            "B" => Some(Variant::B),
+            _ => None,
+        })
+}
+
+// Stable across builds and processes, unlike the std hasher
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    })
+}
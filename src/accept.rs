@@ -0,0 +1,84 @@
+use std::collections::BTreeMap;
+
+// Whether an `Accept` header allows a content type, honoring `*/*`, `type/*` and `q=0` exclusions.
+// The most specific matching range decides, as in RFC 9110.
+pub fn allows(accept: &str, content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    let Some((kind, _)) = essence.split_once('/') else {
+        return true;
+    };
+
+    let mut best: Option<(u8, bool)> = None;
+    for range in accept.split(',') {
+        let mut params = range.split(';').map(str::trim);
+        let media_range = params.next().unwrap_or_default().to_ascii_lowercase();
+        let rejected = params.any(|p| p.strip_prefix("q=").is_some_and(|q| q.parse::<f32>() == Ok(0.0)));
+        let specificity = if media_range == essence {
+            2
+        } else if media_range.strip_suffix("/*") == Some(kind) {
+            1
+        } else if media_range == "*/*" {
+            0
+        } else {
+            continue;
+        };
+        if best.is_none_or(|(best, _)| specificity > best) {
+            best = Some((specificity, !rejected));
+        }
+    }
+    best.is_some_and(|(_, accepted)| accepted)
+}
+
+// Extensions for the media types `Accept` lists by name, most preferred first. Wildcards don't
+// select a variant, so `*/*` keeps the object's usual lookup.
+pub fn preferred_extensions<'a>(accept: &str, extensions: &'a BTreeMap<String, String>) -> Vec<&'a str> {
+    let mut ranked: Vec<(f32, &str)> = accept
+        .split(',')
+        .filter_map(|range| {
+            let mut params = range.split(';').map(str::trim);
+            let media_range = params.next().unwrap_or_default().to_ascii_lowercase();
+            let q = params
+                .find_map(|p| p.strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.parse::<f32>().ok())?;
+            let extension = extensions.get(&media_range)?;
+            (q > 0.0).then_some((q, extension.as_str()))
+        })
+        .collect();
+    // Stable, so equal weights keep the client's order
+    ranked.sort_by(|a, b| b.0.total_cmp(&a.0));
+    let mut preferred: Vec<&str> = Vec::new();
+    for (_, extension) in ranked {
+        if !preferred.contains(&extension) {
+            preferred.push(extension);
+        }
+    }
+    preferred
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_matching_and_wildcard_ranges() {
+        for accept in ["application/json", "text/html, application/json;q=0.5", "application/*", "*/*", "*/*;q=0.1"] {
+            assert!(allows(accept, "application/json; charset=utf-8"), "{}", accept);
+        }
+        for accept in ["text/html", "text/*", "application/*, application/json;q=0", "*/*;q=0", ""] {
+            assert!(!allows(accept, "application/json"), "{}", accept);
+        }
+        // A more specific range overrides a wildcard either way
+        assert!(allows("application/json, */*;q=0", "application/json"));
+        assert!(!allows("*/*, image/png;q=0", "image/png"));
+    }
+
+    #[test]
+    fn prefers_named_types_by_weight() {
+        let extensions: BTreeMap<String, String> =
+            [("image/avif", "avif"), ("image/webp", "webp")].map(|(t, e)| (t.to_string(), e.to_string())).into();
+        let preferred = |accept| preferred_extensions(accept, &extensions);
+        assert_eq!(preferred("image/webp;q=0.8, image/avif, */*"), ["avif", "webp"]);
+        assert_eq!(preferred("image/webp, image/avif"), ["webp", "avif"]);
+        assert!(preferred("image/avif;q=0, image/*, */*").is_empty());
+    }
+}
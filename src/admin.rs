@@ -0,0 +1,140 @@
+use crate::metrics::METRICS;
+use crate::{finalize_headers, has_bearer_token, unauthorized, AppState};
+use anyhow::Result;
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response};
+use hyper_util::rt::TokioIo;
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tracing::error;
+
+// Body of a `POST /_cache/invalidate` request, naming full S3 keys
+#[derive(Deserialize)]
+struct InvalidateRequest {
+    #[serde(default)]
+    keys: Vec<String>,
+    // e.g. `site/` to evict a whole site
+    #[serde(default)]
+    prefixes: Vec<String>,
+}
+
+// Serve operational endpoints on the admin port, apart from site traffic
+pub async fn serve(listener: TcpListener, state: Arc<AppState>) -> Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let io = TokioIo::new(stream);
+        let state = state.clone();
+
+        tokio::task::spawn(async move {
+            if let Err(err) = http1::Builder::new()
+                .serve_connection(io, service_fn(move |req| handle_admin_request(req, state.clone())))
+                .await
+            {
+                error!("Error serving admin connection: {}", err);
+            }
+        });
+    }
+}
+
+async fn handle_admin_request(
+    req: Request<hyper::body::Incoming>,
+    state: Arc<AppState>,
+) -> Result<Response<Full<Bytes>>> {
+    let mut response = route_admin_request(req, state).await?;
+    finalize_headers(response.headers_mut());
+    Ok(response)
+}
+
+async fn route_admin_request(
+    req: Request<hyper::body::Incoming>,
+    state: Arc<AppState>,
+) -> Result<Response<Full<Bytes>>> {
+    match (req.method(), req.uri().path()) {
+        // Liveness for load balancers and orchestrators, answered without contacting S3
+        (&Method::GET, "/_health") => Ok(Response::builder()
+            .status(200)
+            .header("content-type", "text/plain")
+            .body(Full::new(Bytes::from("OK")))
+            .unwrap()),
+        (&Method::GET, "/_metrics") => Ok(Response::builder()
+            .status(200)
+            .header("content-type", "text/plain; version=0.0.4")
+            .body(Full::new(Bytes::from(METRICS.render())))
+            .unwrap()),
+        // The configuration in effect, with secrets redacted by its serializer
+        (&Method::GET, "/_config") => {
+            if let Some(denied) = check_api_key(&req, &state) {
+                return Ok(denied);
+            }
+            Ok(json_response(200, serde_json::to_value(&state.config)?))
+        }
+        // Only meant for test suites; never expose this in production
+        (&Method::POST, "/_metrics/reset") => {
+            if let Some(denied) = check_api_key(&req, &state) {
+                return Ok(denied);
+            }
+            METRICS.reset();
+            Ok(json_response(200, serde_json::json!({ "reset": true })))
+        }
+        (&Method::GET, "/_status") => {
+            if let Some(denied) = check_api_key(&req, &state) {
+                return Ok(denied);
+            }
+            Ok(json_response(200, serde_json::to_value(state.status.snapshot(&state))?))
+        }
+        (&Method::GET, "/_cache/stats") => {
+            if let Some(denied) = check_api_key(&req, &state) {
+                return Ok(denied);
+            }
+            Ok(json_response(200, serde_json::to_value(state.cache.snapshot())?))
+        }
+        (&Method::POST, "/_cache/invalidate") => {
+            if let Some(denied) = check_api_key(&req, &state) {
+                return Ok(denied);
+            }
+            let body = req.into_body().collect().await?.to_bytes();
+            let Ok(invalidate) = serde_json::from_slice::<InvalidateRequest>(&body) else {
+                return Ok(Response::builder()
+                    .status(400)
+                    .body(Full::new(Bytes::from("Bad Request")))
+                    .unwrap());
+            };
+
+            // Versions pinned with `?version=` are cached under `{key}?version=...`
+            let versioned: Vec<String> = invalidate.keys.iter().map(|key| format!("{}?version=", key)).collect();
+            let invalidated = state.cache.remove_all(&invalidate.keys)
+                + state.cache.remove_prefixes(&versioned)
+                + state.cache.remove_prefixes(&invalidate.prefixes);
+            Ok(json_response(200, serde_json::json!({ "invalidated": invalidated })))
+        }
+        _ => Ok(Response::builder()
+            .status(404)
+            .body(Full::new(Bytes::from("Not Found")))
+            .unwrap()),
+    }
+}
+
+// Admin actions require `Authorization: Bearer <ADMIN_API_KEY>`, and are disabled without a key
+fn check_api_key(req: &Request<hyper::body::Incoming>, state: &AppState) -> Option<Response<Full<Bytes>>> {
+    let Some(api_key) = &state.config.admin_api_key else {
+        return Some(
+            Response::builder()
+                .status(405)
+                .body(Full::new(Bytes::from("Method Not Allowed")))
+                .unwrap(),
+        );
+    };
+    (!has_bearer_token(req, api_key)).then(unauthorized)
+}
+
+fn json_response(status: u16, body: serde_json::Value) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(Full::new(Bytes::from(body.to_string())))
+        .unwrap()
+}
@@ -0,0 +1,86 @@
+use crate::source::{ObjectRequest, ObjectSource};
+use anyhow::{bail, Result};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+
+const CONCURRENT_REQUESTS: usize = 100;
+
+// Measure raw GetObject throughput for a single key using one shared client, read with the
+// same checksum mode and requester-pays setting as served objects
+pub async fn run(objects: Arc<dyn ObjectSource>, bucket_name: &str, key: &str) -> Result<()> {
+    // The first request pays for DNS, TCP and TLS setup; the rest should reuse pooled connections
+    let (cold_latency, _) = fetch(objects.clone(), key.to_string()).await?;
+
+    let started = Instant::now();
+    let mut tasks = JoinSet::new();
+    for _ in 0..CONCURRENT_REQUESTS {
+        tasks.spawn(fetch(objects.clone(), key.to_string()));
+    }
+
+    let mut latencies = Vec::with_capacity(CONCURRENT_REQUESTS);
+    let mut total_bytes = 0;
+    let mut failures = 0;
+    while let Some(result) = tasks.join_next().await {
+        match result? {
+            Ok((latency, bytes)) => {
+                latencies.push(latency);
+                total_bytes += bytes;
+            }
+            Err(err) => {
+                eprintln!("Request failed: {:#}", err);
+                failures += 1;
+            }
+        }
+    }
+    let elapsed = started.elapsed();
+
+    if latencies.is_empty() {
+        bail!("All {} benchmark requests failed", CONCURRENT_REQUESTS);
+    }
+    latencies.sort();
+    let mean = latencies.iter().sum::<Duration>() / latencies.len() as u32;
+    let p95 = latencies[(latencies.len() * 95).div_ceil(100) - 1];
+
+    println!("Benchmark of s3://{}/{}", bucket_name, key);
+    println!("  requests:        {} ({} failed)", CONCURRENT_REQUESTS, failures);
+    println!("  min latency:     {:?}", latencies[0]);
+    println!("  max latency:     {:?}", latencies[latencies.len() - 1]);
+    println!("  mean latency:    {:?}", mean);
+    println!("  p95 latency:     {:?}", p95);
+    println!("  requests/sec:    {:.1}", latencies.len() as f64 / elapsed.as_secs_f64());
+    println!("  bytes:           {}", total_bytes);
+    // A mean well below the cold request means connections were reused from the pool
+    println!("  cold request:    {:?}", cold_latency);
+    println!("  mean/cold ratio: {:.2}", mean.as_secs_f64() / cold_latency.as_secs_f64());
+    Ok(())
+}
+
+async fn fetch(objects: Arc<dyn ObjectSource>, key: String) -> Result<(Duration, usize)> {
+    let request = ObjectRequest {
+        key: &key,
+        version: None,
+        range: None,
+        deadline: None,
+    };
+    let started = Instant::now();
+    let resp = objects.get_object(request).await?;
+    let bytes = resp.body.collect().await?.into_bytes().len();
+    Ok((started.elapsed(), bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::MockObjectSource;
+
+    #[tokio::test]
+    async fn fetches_through_the_object_source() {
+        let objects = Arc::new(MockObjectSource::with(&[("alice/app.js", "text/javascript", "1")]));
+        run(objects.clone(), "bucket", "alice/app.js").await.unwrap();
+        assert_eq!(objects.requests().len(), CONCURRENT_REQUESTS + 1);
+
+        objects.fail_with(Some(500));
+        assert!(run(objects, "bucket", "alice/app.js").await.is_err());
+    }
+}
@@ -0,0 +1,137 @@
+use crate::metrics::METRICS;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+enum State {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    // A single probe request is let through to test whether the upstream recovered
+    HalfOpen { probe_started: Instant },
+}
+
+// Fails fast once the upstream has failed `failure_threshold` times in a row
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    cooldown: Duration,
+    state: Mutex<State>,
+}
+
+impl CircuitBreaker {
+    // A threshold of zero disables the breaker
+    pub fn new(failure_threshold: u32, cooldown: Duration) -> Self {
+        METRICS.circuit_breaker_state.set(0);
+        Self {
+            failure_threshold,
+            cooldown,
+            state: Mutex::new(State::Closed { failures: 0 }),
+        }
+    }
+
+    // Whether a request may be sent upstream right now
+    pub fn try_acquire(&self) -> bool {
+        if self.failure_threshold == 0 {
+            return true;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        match *state {
+            State::Closed { .. } => true,
+            State::Open { until } if now >= until => {
+                self.transition(&mut state, State::HalfOpen { probe_started: now });
+                true
+            }
+            State::Open { .. } => false,
+            // A probe that never reported back (e.g. the client went away) is replaced after a cooldown
+            State::HalfOpen { probe_started } if now >= probe_started + self.cooldown => {
+                *state = State::HalfOpen { probe_started: now };
+                true
+            }
+            State::HalfOpen { .. } => false,
+        }
+    }
+
+    pub fn record_success(&self) {
+        if self.failure_threshold == 0 {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        self.transition(&mut state, State::Closed { failures: 0 });
+    }
+
+    pub fn record_failure(&self) {
+        if self.failure_threshold == 0 {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let next = match *state {
+            State::Closed { failures } if failures + 1 < self.failure_threshold => State::Closed { failures: failures + 1 },
+            State::Open { until } => State::Open { until },
+            _ => {
+                warn!("Circuit breaker opened after repeated S3 failures");
+                State::Open { until: Instant::now() + self.cooldown }
+            }
+        };
+        self.transition(&mut state, next);
+    }
+
+    fn transition(&self, state: &mut State, next: State) {
+        METRICS.circuit_breaker_state.set(match next {
+            State::Closed { .. } => 0,
+            State::Open { .. } => 1,
+            State::HalfOpen { .. } => 2,
+        });
+        *state = next;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COOLDOWN: Duration = Duration::from_millis(20);
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let breaker = CircuitBreaker::new(3, COOLDOWN);
+        breaker.record_failure();
+        breaker.record_failure();
+        // A success in between starts the count over
+        breaker.record_success();
+        breaker.record_failure();
+        breaker.record_failure();
+        assert!(breaker.try_acquire());
+        breaker.record_failure();
+        assert!(!breaker.try_acquire());
+    }
+
+    #[test]
+    fn half_opens_for_a_single_probe_after_the_cooldown() {
+        let breaker = CircuitBreaker::new(1, COOLDOWN);
+        breaker.record_failure();
+        assert!(!breaker.try_acquire());
+        std::thread::sleep(COOLDOWN);
+        assert!(breaker.try_acquire());
+        assert!(!breaker.try_acquire());
+
+        // A failed probe opens it again, and a successful one closes it
+        breaker.record_failure();
+        assert!(!breaker.try_acquire());
+        std::thread::sleep(COOLDOWN);
+        assert!(breaker.try_acquire());
+        breaker.record_success();
+        assert!(breaker.try_acquire());
+        assert!(breaker.try_acquire());
+    }
+
+    #[test]
+    fn a_zero_threshold_never_opens() {
+        let breaker = CircuitBreaker::new(0, COOLDOWN);
+        for _ in 0..10 {
+            breaker.record_failure();
+        }
+        assert!(breaker.try_acquire());
+    }
+}
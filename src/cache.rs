@@ -0,0 +1,426 @@
+use crate::eviction::{EvictionNotifier, EvictionReason};
+use bytes::Bytes;
+use hyper::{HeaderMap, StatusCode};
+use lru::LruCache;
+use std::collections::HashSet;
+use std::num::NonZeroUsize;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// A cached response for an S3 key
+#[derive(Clone)]
+pub struct CachedResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+    expires_at: Instant,
+    // Carried over when the entry is refreshed, to rank the most requested keys
+    hits: u64,
+}
+
+// How a response relates to the cache, reported in `X-Cache` and request logs
+#[derive(Clone, Copy)]
+pub enum CacheStatus {
+    Hit,
+    // An expired entry served while it is refreshed, or because the refresh failed
+    Stale,
+    Miss,
+    // The response was not cacheable, or the cache is disabled
+    Bypass,
+}
+
+impl CacheStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CacheStatus::Hit => "HIT",
+            CacheStatus::Stale => "STALE",
+            CacheStatus::Miss => "MISS",
+            CacheStatus::Bypass => "BYPASS",
+        }
+    }
+}
+
+// How usable a cached entry still is
+pub enum Freshness {
+    Fresh,
+    // Expired, but may be served while a refresh runs in the background
+    Stale,
+    // Expired, and only to be served if fetching it again fails
+    StaleIfError,
+}
+
+// In-memory LRU cache of responses, keyed by S3 key
+pub struct ObjectCache {
+    entries: Option<Mutex<LruCache<String, CachedResponse>>>,
+    stale_while_revalidate: Duration,
+    stale_if_error: Duration,
+    // Keys with a background refresh in flight, so each is refreshed once
+    refreshing: Mutex<HashSet<String>>,
+    stats: CacheStats,
+    eviction_notifier: Option<EvictionNotifier>,
+}
+
+// Lookups since startup, plus per-second buckets covering the last minute
+struct CacheStats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    started: Instant,
+    // `(second since startup, hits, misses)`, indexed by the second modulo the window
+    window: Mutex<[(u64, u64, u64); HIT_RATE_WINDOW_SECS as usize]>,
+}
+
+const HIT_RATE_WINDOW_SECS: u64 = 60;
+const TOP_KEYS: usize = 10;
+
+// Body of `GET /_cache/stats`
+#[derive(Serialize)]
+pub struct CacheSnapshot {
+    entries: usize,
+    max_entries: usize,
+    total_bytes_cached: usize,
+    // The cache is bounded by entries only
+    max_bytes: Option<usize>,
+    hits_total: u64,
+    misses_total: u64,
+    // Over the last minute, or `None` without lookups in that time
+    hit_rate_percent: Option<f64>,
+    evictions_total: u64,
+    top_keys: Vec<KeyHits>,
+}
+
+#[derive(Serialize)]
+struct KeyHits {
+    key: String,
+    hits: u64,
+}
+
+impl CacheStats {
+    fn new() -> Self {
+        Self {
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            started: Instant::now(),
+            window: Mutex::new([(0, 0, 0); HIT_RATE_WINDOW_SECS as usize]),
+        }
+    }
+
+    fn record(&self, hit: bool) {
+        let counter = if hit { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        let second = self.started.elapsed().as_secs();
+        let mut window = self.window.lock().unwrap();
+        let bucket = &mut window[(second % HIT_RATE_WINDOW_SECS) as usize];
+        if bucket.0 != second {
+            *bucket = (second, 0, 0);
+        }
+        if hit {
+            bucket.1 += 1;
+        } else {
+            bucket.2 += 1;
+        }
+    }
+
+    fn hit_rate_percent(&self) -> Option<f64> {
+        let second = self.started.elapsed().as_secs();
+        let window = self.window.lock().unwrap();
+        // A lookup recorded while waiting for the lock can be in a later second
+        let (hits, misses) = window
+            .iter()
+            .filter(|(bucket, _, _)| second.saturating_sub(*bucket) < HIT_RATE_WINDOW_SECS)
+            .fold((0, 0), |(hits, misses), (_, h, m)| (hits + h, misses + m));
+        let lookups = hits + misses;
+        (lookups > 0).then(|| hits as f64 * 100.0 / lookups as f64)
+    }
+}
+
+impl ObjectCache {
+    // A cache with zero capacity is disabled
+    pub fn new(
+        max_entries: usize,
+        stale_while_revalidate: Duration,
+        stale_if_error: Duration,
+        eviction_notifier: Option<EvictionNotifier>,
+    ) -> Self {
+        Self {
+            entries: NonZeroUsize::new(max_entries).map(|n| Mutex::new(LruCache::new(n))),
+            stale_while_revalidate,
+            stale_if_error,
+            refreshing: Mutex::new(HashSet::new()),
+            stats: CacheStats::new(),
+            eviction_notifier,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.entries.is_some()
+    }
+
+    // Entries are kept past their TTL for as long as either stale window allows
+    pub fn get(&self, key: &str) -> Option<(CachedResponse, Freshness)> {
+        let mut entries = self.entries.as_ref()?.lock().unwrap();
+        let Some(response) = entries.get_mut(key) else {
+            self.stats.record(false);
+            return None;
+        };
+        let freshness = match Instant::now().checked_duration_since(response.expires_at) {
+            None => Freshness::Fresh,
+            Some(expired_for) if expired_for < self.stale_while_revalidate => Freshness::Stale,
+            Some(expired_for) if expired_for < self.stale_if_error => Freshness::StaleIfError,
+            Some(_) => {
+                entries.pop(key);
+                self.notify_eviction(key, EvictionReason::Ttl);
+                self.stats.record(false);
+                return None;
+            }
+        };
+        // An entry only usable if S3 fails still means a trip to S3
+        let hit = !matches!(freshness, Freshness::StaleIfError);
+        if hit {
+            response.hits += 1;
+        }
+        self.stats.record(hit);
+        Some((response.clone(), freshness))
+    }
+
+    // Whether a key has an entry still usable without S3, without counting a lookup or touching its recency
+    pub fn contains(&self, key: &str) -> bool {
+        let Some(entries) = &self.entries else {
+            return false;
+        };
+        let entries = entries.lock().unwrap();
+        entries
+            .peek(key)
+            .is_some_and(|response| response.expires_at + self.stale_while_revalidate > Instant::now())
+    }
+
+    // Claim a key's background refresh, returning false if one is already running
+    pub fn start_refresh(&self, key: &str) -> bool {
+        self.refreshing.lock().unwrap().insert(key.to_string())
+    }
+
+    pub fn finish_refresh(&self, key: &str) {
+        self.refreshing.lock().unwrap().remove(key);
+    }
+
+    pub fn insert(&self, key: String, status: StatusCode, headers: HeaderMap, body: Bytes, ttl: Duration) {
+        if let Some(entries) = &self.entries {
+            let mut entries = entries.lock().unwrap();
+            let response = CachedResponse {
+                status,
+                headers,
+                body,
+                expires_at: Instant::now() + ttl,
+                hits: entries.peek(&key).map_or(0, |existing| existing.hits),
+            };
+            // Replacing a key's entry isn't an eviction
+            if let Some((evicted, _)) = entries.push(key.clone(), response).filter(|(evicted, _)| *evicted != key) {
+                self.stats.evictions.fetch_add(1, Ordering::Relaxed);
+                self.notify_eviction(&evicted, EvictionReason::Lru);
+            }
+        }
+    }
+
+    // Drop entries past both stale windows, which would otherwise sit in memory until requested
+    // again or pushed out. The cache is walked from its least recently used end in batches, with
+    // the lock released between them so requests waiting on it are never held up for long. An
+    // entry promoted by a request mid-sweep may be skipped until the next sweep.
+    pub fn sweep(&self) -> usize {
+        const BATCH: usize = 256;
+        let Some(entries) = &self.entries else {
+            return 0;
+        };
+        let retention = self.stale_while_revalidate.max(self.stale_if_error);
+
+        // Live entries already walked past, which expired ones removed behind them don't shift
+        let mut kept = 0;
+        let mut removed = 0;
+        loop {
+            let mut entries = entries.lock().unwrap();
+            let now = Instant::now();
+            let mut walked = 0;
+            let expired: Vec<String> = entries
+                .iter()
+                .rev()
+                .skip(kept)
+                .take(BATCH)
+                .inspect(|_| walked += 1)
+                .filter(|(_, response)| response.expires_at + retention <= now)
+                .map(|(key, _)| key.clone())
+                .collect();
+            for key in &expired {
+                entries.pop(key);
+                self.notify_eviction(key, EvictionReason::Ttl);
+            }
+            kept += walked - expired.len();
+            removed += expired.len();
+            if walked < BATCH {
+                return removed;
+            }
+        }
+    }
+
+    // Purges aren't reported, since whoever purged already knows
+    fn notify_eviction(&self, key: &str, reason: EvictionReason) {
+        if let Some(notifier) = &self.eviction_notifier {
+            notifier.notify(key, reason);
+        }
+    }
+
+    // Evict all given keys under a single lock, returning how many were present
+    pub fn remove_all(&self, keys: &[String]) -> usize {
+        let Some(entries) = &self.entries else {
+            return 0;
+        };
+        let mut entries = entries.lock().unwrap();
+        keys.iter().filter(|key| entries.pop(key.as_str()).is_some()).count()
+    }
+
+    // Evict every key starting with one of the prefixes, returning how many were present
+    pub fn remove_prefixes(&self, prefixes: &[String]) -> usize {
+        let Some(entries) = &self.entries else {
+            return 0;
+        };
+        let mut entries = entries.lock().unwrap();
+        let matching: Vec<String> = entries
+            .iter()
+            .map(|(key, _)| key)
+            .filter(|key| prefixes.iter().any(|prefix| key.starts_with(prefix.as_str())))
+            .cloned()
+            .collect();
+        for key in &matching {
+            entries.pop(key);
+        }
+        matching.len()
+    }
+
+    // Over the last minute, or `None` without lookups in that time
+    pub fn hit_rate_percent(&self) -> Option<f64> {
+        self.stats.hit_rate_percent()
+    }
+
+    pub fn snapshot(&self) -> CacheSnapshot {
+        let (entries, max_entries, total_bytes_cached, top_keys) = match &self.entries {
+            Some(entries) => {
+                let entries = entries.lock().unwrap();
+                let total_bytes_cached = entries.iter().map(|(_, response)| response.body.len()).sum();
+                let mut top_keys: Vec<KeyHits> = entries
+                    .iter()
+                    .filter(|(_, response)| response.hits > 0)
+                    .map(|(key, response)| KeyHits {
+                        key: key.clone(),
+                        hits: response.hits,
+                    })
+                    .collect();
+                top_keys.sort_by(|a, b| b.hits.cmp(&a.hits).then_with(|| a.key.cmp(&b.key)));
+                top_keys.truncate(TOP_KEYS);
+                (entries.len(), entries.cap().get(), total_bytes_cached, top_keys)
+            }
+            None => (0, 0, 0, Vec::new()),
+        };
+        CacheSnapshot {
+            entries,
+            max_entries,
+            total_bytes_cached,
+            max_bytes: None,
+            hits_total: self.stats.hits.load(Ordering::Relaxed),
+            misses_total: self.stats.misses.load(Ordering::Relaxed),
+            hit_rate_percent: self.stats.hit_rate_percent(),
+            evictions_total: self.stats.evictions.load(Ordering::Relaxed),
+            top_keys,
+        }
+    }
+}
+
+// TTL allowed by an upstream `Cache-Control` value, or `None` if it forbids caching
+pub fn ttl_for(cache_control: Option<&str>, default_ttl: Duration) -> Option<Duration> {
+    let Some(cache_control) = cache_control else {
+        return Some(default_ttl);
+    };
+
+    let mut ttl = default_ttl;
+    for directive in cache_control.split(',').map(|d| d.trim().to_ascii_lowercase()) {
+        if directive == "no-store" || directive == "private" {
+            return None;
+        }
+        if let Some(max_age) = directive.strip_prefix("max-age=") {
+            if let Ok(secs) = max_age.trim_matches('"').parse() {
+                ttl = Duration::from_secs(secs);
+            }
+        }
+    }
+    Some(ttl)
+}
+
+// Lower any `max-age` above the ceiling. Values within it are returned exactly as they were.
+pub fn clamp_max_age(cache_control: &str, ceiling: u64) -> String {
+    let exceeds = |directive: &str| match directive.split_once('=') {
+        Some((name, value)) if name.trim().eq_ignore_ascii_case("max-age") => {
+            value.trim().trim_matches('"').parse::<u64>().is_ok_and(|max_age| max_age > ceiling)
+        }
+        _ => false,
+    };
+    if !cache_control.split(',').any(exceeds) {
+        return cache_control.to_string();
+    }
+    cache_control
+        .split(',')
+        .map(|directive| match exceeds(directive) {
+            true => format!("max-age={}", ceiling),
+            false => directive.trim().to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hit_rate_counts_lookups_in_the_window() {
+        let stats = CacheStats::new();
+        assert_eq!(stats.hit_rate_percent(), None);
+        stats.record(true);
+        stats.record(true);
+        stats.record(true);
+        stats.record(false);
+        assert_eq!(stats.hit_rate_percent(), Some(75.0));
+    }
+
+    #[test]
+    fn hit_rate_tolerates_buckets_newer_than_the_clock() {
+        let stats = CacheStats::new();
+        // As if recorded in a later second than the rate is read at
+        stats.window.lock().unwrap()[1] = (1, 1, 0);
+        assert_eq!(stats.hit_rate_percent(), Some(100.0));
+    }
+
+    #[test]
+    fn sweep_removes_only_expired_entries_across_batches() {
+        let cache = ObjectCache::new(1000, Duration::ZERO, Duration::ZERO, None);
+        for i in 0..600 {
+            let ttl = if i % 2 == 0 { Duration::ZERO } else { Duration::from_secs(3600) };
+            cache.insert(format!("key{}", i), StatusCode::OK, HeaderMap::new(), Bytes::new(), ttl);
+        }
+        assert_eq!(cache.sweep(), 300);
+        assert_eq!(cache.sweep(), 0);
+        let entries = cache.entries.as_ref().unwrap().lock().unwrap();
+        assert_eq!(entries.len(), 300);
+        assert!(entries.iter().all(|(key, _)| key.trim_start_matches("key").parse::<u32>().unwrap() % 2 == 1));
+    }
+
+    #[test]
+    fn clamps_only_max_ages_above_the_ceiling() {
+        assert_eq!(clamp_max_age("public, max-age=31536000, immutable", 3600), "public, max-age=3600, immutable");
+        assert_eq!(clamp_max_age("Max-Age=\"7200\"", 3600), "max-age=3600");
+        // Left exactly as stored, spacing included
+        for unchanged in ["public,max-age=60", "max-age=3600", "no-store", "s-maxage=86400", "max-age=soon"] {
+            assert_eq!(clamp_max_age(unchanged, 3600), unchanged);
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use clap::{ArgAction, Command, ValueEnum, ValueHint};
+
+// Shells `--generate-completion` can write a script for
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+    Powershell,
+}
+
+// A long flag of the CLI, as offered for completion
+struct Flag {
+    long: String,
+    help: String,
+    takes_value: bool,
+    is_path: bool,
+    values: Vec<String>,
+}
+
+// Build the completion script straight from the clap definition, so new flags are always covered
+pub fn generate(shell: Shell, mut command: Command) -> String {
+    // Building adds `--help` and `--version`
+    command.build();
+    let name = command.get_name().to_string();
+    let flags: Vec<Flag> = command
+        .get_arguments()
+        .filter_map(|arg| {
+            let long = arg.get_long()?;
+            let takes_value = !matches!(
+                arg.get_action(),
+                ArgAction::SetTrue | ArgAction::SetFalse | ArgAction::Help | ArgAction::Version | ArgAction::Count
+            );
+            Some(Flag {
+                long: long.to_string(),
+                help: arg.get_help().map(|help| help.to_string()).unwrap_or_default(),
+                takes_value,
+                is_path: arg.get_value_hint() == ValueHint::FilePath
+                    || arg.get_value_names().is_some_and(|names| names.iter().any(|n| n == "PATH")),
+                values: arg
+                    .get_possible_values()
+                    .iter()
+                    .filter(|value| !value.is_hide_set())
+                    .map(|value| value.get_name().to_string())
+                    .collect(),
+            })
+        })
+        .collect();
+
+    match shell {
+        Shell::Bash => bash(&name, &flags),
+        Shell::Zsh => zsh(&name, &flags),
+        Shell::Fish => fish(&name, &flags),
+        Shell::Powershell => powershell(&name, &flags),
+    }
+}
+
+fn function_name(name: &str) -> String {
+    format!("_{}", name.replace('-', "_"))
+}
+
+fn bash(name: &str, flags: &[Flag]) -> String {
+    let all: Vec<String> = flags.iter().map(|flag| format!("--{}", flag.long)).collect();
+    let mut cases = String::new();
+    for flag in flags.iter().filter(|flag| flag.takes_value) {
+        let reply = if !flag.values.is_empty() {
+            format!("COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))", flag.values.join(" "))
+        } else if flag.is_path {
+            "COMPREPLY=($(compgen -f -- \"$cur\"))".to_string()
+        } else {
+            "COMPREPLY=()".to_string()
+        };
+        cases.push_str(&format!("        --{})\n            {}\n            return 0\n            ;;\n", flag.long, reply));
+    }
+    let function = function_name(name);
+    format!(
+        "{function}() {{\n    local cur=\"${{COMP_WORDS[COMP_CWORD]}}\" prev=\"${{COMP_WORDS[COMP_CWORD-1]}}\"\n    case \"$prev\" in\n{cases}    esac\n    COMPREPLY=($(compgen -W \"{all}\" -- \"$cur\"))\n}}\ncomplete -F {function} -o bashdefault -o default {name}\n",
+        all = all.join(" "),
+    )
+}
+
+fn zsh(name: &str, flags: &[Flag]) -> String {
+    let mut specs = String::new();
+    for flag in flags {
+        let help = flag.help.replace('\'', "'\\''").replace('[', "\\[").replace(']', "\\]");
+        let action = if !flag.values.is_empty() {
+            format!(":value:({})", flag.values.join(" "))
+        } else if flag.is_path {
+            ":path:_files".to_string()
+        } else if flag.takes_value {
+            ":value: ".to_string()
+        } else {
+            String::new()
+        };
+        specs.push_str(&format!(" \\\n    '--{}[{}]{}'", flag.long, help, action));
+    }
+    let function = function_name(name);
+    format!("#compdef {name}\n\n{function}() {{\n  _arguments -s{specs}\n}}\n\n{function} \"$@\"\n")
+}
+
+fn fish(name: &str, flags: &[Flag]) -> String {
+    let mut script = String::new();
+    for flag in flags {
+        let mut line = format!("complete -c {} -l {}", name, flag.long);
+        if !flag.help.is_empty() {
+            line.push_str(&format!(" -d '{}'", flag.help.replace('\\', "\\\\").replace('\'', "\\'")));
+        }
+        if !flag.values.is_empty() {
+            line.push_str(&format!(" -r -f -a '{}'", flag.values.join(" ")));
+        } else if flag.is_path {
+            line.push_str(" -r -F");
+        } else if flag.takes_value {
+            line.push_str(" -r -f");
+        }
+        script.push_str(&line);
+        script.push('\n');
+    }
+    script
+}
+
+fn powershell(name: &str, flags: &[Flag]) -> String {
+    let mut results = String::new();
+    for flag in flags {
+        let help = if flag.help.is_empty() { &flag.long } else { &flag.help };
+        results.push_str(&format!(
+            "        [CompletionResult]::new('--{long}', '--{long}', [CompletionResultType]::ParameterName, '{help}')\n",
+            long = flag.long,
+            help = help.replace('\'', "''"),
+        ));
+    }
+    format!(
+        "using namespace System.Management.Automation\n\nRegister-ArgumentCompleter -Native -CommandName '{name}' -ScriptBlock {{\n    param($wordToComplete, $commandAst, $cursorPosition)\n    @(\n{results}    ) | Where-Object {{ $_.CompletionText -like \"$wordToComplete*\" }}\n}}\n"
+    )
+}
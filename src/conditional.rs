@@ -0,0 +1,98 @@
+use aws_sdk_s3::primitives::{DateTime, DateTimeFormat};
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::{HeaderMap, Response, StatusCode};
+
+// Answer a client whose cached copy still matches with `304 Not Modified`. `If-None-Match` takes
+// precedence over `If-Modified-Since`, and ETags compare weakly, as caches revalidate.
+pub fn apply(request_headers: &HeaderMap, response: Response<Full<Bytes>>) -> Response<Full<Bytes>> {
+    if response.status() != StatusCode::OK || !is_unmodified(request_headers, response.headers()) {
+        return response;
+    }
+    let (mut parts, _) = response.into_parts();
+    parts.status = StatusCode::NOT_MODIFIED;
+    parts.headers.remove("content-length");
+    Response::from_parts(parts, Full::new(Bytes::new()))
+}
+
+fn is_unmodified(request_headers: &HeaderMap, headers: &HeaderMap) -> bool {
+    if let Some(if_none_match) = request_headers.get("if-none-match").and_then(|v| v.to_str().ok()) {
+        let Some(etag) = headers.get("etag").and_then(|e| e.to_str().ok()) else {
+            return false;
+        };
+        return if_none_match
+            .split(',')
+            .map(str::trim)
+            .any(|candidate| candidate == "*" || opaque_tag(candidate) == opaque_tag(etag));
+    }
+
+    let since = request_headers
+        .get("if-modified-since")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| DateTime::from_str(v.trim(), DateTimeFormat::HttpDate).ok());
+    let last_modified = headers
+        .get("last-modified")
+        .and_then(|lm| lm.to_str().ok())
+        .and_then(|lm| DateTime::from_str(lm, DateTimeFormat::HttpDate).ok());
+    match (since, last_modified) {
+        (Some(since), Some(last_modified)) => last_modified.secs() <= since.secs(),
+        _ => false,
+    }
+}
+
+// `"abc"` for both `W/"abc"` and `"abc"`
+fn opaque_tag(etag: &str) -> &str {
+    etag.trim_start_matches("W/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    fn response(etag: &str, last_modified: &str) -> Response<Full<Bytes>> {
+        Response::builder()
+            .header("etag", etag)
+            .header("last-modified", last_modified)
+            .header("content-length", "5")
+            .body(Full::new(Bytes::from("hello")))
+            .unwrap()
+    }
+
+    fn request(name: &'static str, value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn matching_etags_are_not_modified() {
+        let date = "Wed, 01 Jan 2025 00:00:00 GMT";
+        for if_none_match in ["\"abc\"", "W/\"abc\"", "\"x\", \"abc\"", "*"] {
+            let headers = request("if-none-match", if_none_match);
+            let response = apply(&headers, response("\"abc\"", date));
+            assert_eq!(response.status(), StatusCode::NOT_MODIFIED, "{}", if_none_match);
+            assert!(!response.headers().contains_key("content-length"));
+            assert_eq!(response.headers()["etag"], "\"abc\"");
+        }
+        let response = apply(&request("if-none-match", "\"other\""), response("\"abc\"", date));
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn if_modified_since_compares_by_the_second() {
+        let date = "Wed, 01 Jan 2025 00:00:00 GMT";
+        let headers = request("if-modified-since", "Wed, 01 Jan 2025 00:00:00 GMT");
+        assert_eq!(apply(&headers, response("\"abc\"", date)).status(), StatusCode::NOT_MODIFIED);
+        let headers = request("if-modified-since", "Tue, 31 Dec 2024 23:59:59 GMT");
+        assert_eq!(apply(&headers, response("\"abc\"", date)).status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn if_none_match_takes_precedence() {
+        let mut headers = request("if-none-match", "\"other\"");
+        headers.insert("if-modified-since", HeaderValue::from_static("Wed, 01 Jan 2025 00:00:00 GMT"));
+        let response = apply(&headers, response("\"abc\"", "Wed, 01 Jan 2025 00:00:00 GMT"));
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,1023 @@
+use crate::rules;
+use anyhow::{anyhow, bail, Context, Result};
+use aws_sdk_s3::primitives::{DateTime, DateTimeFormat};
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::StatusCode;
+use ipnet::IpNet;
+use regex_lite::Regex;
+use serde::{Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::str::FromStr;
+
+// Configuration struct
+#[derive(Serialize)]
+pub struct Config {
+    pub bucket_name: String,
+    // Prepended to every key: `{KEY_PREFIX}/{subdomain}/{path}`
+    pub key_prefix: String,
+    // Single-site mode: keys are just `{path}`, so use KEY_PREFIX to select the site's prefix
+    pub ignore_subdomain: bool,
+    pub fixed_key_prefix: Option<String>,
+    // Site prefix tried for objects missing from the requested site, e.g. shared JS/CSS libraries.
+    // Only SERVED_EXTENSIONS are looked up at all, so sharing styles or fonts needs `css` or `woff2` listed.
+    pub shared_assets_prefix: Option<String>,
+    pub require_subdomain: bool,
+    // A host serving a single site from `{KEY_PREFIX}/{path}`, like `www.example.com`, whose labels
+    // aren't taken as a subdomain
+    pub custom_domain: Option<String>,
+    // Hosts served as if they had no subdomain, in addition to single labels and IP addresses
+    pub local_hosts: Vec<String>,
+    // Base domains sites are served under, e.g. `example.com`. A base domain itself is the apex.
+    pub allowed_domains: Vec<String>,
+    pub subdomain_mode: SubdomainMode,
+    // Joins the labels of a multi-level subdomain in full mode: `-` or `/`
+    pub subdomain_separator: String,
+    pub clean_urls: Vec<CleanUrl>,
+    // Names tried in order for a directory, e.g. `index.htm` for legacy sites
+    pub index_documents: Vec<String>,
+    // Serve each site's localized copy from a prefix within it, chosen by `Accept-Language`
+    pub language_routing: bool,
+    // Language tags to prefixes, e.g. `LANGUAGE_ROUTES={"en": "en", "fr": "fr", "pt-br": "pt-br"}`
+    pub language_routes: BTreeMap<String, String>,
+    pub trailing_slash_files: TrailingSlashFiles,
+    // Redirect `/foo/index.html` to `/foo/`
+    pub strip_index_html: bool,
+    // Applied in order to every derived key, each replacing its first match
+    pub key_rewrites: Vec<KeyRewrite>,
+    // Extensions fetched from S3 and served by the proxy, or `*` for all. Anything else is
+    // redirected to the public bucket, so that features working on bodies and headers (ranges,
+    // dispositions, type overrides, shared assets) only apply to extensions listed here.
+    // e.g. `SERVED_EXTENSIONS=html,htm,js,json,css,mp4` to also serve styles and seekable video.
+    pub served_extensions: Vec<String>,
+    // `None` with S3_TRANSFER_ACCELERATION, when the SDK resolves the accelerated endpoint itself
+    pub endpoint_url: Option<String>,
+    // Route requests through AWS edge locations. Only AWS S3 offers this: R2, MinIO,
+    // Wasabi and Backblaze B2 have no accelerated endpoint, and R2 is already served from Cloudflare's edge.
+    pub transfer_acceleration: bool,
+    pub force_path_style: bool,
+    pub region: String,
+    pub stalled_stream_grace_period_secs: u64,
+    // Credential modes:
+    // - static keys: AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY (e.g. an R2 API token), plus
+    //   AWS_SESSION_TOKEN and optionally AWS_CREDENTIAL_EXPIRATION for temporary STS credentials
+    // - web identity: AWS_ROLE_ARN and AWS_WEB_IDENTITY_TOKEN_FILE (e.g. EKS IRSA)
+    // - profile: AWS_PROFILE with ~/.aws/config and ~/.aws/credentials
+    // - container or instance role: ECS task role or EC2 instance metadata
+    // Without static keys, the SDK's default provider chain tries the others in that order.
+    #[serde(serialize_with = "redact_optional")]
+    pub access_key_id: Option<String>,
+    #[serde(serialize_with = "redact_optional")]
+    pub secret_access_key: Option<String>,
+    #[serde(serialize_with = "redact_optional")]
+    pub session_token: Option<String>,
+    // RFC 3339, e.g. `2025-01-01T12:00:00Z`
+    pub credential_expiration: Option<String>,
+    pub port: u16,
+    pub admin_port: Option<u16>,
+    #[serde(serialize_with = "redact_optional")]
+    pub admin_api_key: Option<String>,
+    pub cache_max_entries: usize,
+    pub cache_ttl_secs: u64,
+    // Ceiling on TTLs from upstream `max-age`, e.g. for objects uploaded with a year-long cache
+    pub cache_max_ttl_secs: Option<u64>,
+    // How often expired entries are swept from memory, or 0 to only drop them when requested
+    pub cache_sweep_interval_secs: u64,
+    // How long after expiry an entry is served while it is refreshed in the background
+    pub cache_stale_while_revalidate_secs: u64,
+    // How long after expiry an entry is served when S3 fails to return a fresh one
+    pub cache_stale_if_error_secs: u64,
+    // Sent `{"event":"evict","key":...,"reason":"ttl"|"lru"}` for every entry the cache drops on
+    // its own. Plain HTTP only, e.g. a local sidecar that purges the CDN.
+    pub eviction_webhook_url: Option<String>,
+    pub cacheable_statuses: Vec<u16>,
+    pub cache_status_header: bool,
+    // Origins allowed to read responses cross-site, or `*` for any. Empty disables CORS.
+    pub cors_allowed_origins: Vec<String>,
+    pub cors_allow_credentials: bool,
+    pub cors_expose_headers: Vec<String>,
+    #[serde(serialize_with = "redact_optional")]
+    pub purge_api_key: Option<String>,
+    pub enable_write_methods: bool,
+    #[serde(serialize_with = "redact_optional")]
+    pub write_api_key: Option<String>,
+    // Writes are only allowed below this site-relative path, and not at all without it
+    pub write_path_prefix: Option<String>,
+    pub enable_echo_endpoint: bool,
+    #[serde(serialize_with = "redact_optional")]
+    pub echo_api_key: Option<String>,
+    pub listen_backlog: i32,
+    pub reuse_address: bool,
+    pub reuse_port: bool,
+    pub ab_test: Option<AbTestConfig>,
+    pub apex_response: Option<ApexResponse>,
+    // Template read from the NOT_FOUND_PAGE file
+    pub not_found_page: Option<String>,
+    // Site-relative documents served for error statuses, keyed by code (`403`) or class (`5xx`)
+    pub error_pages: BTreeMap<String, String>,
+    // Client statuses for S3 errors, keyed by error code (`AccessDenied`) or upstream status (`403`).
+    // Unlisted errors are masked as 404, so clients can't tell a forbidden key from a missing one.
+    // Exposing statuses helps debugging, but lets anyone probe which keys exist.
+    pub upstream_error_statuses: BTreeMap<String, u16>,
+    // Take the host from `Forwarded` or `X-Forwarded-Host` and the scheme from `Forwarded` or
+    // `X-Forwarded-Proto`, preferring `Forwarded`
+    pub trust_forwarded_host: bool,
+    // Peers whose forwarding headers are trusted. The client IP is only taken from `Forwarded` or
+    // `X-Forwarded-For` when the peer is listed.
+    pub trusted_proxy_cidrs: Vec<IpNet>,
+    pub verify_checksums: bool,
+    pub request_payer: bool,
+    pub allow_version_query: bool,
+    // Both must also be in SERVED_EXTENSIONS, since redirected objects never get the header
+    pub force_download_extensions: Vec<String>,
+    // Shown in the browser even for types it would otherwise download, e.g. `pdf`
+    pub inline_extensions: Vec<String>,
+    pub download_filename: DownloadFilename,
+    // Serve `{key}.br` in place of `{key}` to clients that accept Brotli
+    pub precompressed_brotli: bool,
+    pub emit_digest_header: bool,
+    // Bound on the headers taken from object metadata, which an upload can make arbitrarily large
+    pub max_response_header_size_bytes: usize,
+    // Send a SHA-256 of the body as an `X-Content-SHA256` trailer to clients with `TE: trailers`
+    pub digest_trailer: bool,
+    // Guess a missing `Content-Type` from the body's leading bytes. Off by default, since
+    // a sniffed type can make a browser treat an upload as something its owner didn't intend.
+    pub sniff_content_type: bool,
+    // Forced types for mislabeled objects, by exact S3 key or a glob like `*.wasm`. Their
+    // extensions must be in SERVED_EXTENSIONS, since redirected objects keep their stored type.
+    pub content_type_overrides: BTreeMap<String, String>,
+    // Answer 406 when the object's type doesn't satisfy `Accept`. Off by default, since browsers
+    // request subresources with narrow `Accept` headers that static hosting normally ignores.
+    pub enforce_accept: bool,
+    // Serve `/data` as `data.json` or `data.csv` depending on `Accept`, from media types to extensions
+    pub content_negotiation: bool,
+    pub content_negotiation_types: BTreeMap<String, String>,
+    // Make redirects to a path on the site absolute, with the client's scheme and host. Default
+    // ports are left out either way.
+    pub absolute_redirects: bool,
+    pub preload_links: Vec<PathRule>,
+    // e.g. `Cache-Tag` for Cloudflare or `Surrogate-Key` for Fastly
+    pub cache_tag_header: Option<String>,
+    pub cache_tags: Vec<PathRule>,
+    // `X-Robots-Tag` for every response, e.g. `noindex, nofollow`, with per-path overrides
+    pub x_robots_tag: Option<String>,
+    pub x_robots_tag_rules: Vec<PathRule>,
+    // Takes precedence over both when STAGING is set, so preview deployments stay out of search results
+    pub x_robots_tag_staging: Option<String>,
+    pub staging: bool,
+    // CDN TTLs set apart from the browser's `Cache-Control`
+    pub surrogate_control: SiteHeader,
+    pub cdn_cache_control: SiteHeader,
+    // Warm the cache with scripts and styles referenced by served HTML pages
+    pub prefetch_linked_assets: bool,
+    pub prefetch_concurrency: usize,
+    pub html_inject_after_opening_head: Option<String>,
+    pub html_inject_before_closing_body: Option<String>,
+    // What becomes of the stored object's ETag when the served body is rewritten
+    pub transformed_etag: TransformedEtag,
+    // Filenames matching this carry a content hash and are served as immutable, HTML excepted. The match is
+    // unanchored, so anchor it to the hash segment, e.g. `\.[a-f0-9]{8,}\.` for `app.abcd1234.js`
+    #[serde(serialize_with = "serialize_regex")]
+    pub immutable_asset_pattern: Regex,
+    // Ceiling for `max-age` in object metadata, e.g. to undo an accidental year-long cache on HTML
+    pub max_cache_control_age: Option<u64>,
+    pub log_sample_rate: f64,
+    // Requests that take longer are logged as a warning, even when sampled out
+    pub slow_request_threshold_ms: Option<u64>,
+    pub s3_max_concurrent_requests: usize,
+    // `Retry-After` sent when S3 throttles a request, or when no S3 permit frees up in time
+    pub throttle_retry_after_secs: u64,
+    // How long a request queues for an S3 permit before a 503, or 0 to answer 503 at once
+    pub s3_queue_timeout_ms: u64,
+    // Requests still unanswered after this get a 504, and their S3 calls are aborted
+    pub request_timeout_ms: Option<u64>,
+    pub circuit_breaker_threshold: u32,
+    pub circuit_breaker_cooldown_secs: u64,
+}
+
+// How an extension-less path like `/about` maps to an object
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CleanUrl {
+    // `about.html`
+    Html,
+    // `about/index.html`
+    Index,
+}
+
+// How a file path with a trailing slash like `/style.css/` is served.
+// Directories with a dot in their name, like `/v1.2/`, look the same, hence the default.
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TrailingSlashFiles {
+    // `style.css/index.html`, as for any other directory
+    Index,
+    // `style.css`
+    Strip,
+    // A 404 without looking anything up
+    NotFound,
+}
+
+// How a host under one of ALLOWED_DOMAINS maps to a site
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SubdomainMode {
+    // `staging.app.example.com` is the `staging` site
+    FirstLabel,
+    // `staging.app.example.com` is the `staging-app` site, with the configured separator
+    Full,
+}
+
+// Filename offered in `Content-Disposition`
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DownloadFilename {
+    // The last path segment of the served object
+    Segment,
+    // No filename, leaving the choice to the browser
+    None,
+}
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransformedEtag {
+    // `W/` before the stored ETag, so caches can still revalidate but ranges aren't resumed with it
+    Weak,
+    // A strong ETag from the SHA-256 of the rewritten body, at the cost of hashing every response
+    Recompute,
+}
+
+// Response for apex requests (empty subdomain) that match no object
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ApexResponse {
+    Redirect(String),
+    Message(String),
+}
+
+// Values that apply to objects whose path matches a glob like `blog/*.html`
+#[derive(Serialize)]
+pub struct PathRule {
+    // Limits the rule to one site, written as `{subdomain}:{pattern}`
+    pub subdomain: Option<String>,
+    pub pattern: String,
+    pub values: Vec<String>,
+}
+
+// A header value sent on every successful response, unless the site has its own
+#[derive(Serialize)]
+pub struct SiteHeader {
+    pub default: Option<String>,
+    pub sites: BTreeMap<String, String>,
+}
+
+// A regex replacement on object keys, where the replacement can use `$1` or `${name}` groups
+#[derive(Serialize)]
+pub struct KeyRewrite {
+    #[serde(serialize_with = "serialize_regex")]
+    pub pattern: Regex,
+    pub replacement: String,
+}
+
+// Percentage-based routing between two S3 key prefixes
+#[derive(Serialize)]
+pub struct AbTestConfig {
+    pub prefix_a: String,
+    pub prefix_b: String,
+    pub percentage_b: u8,
+}
+
+impl Config {
+    // Initialize configuration from environment variables
+    pub fn from_env() -> Result<Self> {
+        let transfer_acceleration = parse_env("S3_TRANSFER_ACCELERATION", "false", "true or false")?;
+        let config = Config {
+            bucket_name: required_env("R2_BUCKET_NAME")?,
+            key_prefix: optional_env("KEY_PREFIX").unwrap_or_default().trim_matches('/').to_string(),
+            ignore_subdomain: parse_env("IGNORE_SUBDOMAIN", "false", "true or false")?,
+            fixed_key_prefix: optional_env("FIXED_KEY_PREFIX").map(|p| p.trim_matches('/').to_string()),
+            shared_assets_prefix: optional_env("SHARED_ASSETS_PREFIX").map(|p| p.trim_matches('/').to_string()),
+            local_hosts: parse_list_env("LOCAL_HOSTS", "localhost", "a comma-separated list of hostnames")?,
+            allowed_domains: parse_list_env("ALLOWED_DOMAINS", "", "a comma-separated list of domains")?
+                .into_iter()
+                .map(|domain: String| domain.trim_matches('.').to_ascii_lowercase())
+                .collect(),
+            subdomain_mode: parse_env("SUBDOMAIN_MODE", "first-label", "first-label or full")?,
+            subdomain_separator: parse_env("SUBDOMAIN_SEPARATOR", "-", "- or /")?,
+            require_subdomain: parse_env("REQUIRE_SUBDOMAIN", "false", "true or false")?,
+            custom_domain: optional_env("CUSTOM_DOMAIN").map(|domain| domain.trim_matches('.').to_ascii_lowercase()),
+            key_rewrites: KeyRewrite::list_from_env()?,
+            served_extensions: parse_list_env("SERVED_EXTENSIONS", "html,htm,js,json", "a comma-separated list of extensions")?
+                .into_iter()
+                .map(|ext: String| ext.trim_start_matches('.').to_ascii_lowercase())
+                .collect(),
+            strip_index_html: parse_env("STRIP_INDEX_HTML", "false", "true or false")?,
+            trailing_slash_files: parse_env("TRAILING_SLASH_FILES", "index", "index, strip or not-found")?,
+            language_routing: parse_env("LANGUAGE_ROUTING", "false", "true or false")?,
+            language_routes: language_routes_from_env()?,
+            clean_urls: parse_list_env("CLEAN_URLS", "index", "a comma-separated list of html and index")?,
+            index_documents: parse_list_env("INDEX_DOCUMENTS", "index.html,index.htm", "a comma-separated list of file names")?,
+            endpoint_url: endpoint_url_from_env(transfer_acceleration)?,
+            transfer_acceleration,
+            force_path_style: parse_env("S3_FORCE_PATH_STYLE", "false", "true or false")?,
+            region: optional_env("S3_REGION").unwrap_or_else(|| "auto".to_string()),
+            stalled_stream_grace_period_secs: parse_env("S3_STALLED_STREAM_GRACE_PERIOD_SECS", "5", "a valid number")?,
+            access_key_id: optional_env("AWS_ACCESS_KEY_ID"),
+            secret_access_key: optional_env("AWS_SECRET_ACCESS_KEY"),
+            session_token: optional_env("AWS_SESSION_TOKEN"),
+            credential_expiration: optional_env("AWS_CREDENTIAL_EXPIRATION"),
+            port: parse_env("PORT", "5000", "a valid number")?,
+            admin_port: parse_optional_env("ADMIN_PORT", "a valid number")?,
+            admin_api_key: optional_env("ADMIN_API_KEY"),
+            cache_max_entries: parse_env("CACHE_MAX_ENTRIES", "0", "a valid number")?,
+            cache_ttl_secs: parse_env("CACHE_TTL_SECS", "60", "a valid number")?,
+            cache_max_ttl_secs: parse_optional_env("CACHE_MAX_TTL_SECS", "a valid number")?,
+            cache_sweep_interval_secs: parse_env("CACHE_SWEEP_INTERVAL_SECS", "60", "a valid number")?,
+            eviction_webhook_url: optional_env("EVICTION_WEBHOOK_URL"),
+            cache_stale_while_revalidate_secs: parse_env("CACHE_STALE_WHILE_REVALIDATE_SECS", "0", "a valid number")?,
+            cache_stale_if_error_secs: parse_env("CACHE_STALE_IF_ERROR_SECS", "0", "a valid number")?,
+            cacheable_statuses: parse_list_env("CACHEABLE_STATUS_CODES", "200", "a comma-separated list of status codes")?,
+            cache_status_header: parse_env("CACHE_STATUS_HEADER", "false", "true or false")?,
+            cors_allowed_origins: parse_list_env("CORS_ALLOWED_ORIGINS", "", "a comma-separated list of origins")?,
+            cors_allow_credentials: parse_env("CORS_ALLOW_CREDENTIALS", "false", "true or false")?,
+            cors_expose_headers: parse_list_env("CORS_EXPOSE_HEADERS", "", "a comma-separated list of headers")?,
+            purge_api_key: optional_env("PURGE_API_KEY"),
+            enable_write_methods: parse_env("ENABLE_WRITE_METHODS", "false", "true or false")?,
+            write_api_key: optional_env("WRITE_API_KEY"),
+            write_path_prefix: optional_env("WRITE_PATH_PREFIX").map(|p| p.trim_start_matches('/').to_string()),
+            enable_echo_endpoint: parse_env("ENABLE_ECHO_ENDPOINT", "false", "true or false")?,
+            echo_api_key: optional_env("ECHO_API_KEY"),
+            listen_backlog: parse_env("LISTEN_BACKLOG", "1024", "a valid number")?,
+            reuse_address: parse_env("SO_REUSEADDR", "true", "true or false")?,
+            reuse_port: parse_env("SO_REUSEPORT", "false", "true or false")?,
+            ab_test: AbTestConfig::from_env()?,
+            apex_response: ApexResponse::from_env()?,
+            not_found_page: optional_env("NOT_FOUND_PAGE")
+                .map(|path| std::fs::read_to_string(&path).with_context(|| format!("Could not read NOT_FOUND_PAGE {}", path)))
+                .transpose()?,
+            error_pages: error_pages_from_env()?,
+            upstream_error_statuses: upstream_error_statuses_from_env()?,
+            trust_forwarded_host: parse_env("TRUST_FORWARDED_HOST", "false", "true or false")?,
+            trusted_proxy_cidrs: parse_list_env("TRUSTED_PROXY_CIDRS", "", "a comma-separated list of CIDRs")?,
+            verify_checksums: verify_checksums_from_env()?,
+            request_payer: parse_env("S3_REQUEST_PAYER", "false", "true or false")?,
+            allow_version_query: parse_env("ALLOW_VERSION_QUERY", "false", "true or false")?,
+            force_download_extensions: parse_list_env("FORCE_DOWNLOAD_EXTENSIONS", "", "a comma-separated list of extensions")?
+                .into_iter()
+                .map(|ext: String| ext.trim_start_matches('.').to_ascii_lowercase())
+                .collect(),
+            inline_extensions: parse_list_env("INLINE_EXTENSIONS", "", "a comma-separated list of extensions")?
+                .into_iter()
+                .map(|ext: String| ext.trim_start_matches('.').to_ascii_lowercase())
+                .collect(),
+            download_filename: parse_env("DOWNLOAD_FILENAME", "segment", "segment or none")?,
+            precompressed_brotli: parse_env("PRECOMPRESSED_BROTLI", "false", "true or false")?,
+            emit_digest_header: parse_env("EMIT_DIGEST_HEADER", "false", "true or false")?,
+            max_response_header_size_bytes: parse_env("MAX_RESPONSE_HEADER_SIZE_BYTES", "8192", "a number of bytes")?,
+            digest_trailer: parse_env("DIGEST_TRAILER", "false", "true or false")?,
+            sniff_content_type: parse_env("SNIFF_CONTENT_TYPE", "false", "true or false")?,
+            content_type_overrides: content_type_overrides_from_env()?,
+            enforce_accept: parse_env("ENFORCE_ACCEPT", "false", "true or false")?,
+            content_negotiation: parse_env("CONTENT_NEGOTIATION", "false", "true or false")?,
+            content_negotiation_types: content_negotiation_types_from_env()?,
+            absolute_redirects: parse_env("ABSOLUTE_REDIRECTS", "false", "true or false")?,
+            preload_links: PathRule::list_from_env("PRELOAD_LINKS")?,
+            cache_tag_header: optional_env("CACHE_TAG_HEADER"),
+            cache_tags: PathRule::list_from_env("CACHE_TAG_RULES")?,
+            x_robots_tag: optional_env("X_ROBOTS_TAG"),
+            x_robots_tag_rules: PathRule::list_from_env("X_ROBOTS_TAG_RULES")?,
+            x_robots_tag_staging: optional_env("X_ROBOTS_TAG_STAGING"),
+            staging: parse_env("STAGING", "false", "true or false")?,
+            surrogate_control: SiteHeader::from_env("SURROGATE_CONTROL")?,
+            cdn_cache_control: SiteHeader::from_env("CDN_CACHE_CONTROL")?,
+            prefetch_linked_assets: parse_env("PREFETCH_LINKED_ASSETS", "false", "true or false")?,
+            prefetch_concurrency: parse_env("PREFETCH_CONCURRENCY", "4", "a valid number")?,
+            html_inject_after_opening_head: optional_env("HTML_INJECT_AFTER_OPENING_HEAD"),
+            html_inject_before_closing_body: optional_env("HTML_INJECT_BEFORE_CLOSING_BODY"),
+            transformed_etag: parse_env("TRANSFORMED_ETAG", "weak", "weak or recompute")?,
+            immutable_asset_pattern: parse_env("IMMUTABLE_ASSET_PATTERN", "[a-f0-9]{8,}", "a valid regex")?,
+            max_cache_control_age: parse_optional_env("MAX_CACHE_CONTROL_AGE", "a number of seconds")?,
+            log_sample_rate: parse_env("LOG_SAMPLE_RATE", "1.0", "a number between 0.0 and 1.0")?,
+            slow_request_threshold_ms: parse_optional_env("SLOW_REQUEST_THRESHOLD_MS", "a number of milliseconds")?,
+            s3_max_concurrent_requests: parse_env("S3_MAX_CONCURRENT_REQUESTS", "100", "a valid number")?,
+            throttle_retry_after_secs: parse_env("THROTTLE_RETRY_AFTER_SECS", "5", "a valid number")?,
+            s3_queue_timeout_ms: parse_env("S3_QUEUE_TIMEOUT_MS", "5000", "a valid number")?,
+            request_timeout_ms: parse_optional_env("REQUEST_TIMEOUT_MS", "a number of milliseconds")?,
+            circuit_breaker_threshold: parse_env("CIRCUIT_BREAKER_THRESHOLD", "0", "a valid number")?,
+            circuit_breaker_cooldown_secs: parse_env("CIRCUIT_BREAKER_COOLDOWN_SECS", "30", "a valid number")?,
+        };
+
+        if config.access_key_id.is_some() != config.secret_access_key.is_some() {
+            bail!("AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY must be set together");
+        }
+        if config.session_token.is_some() && config.access_key_id.is_none() {
+            bail!("AWS_SESSION_TOKEN requires AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY");
+        }
+        if let Some(expiration) = &config.credential_expiration {
+            if config.session_token.is_none() {
+                bail!("AWS_CREDENTIAL_EXPIRATION only applies with AWS_SESSION_TOKEN");
+            }
+            DateTime::from_str(expiration, DateTimeFormat::DateTime)
+                .map_err(|_| anyhow!("AWS_CREDENTIAL_EXPIRATION must be an RFC 3339 timestamp"))?;
+        }
+        if config.enable_write_methods && config.write_api_key.is_none() {
+            bail!("WRITE_API_KEY must be set when ENABLE_WRITE_METHODS is enabled");
+        }
+        if config.enable_echo_endpoint && config.echo_api_key.is_none() {
+            bail!("ECHO_API_KEY must be set when ENABLE_ECHO_ENDPOINT is enabled");
+        }
+        if let Some(header) = &config.cache_tag_header {
+            HeaderName::from_bytes(header.as_bytes()).map_err(|_| anyhow!("CACHE_TAG_HEADER must be a valid header name"))?;
+        }
+        // The SDK signs accelerated requests for the bucket's region, which `auto` isn't
+        if config.transfer_acceleration && config.region == "auto" {
+            bail!("S3_REGION must be set to the bucket's region when S3_TRANSFER_ACCELERATION is enabled");
+        }
+        if matches!(config.subdomain_mode, SubdomainMode::Full) && config.allowed_domains.is_empty() {
+            bail!("ALLOWED_DOMAINS must be set when SUBDOMAIN_MODE is full");
+        }
+        if config.subdomain_separator != "-" && config.subdomain_separator != "/" {
+            bail!("SUBDOMAIN_SEPARATOR must be - or /");
+        }
+        if config.language_routing && config.language_routes.is_empty() {
+            bail!("LANGUAGE_ROUTES must be set when LANGUAGE_ROUTING is enabled");
+        }
+        // Browsers reject credentialed responses that allow any origin
+        if config.cors_allow_credentials && config.cors_allowed_origins.iter().any(|origin| origin == "*") {
+            bail!("CORS_ALLOW_CREDENTIALS can't be combined with CORS_ALLOWED_ORIGINS=*");
+        }
+        if config.content_negotiation && config.content_negotiation_types.is_empty() {
+            bail!("CONTENT_NEGOTIATION needs CONTENT_NEGOTIATION_TYPES to map media types to extensions");
+        }
+        if config.s3_max_concurrent_requests == 0 {
+            bail!("S3_MAX_CONCURRENT_REQUESTS must be at least 1");
+        }
+        if !(0.0..=1.0).contains(&config.log_sample_rate) {
+            bail!("LOG_SAMPLE_RATE must be a number between 0.0 and 1.0");
+        }
+        if let Some(extension) = config
+            .inline_extensions
+            .iter()
+            .find(|extension| config.force_download_extensions.contains(extension))
+        {
+            bail!("{} can't be in both FORCE_DOWNLOAD_EXTENSIONS and INLINE_EXTENSIONS", extension);
+        }
+        for (name, extensions) in [
+            ("FORCE_DOWNLOAD_EXTENSIONS", &config.force_download_extensions),
+            ("INLINE_EXTENSIONS", &config.inline_extensions),
+        ] {
+            if let Some(extension) = extensions.iter().find(|ext| !config.serves_extension(ext)) {
+                bail!("{} lists {}, which SERVED_EXTENSIONS redirects to the public bucket", name, extension);
+            }
+        }
+        if let Some(extension) = config
+            .content_negotiation_types
+            .values()
+            .find(|extension| !config.serves_extension(extension))
+        {
+            bail!("CONTENT_NEGOTIATION_TYPES maps to {}, which SERVED_EXTENSIONS redirects to the public bucket", extension);
+        }
+        // `*.wasm` or `alice/data.txt`, but not a glob like `alice/*`
+        let override_extension = |pattern: &str| {
+            let (_, extension) = pattern.rsplit_once('.')?;
+            (!extension.contains(['*', '/'])).then(|| extension.to_string())
+        };
+        if let Some(pattern) = config
+            .content_type_overrides
+            .keys()
+            .find(|pattern| override_extension(pattern).is_some_and(|ext| !config.serves_extension(&ext)))
+        {
+            bail!("CONTENT_TYPE_OVERRIDES has {}, which SERVED_EXTENSIONS redirects to the public bucket", pattern);
+        }
+        if let Some(url) = &config.eviction_webhook_url {
+            let uri: hyper::Uri = url.parse().map_err(|_| anyhow!("EVICTION_WEBHOOK_URL must be a valid URL"))?;
+            if uri.scheme_str() != Some("http") || uri.host().is_none() {
+                bail!("EVICTION_WEBHOOK_URL must be an http:// URL");
+            }
+        }
+        if config.clean_urls.is_empty() {
+            bail!("CLEAN_URLS must list at least one of html and index");
+        }
+        if config.index_documents.is_empty() || config.index_documents.iter().any(|name| name.contains('/')) {
+            bail!("INDEX_DOCUMENTS must list at least one file name");
+        }
+        if let Some(name) = config.index_documents.iter().find(|name| !config.serves_path(name)) {
+            bail!("INDEX_DOCUMENTS lists {}, but SERVED_EXTENSIONS doesn't include its extension", name);
+        }
+        Ok(config)
+    }
+
+    // Whether an object path is served from S3 rather than redirected to the public bucket
+    pub fn serves_path(&self, path: &str) -> bool {
+        let filename = path.rsplit('/').next().unwrap_or_default();
+        let extension = filename.rsplit_once('.').map(|(_, extension)| extension).unwrap_or_default();
+        self.serves_extension(extension)
+    }
+
+    pub fn serves_extension(&self, extension: &str) -> bool {
+        self.served_extensions
+            .iter()
+            .any(|served| served == "*" || served.eq_ignore_ascii_case(extension))
+    }
+
+    // An exact status code takes precedence over its class
+    pub fn error_page(&self, status: StatusCode) -> Option<&str> {
+        let class = format!("{}xx", status.as_u16() / 100);
+        self.error_pages
+            .get(status.as_str())
+            .or_else(|| self.error_pages.get(&class))
+            .map(String::as_str)
+    }
+
+    // An exact key takes precedence over globs
+    pub fn content_type_override(&self, key: &str) -> Option<&str> {
+        self.content_type_overrides
+            .get(key)
+            .or_else(|| {
+                self.content_type_overrides
+                    .iter()
+                    .find(|(pattern, _)| pattern.contains('*') && rules::glob_match(pattern, key))
+                    .map(|(_, content_type)| content_type)
+            })
+            .map(String::as_str)
+    }
+}
+
+impl FromStr for CleanUrl {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "html" => Ok(CleanUrl::Html),
+            "index" => Ok(CleanUrl::Index),
+            _ => Err(()),
+        }
+    }
+}
+
+impl FromStr for TrailingSlashFiles {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "index" => Ok(TrailingSlashFiles::Index),
+            "strip" => Ok(TrailingSlashFiles::Strip),
+            "not-found" => Ok(TrailingSlashFiles::NotFound),
+            _ => Err(()),
+        }
+    }
+}
+
+impl FromStr for SubdomainMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "first-label" => Ok(SubdomainMode::FirstLabel),
+            "full" => Ok(SubdomainMode::Full),
+            _ => Err(()),
+        }
+    }
+}
+
+impl FromStr for TransformedEtag {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "weak" => Ok(TransformedEtag::Weak),
+            "recompute" => Ok(TransformedEtag::Recompute),
+            _ => Err(()),
+        }
+    }
+}
+
+impl FromStr for DownloadFilename {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "segment" => Ok(DownloadFilename::Segment),
+            "none" => Ok(DownloadFilename::None),
+            _ => Err(()),
+        }
+    }
+}
+
+impl ApexResponse {
+    fn from_env() -> Result<Option<Self>> {
+        match (optional_env("APEX_REDIRECT_URL"), optional_env("APEX_MESSAGE")) {
+            (Some(url), None) => Ok(Some(ApexResponse::Redirect(url))),
+            (None, Some(message)) => Ok(Some(ApexResponse::Message(message))),
+            (None, None) => Ok(None),
+            _ => bail!("Only one of APEX_REDIRECT_URL and APEX_MESSAGE may be set"),
+        }
+    }
+}
+
+impl PathRule {
+    // Rules are a JSON object of patterns to values, e.g. for PRELOAD_LINKS
+    // `{"*.html": ["</styles/main.css>; rel=preload; as=style"], "docs:*.html": ["</docs.css>; rel=preload; as=style"]}`
+    // or for CACHE_TAG_RULES `{"docs:*": ["docs-site"], "*.css": ["css-assets"]}`
+    fn list_from_env(name: &str) -> Result<Vec<Self>> {
+        let Some(json) = optional_env(name) else {
+            return Ok(Vec::new());
+        };
+        let rules: BTreeMap<String, Vec<String>> = serde_json::from_str(&json)
+            .map_err(|_| anyhow!("{} must be a JSON object of patterns to lists of values", name))?;
+        Ok(rules
+            .into_iter()
+            .map(|(pattern, values)| {
+                let (subdomain, pattern) = match pattern.split_once(':') {
+                    Some((subdomain, pattern)) => (Some(subdomain.to_string()), pattern),
+                    None => (None, pattern.as_str()),
+                };
+                PathRule {
+                    subdomain,
+                    pattern: pattern.trim_start_matches('/').to_string(),
+                    values,
+                }
+            })
+            .collect())
+    }
+}
+
+impl KeyRewrite {
+    // A JSON array of `[pattern, replacement]` pairs, e.g. to drop a version segment:
+    // `KEY_REWRITE_RULES=[["^([^/]+)/v[0-9]+/", "$1/"]]`
+    fn list_from_env() -> Result<Vec<Self>> {
+        let Some(json) = optional_env("KEY_REWRITE_RULES") else {
+            return Ok(Vec::new());
+        };
+        let rules: Vec<(String, String)> = serde_json::from_str(&json)
+            .map_err(|_| anyhow!("KEY_REWRITE_RULES must be a JSON array of [pattern, replacement] pairs"))?;
+        rules
+            .into_iter()
+            .enumerate()
+            .map(|(index, (pattern, replacement))| {
+                let pattern = Regex::new(&pattern)
+                    .with_context(|| format!("KEY_REWRITE_RULES rule {} has an invalid pattern {}", index + 1, pattern))?;
+                Ok(KeyRewrite { pattern, replacement })
+            })
+            .collect()
+    }
+}
+
+impl SiteHeader {
+    // `{NAME}` holds the default and `{NAME}_SITES` a JSON object of subdomains to values,
+    // e.g. `SURROGATE_CONTROL_SITES={"docs": "max-age=60"}`
+    fn from_env(name: &str) -> Result<Self> {
+        let sites_name = format!("{}_SITES", name);
+        let sites: BTreeMap<String, String> = match optional_env(&sites_name) {
+            Some(json) => serde_json::from_str(&json)
+                .map_err(|_| anyhow!("{} must be a JSON object of subdomains to values", sites_name))?,
+            None => BTreeMap::new(),
+        };
+        let sites = sites.into_iter().map(|(site, value)| (site.to_ascii_lowercase(), value)).collect();
+        let header = SiteHeader {
+            default: optional_env(name),
+            sites,
+        };
+        if header.default.iter().chain(header.sites.values()).any(|v| HeaderValue::from_str(v).is_err()) {
+            bail!("{} and {} must be valid header values", name, sites_name);
+        }
+        Ok(header)
+    }
+
+    pub fn for_site(&self, subdomain: &str) -> Option<&str> {
+        self.sites
+            .get(&subdomain.to_ascii_lowercase())
+            .or(self.default.as_ref())
+            .map(String::as_str)
+    }
+}
+
+impl AbTestConfig {
+    // A/B testing is enabled only when both prefixes are set
+    fn from_env() -> Result<Option<Self>> {
+        let prefix_a = optional_env("AB_TEST_PREFIX_A");
+        let prefix_b = optional_env("AB_TEST_PREFIX_B");
+        let percentage_b: u8 = parse_env("AB_TEST_PERCENTAGE_B", "0", "a number between 0 and 100")?;
+        if percentage_b > 100 {
+            bail!("AB_TEST_PERCENTAGE_B must be a number between 0 and 100");
+        }
+
+        match (prefix_a, prefix_b) {
+            (Some(prefix_a), Some(prefix_b)) => Ok(Some(AbTestConfig {
+                prefix_a: prefix_a.trim_matches('/').to_string(),
+                prefix_b: prefix_b.trim_matches('/').to_string(),
+                percentage_b,
+            })),
+            (None, None) => Ok(None),
+            _ => bail!("AB_TEST_PREFIX_A and AB_TEST_PREFIX_B must be set together"),
+        }
+    }
+}
+
+// S3_ENDPOINT_URL takes precedence over the endpoint derived from R2_ACCOUNT_ID. Transfer
+// acceleration leaves the endpoint to the SDK, which can't combine it with a custom one.
+fn endpoint_url_from_env(transfer_acceleration: bool) -> Result<Option<String>> {
+    if transfer_acceleration {
+        return match (optional_env("S3_ENDPOINT_URL"), optional_env("R2_ACCOUNT_ID")) {
+            (Some(_), _) => bail!("S3_ENDPOINT_URL can't be set with S3_TRANSFER_ACCELERATION, which only AWS S3 supports"),
+            (None, Some(_)) => bail!("S3_TRANSFER_ACCELERATION is not supported by Cloudflare R2"),
+            (None, None) => Ok(None),
+        };
+    }
+    match (optional_env("S3_ENDPOINT_URL"), optional_env("R2_ACCOUNT_ID")) {
+        (Some(endpoint_url), _) => Ok(Some(endpoint_url)),
+        (None, Some(account_id)) => Ok(Some(format!("https://{}.r2.cloudflarestorage.com", account_id))),
+        (None, None) => bail!(
+            "No storage endpoint configured. Set one of:\n  \
+             Cloudflare R2: R2_ACCOUNT_ID=<account id>\n  \
+             MinIO: S3_ENDPOINT_URL=http://<host>:9000 S3_FORCE_PATH_STYLE=true\n  \
+             Wasabi: S3_ENDPOINT_URL=https://s3.<region>.wasabisys.com\n  \
+             Backblaze B2: S3_ENDPOINT_URL=https://s3.<region>.backblazeb2.com"
+        ),
+    }
+}
+
+// S3_CHECKSUM_MODE=enabled is the S3 spelling of VERIFY_CHECKSUMS=true, and
+// VERIFY_RESPONSE_CHECKSUM another name for it. Any of them may be set, but they must agree.
+fn verify_checksums_from_env() -> Result<bool> {
+    let checksum_mode = match optional_env("S3_CHECKSUM_MODE").as_deref() {
+        Some("enabled") => Some(true),
+        Some("disabled") => Some(false),
+        Some(_) => bail!("S3_CHECKSUM_MODE must be enabled or disabled"),
+        None => None,
+    };
+    let settings = [
+        ("S3_CHECKSUM_MODE", checksum_mode),
+        ("VERIFY_CHECKSUMS", parse_optional_env::<bool>("VERIFY_CHECKSUMS", "true or false")?),
+        ("VERIFY_RESPONSE_CHECKSUM", parse_optional_env("VERIFY_RESPONSE_CHECKSUM", "true or false")?),
+    ];
+    let mut set = settings.into_iter().filter_map(|(name, value)| Some((name, value?)));
+    let Some((first_name, verify)) = set.next() else {
+        return Ok(false);
+    };
+    if let Some((name, _)) = set.find(|&(_, value)| value != verify) {
+        bail!("{} and {} disagree about checksum verification", first_name, name);
+    }
+    Ok(verify)
+}
+
+fn required_env(name: &str) -> Result<String> {
+    env_var(name).ok_or_else(|| anyhow!("{} must be set", name))
+}
+
+// Set every `KEY=VALUE` line of a file as an environment variable, skipping blanks and `#` comments.
+// Only sound before any other thread starts, so it runs before the runtime is built.
+pub fn load_env_file(path: &Path) -> Result<()> {
+    if path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("toml")) {
+        bail!("{} looks like TOML, which --env-file does not read; use KEY=VALUE lines", path.display());
+    }
+    let contents = std::fs::read_to_string(path).with_context(|| format!("Could not read {}", path.display()))?;
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let Some((name, value)) = line.split_once('=') else {
+            bail!("{}:{}: expected KEY=VALUE", path.display(), number + 1);
+        };
+        let value = value.trim();
+        let value = match (value.strip_prefix('"'), value.strip_prefix('\'')) {
+            (Some(quoted), _) => quoted.strip_suffix('"'),
+            (_, Some(quoted)) => quoted.strip_suffix('\''),
+            _ => Some(value),
+        };
+        let Some(value) = value else {
+            bail!("{}:{}: unterminated quote", path.display(), number + 1);
+        };
+        std::env::set_var(name.trim(), value);
+    }
+    Ok(())
+}
+
+fn optional_env(name: &str) -> Option<String> {
+    env_var(name).filter(|v| !v.is_empty())
+}
+
+#[cfg(not(test))]
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok()
+}
+
+// Tests run in parallel, so each reads its own variables instead of the shared process environment
+#[cfg(test)]
+thread_local! {
+    static TEST_ENV: std::cell::RefCell<BTreeMap<String, String>> = const { std::cell::RefCell::new(BTreeMap::new()) };
+}
+
+#[cfg(test)]
+fn env_var(name: &str) -> Option<String> {
+    TEST_ENV.with(|env| env.borrow().get(name).cloned())
+}
+
+#[cfg(test)]
+impl Config {
+    // The configuration for the given variables, with a bucket and endpoint unless they are given
+    pub fn for_test(vars: &[(&str, &str)]) -> Result<Self> {
+        let defaults = [("R2_BUCKET_NAME", "bucket"), ("S3_ENDPOINT_URL", "http://127.0.0.1:9")];
+        TEST_ENV.with(|env| {
+            *env.borrow_mut() = defaults
+                .iter()
+                .chain(vars)
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect();
+        });
+        let config = Config::from_env();
+        TEST_ENV.with(|env| env.borrow_mut().clear());
+        config
+    }
+}
+
+fn language_routes_from_env() -> Result<BTreeMap<String, String>> {
+    let Some(json) = optional_env("LANGUAGE_ROUTES") else {
+        return Ok(BTreeMap::new());
+    };
+    let routes: BTreeMap<String, String> = serde_json::from_str(&json)
+        .map_err(|_| anyhow!("LANGUAGE_ROUTES must be a JSON object of language tags to key prefixes"))?;
+    Ok(routes
+        .into_iter()
+        .map(|(language, prefix)| (language.to_ascii_lowercase(), prefix.trim_matches('/').to_string()))
+        .collect())
+}
+
+// e.g. `ERROR_PAGES={"403": "403.html", "5xx": "error.html"}`
+// e.g. `CONTENT_TYPE_OVERRIDES={"alice/data.txt": "application/json", "*.wasm": "application/wasm"}`
+fn content_type_overrides_from_env() -> Result<BTreeMap<String, String>> {
+    let Some(json) = optional_env("CONTENT_TYPE_OVERRIDES") else {
+        return Ok(BTreeMap::new());
+    };
+    let overrides: BTreeMap<String, String> = serde_json::from_str(&json)
+        .map_err(|_| anyhow!("CONTENT_TYPE_OVERRIDES must be a JSON object of keys to content types"))?;
+    for (key, content_type) in &overrides {
+        if HeaderValue::from_str(content_type).is_err() {
+            bail!("CONTENT_TYPE_OVERRIDES has an invalid content type for {}: {}", key, content_type);
+        }
+    }
+    Ok(overrides
+        .into_iter()
+        .map(|(key, content_type)| (key.trim_start_matches('/').to_string(), content_type))
+        .collect())
+}
+
+// e.g. `CONTENT_NEGOTIATION_TYPES={"application/json": "json", "text/csv": "csv"}`
+fn content_negotiation_types_from_env() -> Result<BTreeMap<String, String>> {
+    let Some(json) = optional_env("CONTENT_NEGOTIATION_TYPES") else {
+        return Ok(BTreeMap::new());
+    };
+    let types: BTreeMap<String, String> = serde_json::from_str(&json)
+        .map_err(|_| anyhow!("CONTENT_NEGOTIATION_TYPES must be a JSON object of media types to extensions"))?;
+    Ok(types
+        .into_iter()
+        .map(|(media_type, extension)| (media_type.to_ascii_lowercase(), extension.trim_start_matches('.').to_string()))
+        .collect())
+}
+
+// e.g. `UPSTREAM_ERROR_STATUSES={"AccessDenied": 403, "500": 502}`
+fn upstream_error_statuses_from_env() -> Result<BTreeMap<String, u16>> {
+    let Some(json) = optional_env("UPSTREAM_ERROR_STATUSES") else {
+        return Ok(BTreeMap::new());
+    };
+    let statuses: BTreeMap<String, u16> = serde_json::from_str(&json)
+        .map_err(|_| anyhow!("UPSTREAM_ERROR_STATUSES must be a JSON object of S3 error codes to status codes"))?;
+    if let Some((error, status)) = statuses.iter().find(|(_, status)| !(400..600).contains(*status)) {
+        bail!("UPSTREAM_ERROR_STATUSES maps {} to {}, which is not an error status", error, status);
+    }
+    Ok(statuses)
+}
+
+fn error_pages_from_env() -> Result<BTreeMap<String, String>> {
+    let Some(json) = optional_env("ERROR_PAGES") else {
+        return Ok(BTreeMap::new());
+    };
+    let pages: BTreeMap<String, String> = serde_json::from_str(&json)
+        .map_err(|_| anyhow!("ERROR_PAGES must be a JSON object of statuses to document paths"))?;
+    let mut error_pages = BTreeMap::new();
+    for (status, document) in pages {
+        let status = status.to_ascii_lowercase();
+        let is_code = status.parse::<u16>().is_ok_and(|code| (400..600).contains(&code));
+        let is_class = status == "4xx" || status == "5xx";
+        if !is_code && !is_class {
+            bail!("ERROR_PAGES keys must be error statuses like 403 or classes like 5xx, not {}", status);
+        }
+        error_pages.insert(status, document.trim_start_matches('/').to_string());
+    }
+    Ok(error_pages)
+}
+
+fn parse_optional_env<T: FromStr>(name: &str, expected: &str) -> Result<Option<T>> {
+    optional_env(name)
+        .map(|v| v.parse().map_err(|_| anyhow!("{} must be {}", name, expected)))
+        .transpose()
+}
+
+fn parse_list_env<T: FromStr>(name: &str, default: &str, expected: &str) -> Result<Vec<T>> {
+    env_var(name)
+        .unwrap_or_else(|| default.to_string())
+        .split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(|v| v.parse().map_err(|_| anyhow!("{} must be {}", name, expected)))
+        .collect()
+}
+
+fn parse_env<T: FromStr>(name: &str, default: &str, expected: &str) -> Result<T> {
+    env_var(name)
+        .unwrap_or_else(|| default.to_string())
+        .parse()
+        .map_err(|_| anyhow!("{} must be {}", name, expected))
+}
+
+// Secrets are never printed, even partially
+fn redact_optional<S: Serializer>(value: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(_) => serializer.serialize_str("***"),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn serialize_regex<S: Serializer>(regex: &Regex, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(regex.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content_type_overrides_prefer_exact_keys() {
+        let overrides = r#"{"alice/data.txt": "application/json", "*.wasm": "application/wasm", "alice/*.txt": "text/csv"}"#;
+        let vars = [("SERVED_EXTENSIONS", "txt,wasm,html,htm"), ("CONTENT_TYPE_OVERRIDES", overrides)];
+        let config = Config::for_test(&vars).unwrap();
+        assert_eq!(config.content_type_override("alice/data.txt"), Some("application/json"));
+        assert_eq!(config.content_type_override("alice/other.txt"), Some("text/csv"));
+        assert_eq!(config.content_type_override("bob/app.wasm"), Some("application/wasm"));
+        assert_eq!(config.content_type_override("bob/data.txt"), None);
+    }
+
+    #[test]
+    fn content_type_overrides_must_be_served() {
+        let overrides = r#"{"*.wasm": "application/wasm"}"#;
+        let err = Config::for_test(&[("CONTENT_TYPE_OVERRIDES", overrides)]).err().unwrap();
+        assert!(err.to_string().contains("*.wasm"), "{}", err);
+        // A glob over whole directories can't be checked
+        assert!(Config::for_test(&[("CONTENT_TYPE_OVERRIDES", r#"{"alice/*": "text/plain"}"#)]).is_ok());
+    }
+
+    #[test]
+    fn negotiated_extensions_must_be_served() {
+        let types = r#"{"application/json": "json", "text/csv": "csv"}"#;
+        let vars = [("CONTENT_NEGOTIATION", "true"), ("CONTENT_NEGOTIATION_TYPES", types)];
+        let err = Config::for_test(&vars).err().unwrap();
+        assert!(err.to_string().contains("csv"), "{}", err);
+        let served = [vars[0], vars[1], ("SERVED_EXTENSIONS", "html,htm,json,csv")];
+        assert!(Config::for_test(&served).is_ok());
+    }
+
+    #[test]
+    fn checksum_verification_accepts_every_name() {
+        for name in ["VERIFY_CHECKSUMS", "VERIFY_RESPONSE_CHECKSUM"] {
+            assert!(Config::for_test(&[(name, "true")]).unwrap().verify_checksums, "{}", name);
+            assert!(!Config::for_test(&[(name, "false")]).unwrap().verify_checksums, "{}", name);
+        }
+        assert!(!Config::for_test(&[]).unwrap().verify_checksums);
+        let agreeing = [("VERIFY_CHECKSUMS", "true"), ("VERIFY_RESPONSE_CHECKSUM", "true")];
+        assert!(Config::for_test(&agreeing).unwrap().verify_checksums);
+
+        for (mode, verify) in [("enabled", true), ("disabled", false)] {
+            assert_eq!(Config::for_test(&[("S3_CHECKSUM_MODE", mode)]).unwrap().verify_checksums, verify, "{}", mode);
+        }
+        let agreeing = [("S3_CHECKSUM_MODE", "enabled"), ("VERIFY_CHECKSUMS", "true")];
+        assert!(Config::for_test(&agreeing).unwrap().verify_checksums);
+
+        let err = Config::for_test(&[("VERIFY_CHECKSUMS", "true"), ("VERIFY_RESPONSE_CHECKSUM", "false")]).err().unwrap();
+        assert!(err.to_string().contains("disagree"), "{}", err);
+        let err = Config::for_test(&[("S3_CHECKSUM_MODE", "disabled"), ("VERIFY_RESPONSE_CHECKSUM", "true")]).err().unwrap();
+        assert!(err.to_string().contains("disagree"), "{}", err);
+        assert!(Config::for_test(&[("VERIFY_RESPONSE_CHECKSUM", "yes")]).is_err());
+        assert!(Config::for_test(&[("S3_CHECKSUM_MODE", "on")]).is_err());
+    }
+
+    #[test]
+    fn transfer_acceleration_leaves_the_endpoint_to_the_sdk() {
+        let vars = [("S3_TRANSFER_ACCELERATION", "true"), ("S3_ENDPOINT_URL", ""), ("S3_REGION", "us-east-1")];
+        let config = Config::for_test(&vars).unwrap();
+        assert!(config.transfer_acceleration);
+        assert_eq!(config.endpoint_url, None);
+
+        let err = Config::for_test(&[vars[0], vars[2]]).err().unwrap();
+        assert!(err.to_string().contains("S3_ENDPOINT_URL"), "{}", err);
+        let err = Config::for_test(&[vars[0], vars[1]]).err().unwrap();
+        assert!(err.to_string().contains("S3_REGION"), "{}", err);
+    }
+
+    #[test]
+    fn env_files_are_not_toml() {
+        let err = load_env_file(Path::new("proxy.toml")).err().unwrap();
+        assert!(err.to_string().contains("TOML"), "{}", err);
+    }
+}
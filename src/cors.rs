@@ -0,0 +1,97 @@
+use crate::config::Config;
+use hyper::header::HeaderValue;
+use hyper::{HeaderMap, Method};
+
+// What a request asked of CORS, kept because the request itself is consumed while serving
+pub struct CorsRequest {
+    origin: Option<HeaderValue>,
+    preflight: bool,
+    request_headers: Option<HeaderValue>,
+}
+
+impl CorsRequest {
+    pub fn from_request(method: &Method, headers: &HeaderMap) -> Self {
+        Self {
+            origin: headers.get("origin").cloned(),
+            preflight: method == Method::OPTIONS && headers.contains_key("access-control-request-method"),
+            request_headers: headers.get("access-control-request-headers").cloned(),
+        }
+    }
+}
+
+// Let pages on CORS_ALLOWED_ORIGINS read responses, including errors, e.g. fonts or JSON fetched cross-site
+pub fn apply(config: &Config, request: &CorsRequest, headers: &mut HeaderMap) {
+    let any_origin = config.cors_allowed_origins.iter().any(|allowed| allowed == "*");
+    // The response differs by origin, so caches must key on it even when this request gets no CORS headers
+    if !any_origin && !config.cors_allowed_origins.is_empty() {
+        headers.append("vary", HeaderValue::from_static("Origin"));
+    }
+    let Some(origin) = &request.origin else {
+        return;
+    };
+    let allowed = any_origin
+        || config
+            .cors_allowed_origins
+            .iter()
+            .any(|allowed| origin.to_str().is_ok_and(|origin| allowed.eq_ignore_ascii_case(origin)));
+    if !allowed {
+        return;
+    }
+
+    if any_origin {
+        headers.insert("access-control-allow-origin", HeaderValue::from_static("*"));
+    } else {
+        headers.insert("access-control-allow-origin", origin.clone());
+    }
+    // Startup rejects credentials with `*`, which browsers would refuse anyway
+    if config.cors_allow_credentials {
+        headers.insert("access-control-allow-credentials", HeaderValue::from_static("true"));
+    }
+
+    if request.preflight {
+        if let Some(allow) = headers.get("allow").cloned() {
+            headers.insert("access-control-allow-methods", allow);
+        }
+        if let Some(request_headers) = &request.request_headers {
+            headers.insert("access-control-allow-headers", request_headers.clone());
+        }
+    } else if !config.cors_expose_headers.is_empty() {
+        if let Ok(expose) = HeaderValue::from_str(&config.cors_expose_headers.join(", ")) {
+            headers.insert("access-control-expose-headers", expose);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cors_headers(origins: &str, origin: Option<&'static str>) -> HeaderMap {
+        let config = Config::for_test(&[("CORS_ALLOWED_ORIGINS", origins)]).unwrap();
+        let mut request_headers = HeaderMap::new();
+        if let Some(origin) = origin {
+            request_headers.insert("origin", HeaderValue::from_static(origin));
+        }
+        let mut headers = HeaderMap::new();
+        apply(&config, &CorsRequest::from_request(&Method::GET, &request_headers), &mut headers);
+        headers
+    }
+
+    #[test]
+    fn specific_origins_always_vary_on_origin() {
+        for origin in [Some("https://alice.example"), Some("https://evil.example"), None] {
+            let headers = cors_headers("https://alice.example", origin);
+            assert_eq!(headers.get_all("vary").iter().collect::<Vec<_>>(), ["Origin"], "{:?}", origin);
+        }
+        let headers = cors_headers("https://alice.example", Some("https://alice.example"));
+        assert_eq!(headers["access-control-allow-origin"], "https://alice.example");
+        assert!(!cors_headers("https://alice.example", Some("https://evil.example")).contains_key("access-control-allow-origin"));
+    }
+
+    #[test]
+    fn any_origin_needs_no_vary() {
+        let headers = cors_headers("*", Some("https://alice.example"));
+        assert_eq!(headers["access-control-allow-origin"], "*");
+        assert!(!headers.contains_key("vary"));
+    }
+}
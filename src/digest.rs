@@ -0,0 +1,87 @@
+use base64::engine::general_purpose::STANDARD;
+use crate::config::TransformedEtag;
+use base64::Engine;
+use hyper::header::HeaderValue;
+use hyper::HeaderMap;
+use sha2::{Digest, Sha256};
+use tracing::debug;
+
+// Emit `Content-MD5` and an RFC 3230 `Digest` from an S3 ETag that is the object's MD5
+pub fn insert(headers: &mut HeaderMap, etag: Option<&str>) {
+    let Some(etag) = etag.map(|e| e.trim_start_matches("W/").trim_matches('"')) else {
+        return;
+    };
+    // Multipart uploads get `{md5 of part md5s}-{parts}`, which isn't a digest of the body
+    if etag.contains('-') {
+        debug!("Not emitting a digest for multipart ETag {}", etag);
+        return;
+    }
+    let Some(md5) = decode_hex(etag).filter(|md5| md5.len() == 16) else {
+        return;
+    };
+
+    let md5 = STANDARD.encode(md5);
+    headers.insert("content-md5", HeaderValue::from_str(&md5).unwrap());
+    headers.insert("digest", HeaderValue::from_str(&format!("md5={}", md5)).unwrap());
+}
+
+// Drop digests and S3 checksums once the body no longer matches the stored object
+pub fn remove(headers: &mut HeaderMap) {
+    for name in [
+        "content-md5",
+        "digest",
+        "x-amz-checksum-crc32",
+        "x-amz-checksum-crc32c",
+        "x-amz-checksum-sha1",
+        "x-amz-checksum-sha256",
+    ] {
+        headers.remove(name);
+    }
+}
+
+// Once the body is rewritten, the stored object's ETag no longer identifies it
+pub fn replace_etag(mode: TransformedEtag, headers: &mut HeaderMap, body: &[u8]) {
+    let Some(etag) = headers.get("etag").and_then(|e| e.to_str().ok()) else {
+        return;
+    };
+    let etag = match mode {
+        TransformedEtag::Weak if etag.starts_with("W/") => return,
+        TransformedEtag::Weak => format!("W/{}", etag),
+        TransformedEtag::Recompute => format!("\"{}\"", STANDARD.encode(Sha256::digest(body))),
+    };
+    if let Ok(etag) = HeaderValue::from_str(&etag) {
+        headers.insert("etag", etag);
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(etag: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("etag", HeaderValue::from_static(etag));
+        headers
+    }
+
+    #[test]
+    fn rewritten_bodies_get_weak_or_recomputed_etags() {
+        let mut weak = headers("\"abc\"");
+        replace_etag(TransformedEtag::Weak, &mut weak, b"body");
+        assert_eq!(weak["etag"], "W/\"abc\"");
+        let mut already_weak = headers("W/\"abc\"");
+        replace_etag(TransformedEtag::Weak, &mut already_weak, b"body");
+        assert_eq!(already_weak["etag"], "W/\"abc\"");
+
+        let mut recomputed = headers("\"abc\"");
+        replace_etag(TransformedEtag::Recompute, &mut recomputed, b"body");
+        assert_eq!(recomputed["etag"], format!("\"{}\"", STANDARD.encode(Sha256::digest(b"body"))));
+    }
+}
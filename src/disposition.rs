@@ -0,0 +1,128 @@
+use crate::config::{Config, DownloadFilename};
+use hyper::header::HeaderValue;
+use hyper::HeaderMap;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+
+// `attr-char` from RFC 5987, everything else is percent-encoded in `filename*`
+const ATTR_CHAR: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'!')
+    .remove(b'#')
+    .remove(b'$')
+    .remove(b'&')
+    .remove(b'+')
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'^')
+    .remove(b'_')
+    .remove(b'`')
+    .remove(b'|')
+    .remove(b'~');
+
+// Force a download or inline display for configured extensions, unless the object already has a disposition
+pub fn apply(config: &Config, path: &str, headers: &mut HeaderMap) {
+    if headers.contains_key("content-disposition") {
+        return;
+    }
+
+    let filename = path.rsplit('/').next().unwrap_or_default();
+    let Some((_, extension)) = filename.rsplit_once('.') else {
+        return;
+    };
+    let extension = extension.to_ascii_lowercase();
+    let disposition = if config.force_download_extensions.contains(&extension) {
+        "attachment"
+    } else if config.inline_extensions.contains(&extension) {
+        "inline"
+    } else {
+        return;
+    };
+
+    let value = match config.download_filename {
+        DownloadFilename::Segment => format!("{}; {}", disposition, filename_params(filename)),
+        DownloadFilename::None => disposition.to_string(),
+    };
+    if let Ok(value) = HeaderValue::from_str(&value) {
+        headers.insert("content-disposition", value);
+    }
+}
+
+// A quoted `filename` per RFC 6266, plus `filename*` when the name isn't plain ASCII
+fn filename_params(filename: &str) -> String {
+    let ascii: String = filename
+        .chars()
+        .map(|c| if c.is_ascii() && !c.is_ascii_control() { c } else { '_' })
+        .collect();
+    let quoted = format!("filename=\"{}\"", ascii.replace('\\', "\\\\").replace('"', "\\\""));
+    if ascii == filename {
+        return quoted;
+    }
+    format!("{}; filename*=UTF-8''{}", quoted, utf8_percent_encode(filename, ATTR_CHAR))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn disposition(vars: &[(&str, &str)], path: &str) -> Option<String> {
+        let config = Config::for_test(vars).unwrap();
+        let mut headers = HeaderMap::new();
+        apply(&config, path, &mut headers);
+        headers.get("content-disposition").map(|v| v.to_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn listed_extensions_are_attachments() {
+        let vars = [("SERVED_EXTENSIONS", "*"), ("FORCE_DOWNLOAD_EXTENSIONS", ".CSV,zip")];
+        assert_eq!(
+            disposition(&vars, "reports/2024.csv").as_deref(),
+            Some("attachment; filename=\"2024.csv\"")
+        );
+        assert_eq!(disposition(&vars, "a.ZIP").as_deref(), Some("attachment; filename=\"a.ZIP\""));
+        assert_eq!(disposition(&vars, "index.html"), None);
+        assert_eq!(disposition(&vars, "csv"), None);
+    }
+
+    #[test]
+    fn the_filename_can_be_left_out() {
+        let vars = [
+            ("SERVED_EXTENSIONS", "*"),
+            ("FORCE_DOWNLOAD_EXTENSIONS", "csv"),
+            ("DOWNLOAD_FILENAME", "none"),
+        ];
+        assert_eq!(disposition(&vars, "data.csv").as_deref(), Some("attachment"));
+    }
+
+    #[test]
+    fn stored_dispositions_are_kept() {
+        let config = Config::for_test(&[("SERVED_EXTENSIONS", "*"), ("FORCE_DOWNLOAD_EXTENSIONS", "csv")]).unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("content-disposition", HeaderValue::from_static("inline"));
+        apply(&config, "data.csv", &mut headers);
+        assert_eq!(headers["content-disposition"], "inline");
+    }
+
+    #[test]
+    fn forced_downloads_must_be_served() {
+        let err = Config::for_test(&[("FORCE_DOWNLOAD_EXTENSIONS", "csv")]).err().unwrap();
+        assert!(err.to_string().contains("SERVED_EXTENSIONS"), "{}", err);
+    }
+
+    #[test]
+    fn inline_extensions_are_shown() {
+        let vars = [("SERVED_EXTENSIONS", "*"), ("INLINE_EXTENSIONS", "pdf")];
+        assert_eq!(disposition(&vars, "docs/guide.pdf").as_deref(), Some("inline; filename=\"guide.pdf\""));
+        let err = Config::for_test(&[("INLINE_EXTENSIONS", "pdf")]).err().unwrap();
+        assert!(err.to_string().starts_with("INLINE_EXTENSIONS lists pdf"), "{}", err);
+    }
+
+    #[test]
+    fn filenames_are_quoted_per_rfc_6266() {
+        assert_eq!(filename_params("report.csv"), "filename=\"report.csv\"");
+        assert_eq!(filename_params("say \"hi\".csv"), "filename=\"say \\\"hi\\\".csv\"");
+        assert_eq!(filename_params("a\\b.csv"), "filename=\"a\\\\b.csv\"");
+        assert_eq!(
+            filename_params("résumé 1.pdf"),
+            "filename=\"r_sum_ 1.pdf\"; filename*=UTF-8''r%C3%A9sum%C3%A9%201.pdf"
+        );
+    }
+}
@@ -0,0 +1,42 @@
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::header::HeaderValue;
+use hyper::{HeaderMap, Response};
+
+// Whether `Accept-Encoding` lists the coding without `q=0`
+pub fn accepts(headers: &HeaderMap, coding: &str) -> bool {
+    headers
+        .get_all("accept-encoding")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|entry| {
+            let mut params = entry.split(';').map(str::trim);
+            let name = params.next().unwrap_or_default();
+            let rejected = params.any(|p| p.strip_prefix("q=").is_some_and(|q| q.parse::<f32>() == Ok(0.0)));
+            name.eq_ignore_ascii_case(coding) && !rejected
+        })
+}
+
+// Mark a response served from a `.br` object, giving it an ETag distinct from the identity variant
+pub fn mark_brotli(mut response: Response<Full<Bytes>>) -> Response<Full<Bytes>> {
+    let headers = response.headers_mut();
+    headers.insert("content-encoding", HeaderValue::from_static("br"));
+    add_vary(headers);
+    if let Some(etag) = headers.get("etag").and_then(|e| e.to_str().ok()) {
+        // `"abc"` becomes `"abc-br"`, keeping a `W/` prefix
+        let etag = match etag.strip_suffix('"') {
+            Some(opaque) => format!("{}-br\"", opaque),
+            None => format!("{}-br", etag),
+        };
+        if let Ok(etag) = HeaderValue::from_str(&etag) {
+            headers.insert("etag", etag);
+        }
+    }
+    response
+}
+
+// Responses that may be served precompressed must tell caches to key on the encoding
+pub fn add_vary(headers: &mut HeaderMap) {
+    headers.append("vary", HeaderValue::from_static("Accept-Encoding"));
+}
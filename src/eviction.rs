@@ -0,0 +1,78 @@
+use crate::metrics::METRICS;
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::{Request, Uri};
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::debug;
+
+// Notices waiting for delivery. Evictions beyond this are dropped rather than held in memory.
+const QUEUE_CAPACITY: usize = 1024;
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy)]
+pub enum EvictionReason {
+    // Past both stale windows
+    Ttl,
+    // Pushed out by a newer entry
+    Lru,
+}
+
+impl EvictionReason {
+    fn as_str(self) -> &'static str {
+        match self {
+            EvictionReason::Ttl => "ttl",
+            EvictionReason::Lru => "lru",
+        }
+    }
+}
+
+// Tells EVICTION_WEBHOOK_URL about cache evictions, without the cache ever waiting on it
+pub struct EvictionNotifier {
+    sender: mpsc::Sender<(String, EvictionReason)>,
+}
+
+impl EvictionNotifier {
+    // Start the task that posts notices, one at a time
+    pub fn spawn(url: Uri) -> Self {
+        let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+        tokio::task::spawn(deliver(url, receiver));
+        Self { sender }
+    }
+
+    pub fn notify(&self, key: &str, reason: EvictionReason) {
+        if self.sender.try_send((key.to_string(), reason)).is_err() {
+            METRICS.eviction_webhook_failures.inc();
+        }
+    }
+}
+
+async fn deliver(url: Uri, mut receiver: mpsc::Receiver<(String, EvictionReason)>) {
+    let client = Client::builder(TokioExecutor::new()).build_http();
+    while let Some((key, reason)) = receiver.recv().await {
+        let body = serde_json::json!({ "event": "evict", "key": key, "reason": reason.as_str() });
+        let request = Request::post(url.clone())
+            .header("content-type", "application/json")
+            .body(Full::new(Bytes::from(body.to_string())))
+            .unwrap();
+        let exchange = async {
+            let response = client.request(request).await.map_err(|err| err.to_string())?;
+            let status = response.status();
+            // Read the body so the connection can be reused
+            response.into_body().collect().await.map_err(|err| err.to_string())?;
+            if !status.is_success() {
+                return Err(format!("status {}", status));
+            }
+            Ok(())
+        };
+        let error = match tokio::time::timeout(DELIVERY_TIMEOUT, exchange).await {
+            Ok(Ok(())) => continue,
+            Ok(Err(err)) => err,
+            Err(_) => "timed out".to_string(),
+        };
+        METRICS.eviction_webhook_failures.inc();
+        debug!("Eviction webhook for {} failed: {}", key, error);
+    }
+}
@@ -0,0 +1,43 @@
+use hyper::HeaderMap;
+use std::net::{IpAddr, SocketAddr};
+
+// A parameter of the first RFC 7239 `Forwarded` element, added by the proxy nearest the client,
+// e.g. `host` from `Forwarded: for=192.0.2.1;host=example.com;proto=https, for=198.51.100.7`
+pub fn param(headers: &HeaderMap, name: &str) -> Option<String> {
+    let first = headers.get("forwarded")?.to_str().ok()?.split(',').next()?;
+    first
+        .split(';')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim().trim_matches('"').to_string())
+        .filter(|value| !value.is_empty())
+}
+
+// The `for=` node of every `Forwarded` element, in the order proxies added them
+pub fn for_nodes(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all("forwarded")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|element| {
+            element
+                .split(';')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+                .map(|(_, value)| value.trim().trim_matches('"').to_string())
+        })
+        .collect()
+}
+
+// The address in a `for=` node: `192.0.2.1`, `192.0.2.1:8080` or `[2001:db8::1]:4711`.
+// Obfuscated nodes like `_hidden` and `unknown` have none.
+pub fn node_ip(node: &str) -> Option<IpAddr> {
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+    node.strip_prefix('[')?.strip_suffix(']')?.parse().ok()
+}
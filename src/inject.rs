@@ -0,0 +1,110 @@
+use crate::config::Config;
+use crate::digest;
+use bytes::{BufMut, Bytes, BytesMut};
+use http_body_util::{BodyExt, Full};
+use hyper::{Response, StatusCode};
+
+pub fn is_enabled(config: &Config) -> bool {
+    config.html_inject_after_opening_head.is_some() || config.html_inject_before_closing_body.is_some()
+}
+
+// Inject the configured snippets into HTML pages, e.g. analytics or a consent banner
+pub async fn apply(config: &Config, response: Response<Full<Bytes>>) -> Response<Full<Bytes>> {
+    let head_snippet = config.html_inject_after_opening_head.as_deref();
+    let body_snippet = config.html_inject_before_closing_body.as_deref();
+    if !is_enabled(config) {
+        return response;
+    }
+    let is_html = response
+        .headers()
+        .get("content-type")
+        .and_then(|ct| ct.to_str().ok())
+        .is_some_and(|ct| ct.trim_start().to_ascii_lowercase().starts_with("text/html"));
+    // Encoded bodies can't be searched for tags
+    let is_encoded = response.headers().contains_key("content-encoding");
+    if response.status() != StatusCode::OK || !is_html || is_encoded {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let body = body.collect().await.unwrap().to_bytes();
+    let tags = find_tags(&body);
+
+    // Snippets go after the first `<head>`, so skipped without one, and before the
+    // last `</body>`, or at the end of the page without one
+    let mut insertions = Vec::new();
+    if let (Some(snippet), Some(at)) = (head_snippet, tags.head_end) {
+        insertions.push((at, snippet));
+    }
+    if let Some(snippet) = body_snippet {
+        insertions.push((tags.body_close.unwrap_or(body.len()), snippet));
+    }
+    // A page without `<head>` served with only a head snippet is passed through as stored
+    if insertions.is_empty() {
+        return Response::from_parts(parts, Full::new(body));
+    }
+    digest::remove(&mut parts.headers);
+
+    let extra: usize = insertions.iter().map(|(_, snippet)| snippet.len()).sum();
+    let mut injected = BytesMut::with_capacity(body.len() + extra);
+    let mut copied = 0;
+    for (at, snippet) in insertions {
+        injected.put(&body[copied..at]);
+        injected.put(snippet.as_bytes());
+        copied = at;
+    }
+    injected.put(&body[copied..]);
+    let injected = injected.freeze();
+    digest::replace_etag(config.transformed_etag, &mut parts.headers, &injected);
+    Response::from_parts(parts, Full::new(injected))
+}
+
+struct Tags {
+    // Offset just past the first `<head ...>` tag
+    head_end: Option<usize>,
+    // Offset of the last `</body>` tag
+    body_close: Option<usize>,
+}
+
+// Locate tags case-insensitively, ignoring anything inside `<!-- ... -->` comments
+fn find_tags(html: &[u8]) -> Tags {
+    let mut tags = Tags {
+        head_end: None,
+        body_close: None,
+    };
+    let mut i = 0;
+    while i < html.len() {
+        if html[i] != b'<' {
+            i += 1;
+            continue;
+        }
+        let rest = &html[i..];
+        if rest.starts_with(b"<!--") {
+            i = match find(&rest[4..], b"-->") {
+                Some(end) => i + 4 + end + 3,
+                None => html.len(),
+            };
+            continue;
+        }
+        if tags.head_end.is_none() && is_tag(rest, b"<head") {
+            if let Some(end) = find(rest, b">") {
+                tags.head_end = Some(i + end + 1);
+            }
+        } else if is_tag(rest, b"</body") {
+            tags.body_close = Some(i);
+        }
+        i += 1;
+    }
+    tags
+}
+
+// Whether `html` starts with the tag `name`, not a longer one like `<header>`
+fn is_tag(html: &[u8], name: &[u8]) -> bool {
+    html.len() > name.len()
+        && html[..name.len()].eq_ignore_ascii_case(name)
+        && matches!(html[name.len()], b'>' | b'/' | b' ' | b'\t' | b'\r' | b'\n')
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
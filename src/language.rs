@@ -0,0 +1,29 @@
+use hyper::HeaderMap;
+use std::collections::BTreeMap;
+
+// Key prefix of the configured language the client prefers most, trying `fr` for `fr-CA`.
+// `Accept-Language: fr;q=0.9, en;q=0.8` prefers `fr`; `q=0` rules a language out.
+pub fn select<'a>(routes: &'a BTreeMap<String, String>, headers: &HeaderMap) -> Option<&'a str> {
+    let mut languages: Vec<(String, f32)> = headers
+        .get_all("accept-language")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|entry| {
+            let mut params = entry.split(';').map(str::trim);
+            let tag = params.next().filter(|tag| !tag.is_empty())?.to_ascii_lowercase();
+            let quality = params
+                .find_map(|p| p.strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.parse::<f32>().ok())?;
+            Some((tag, quality))
+        })
+        .filter(|(_, quality)| *quality > 0.0)
+        .collect();
+    // Stable, so equally preferred languages keep the client's order
+    languages.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    languages.iter().find_map(|(tag, _)| {
+        let primary = tag.split('-').next().unwrap_or_default();
+        routes.get(tag).or_else(|| routes.get(primary)).map(String::as_str)
+    })
+}
@@ -0,0 +1,84 @@
+use crate::metrics::{GaugeGuard, METRICS};
+use std::time::Duration;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+// Caps the number of S3 requests in flight, so bursts of traffic queue instead of tripping rate limits
+pub struct S3Limiter {
+    permits: Semaphore,
+    queue_timeout: Duration,
+}
+
+impl S3Limiter {
+    pub fn new(max_concurrent: usize, queue_timeout: Duration) -> Self {
+        Self {
+            permits: Semaphore::new(max_concurrent),
+            queue_timeout,
+        }
+    }
+
+    // Wait for a free slot, or give up with `None` after the queue timeout. A timeout of zero never queues.
+    pub async fn acquire(&self) -> Option<S3Permit<'_>> {
+        if let Ok(permit) = self.permits.try_acquire() {
+            return Some(S3Permit::new(permit));
+        }
+        if self.queue_timeout.is_zero() {
+            METRICS.s3_queue_rejections.inc();
+            return None;
+        }
+
+        METRICS.s3_queue_depth.add(1);
+        let permit = tokio::time::timeout(self.queue_timeout, self.permits.acquire()).await;
+        METRICS.s3_queue_depth.add(-1);
+        // The semaphore is never closed, so only the timeout can fail
+        match permit {
+            Ok(Ok(permit)) => Some(S3Permit::new(permit)),
+            _ => {
+                METRICS.s3_queue_rejections.inc();
+                None
+            }
+        }
+    }
+}
+
+// A slot for one S3 request, counted in `proxy_s3_requests_in_flight` until dropped
+pub struct S3Permit<'a> {
+    _permit: SemaphorePermit<'a>,
+    _in_flight: GaugeGuard,
+}
+
+impl<'a> S3Permit<'a> {
+    fn new(permit: SemaphorePermit<'a>) -> Self {
+        Self {
+            _permit: permit,
+            _in_flight: GaugeGuard::new(&METRICS.s3_requests_in_flight),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn rejects_at_once_without_a_queue() {
+        let limiter = S3Limiter::new(1, Duration::ZERO);
+        let permit = limiter.acquire().await;
+        assert!(permit.is_some());
+        assert!(limiter.acquire().await.is_none());
+        drop(permit);
+        assert!(limiter.acquire().await.is_some());
+    }
+
+    #[tokio::test]
+    async fn queues_until_a_permit_frees_up_or_the_timeout() {
+        let limiter = S3Limiter::new(1, Duration::from_millis(50));
+        let permit = limiter.acquire().await;
+        assert!(limiter.acquire().await.is_none());
+
+        let (queued, ()) = tokio::join!(limiter.acquire(), async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            drop(permit);
+        });
+        assert!(queued.is_some());
+    }
+}
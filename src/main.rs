@@ -1,135 +1,820 @@
-use anyhow::Result;
+mod ab_test;
+mod accept;
+mod admin;
+mod benchmark;
+mod breaker;
+mod cache;
+mod completion;
+mod conditional;
+mod config;
+mod cors;
+mod digest;
+mod disposition;
+mod encoding;
+mod eviction;
+mod forwarded;
+mod inject;
+mod language;
+mod limiter;
+mod metrics;
+mod not_found;
+mod prefetch;
+mod preload;
+mod range;
+mod rules;
+mod sniff;
+mod source;
+mod status;
+mod trace;
+mod trailer;
+
+use anyhow::{Context, Result};
 use aws_sdk_s3::Client as S3Client;
-use aws_sdk_s3::config::Credentials;
+use aws_sdk_s3::config::http::HttpResponse;
+use aws_sdk_s3::config::{Credentials, ProvideCredentials, StalledStreamProtectionConfig};
+use aws_sdk_s3::error::{DisplayErrorContext, ProvideErrorMetadata, SdkError};
+use aws_sdk_s3::primitives::{ByteStream, DateTime, DateTimeFormat};
+use aws_sdk_s3::types::RequestPayer;
+use breaker::CircuitBreaker;
 use bytes::Bytes;
-use http_body_util::Full;
+use cache::{CacheStatus, CachedResponse, Freshness, ObjectCache};
+use clap::{CommandFactory, Parser};
+use limiter::S3Limiter;
+use metrics::{GaugeGuard, METRICS};
+use config::{ApexResponse, CleanUrl, Config, SubdomainMode, TrailingSlashFiles};
+use eviction::EvictionNotifier;
+use cors::CorsRequest;
+use http_body_util::{BodyExt, Full};
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
-use hyper::{Request, Response};
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{HeaderMap, Method, Request, Response, StatusCode};
 use hyper_util::rt::TokioIo;
 use tokio::net::TcpListener;
+use prefetch::Prefetcher;
 use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use trace::TraceContext;
+use source::{operation_timeout, ObjectRequest, ObjectSource, S3Source};
+use status::SystemStatus;
+use trailer::DigestBody;
+use tracing::{debug, error, info, info_span, warn, Instrument};
+use tracing_subscriber::EnvFilter;
+
+// State shared by all connections
+struct AppState {
+    config: Config,
+    // Writes go straight to the bucket, reads through `objects`
+    s3_client: S3Client,
+    objects: Arc<dyn ObjectSource>,
+    cache: ObjectCache,
+    breaker: CircuitBreaker,
+    s3_limiter: S3Limiter,
+    prefetcher: Prefetcher,
+    status: SystemStatus,
+}
+
+impl AppState {
+    fn new(config: Config, s3_client: S3Client, objects: Arc<dyn ObjectSource>) -> Result<Self> {
+        let eviction_notifier = match &config.eviction_webhook_url {
+            Some(url) => Some(EvictionNotifier::spawn(url.parse()?)),
+            None => None,
+        };
+        let cache = ObjectCache::new(
+            config.cache_max_entries,
+            Duration::from_secs(config.cache_stale_while_revalidate_secs),
+            Duration::from_secs(config.cache_stale_if_error_secs),
+            eviction_notifier,
+        );
+        let breaker = CircuitBreaker::new(
+            config.circuit_breaker_threshold,
+            Duration::from_secs(config.circuit_breaker_cooldown_secs),
+        );
+        let s3_limiter = S3Limiter::new(
+            config.s3_max_concurrent_requests,
+            Duration::from_millis(config.s3_queue_timeout_ms),
+        );
+        let prefetcher = Prefetcher::new(config.prefetch_concurrency);
+        let status = SystemStatus::new(&config);
+        Ok(Self {
+            config,
+            s3_client,
+            objects,
+            cache,
+            breaker,
+            s3_limiter,
+            prefetcher,
+            status,
+        })
+    }
+}
+
+// How a request wants its objects fetched, shared by every candidate path and key
+#[derive(Clone, Copy)]
+struct Fetch<'a> {
+    method: &'a Method,
+    // Pinned with `?version=<id>`
+    version: Option<&'a str>,
+    accepts_brotli: bool,
+    // A single `Range` to fetch from S3 instead of the whole object. Large media only gets here
+    // when SERVED_EXTENSIONS lists it; otherwise the public bucket answers the range.
+    range: Option<&'a str>,
+    // When S3 must have answered, from REQUEST_TIMEOUT_MS
+    deadline: Option<Instant>,
+}
+
+// Marks a 404 for a key S3 reported missing, as opposed to a failed lookup
+#[derive(Clone, Copy)]
+struct MissingObject;
+
+// The S3 key a response was served from, for slow request logs
+#[derive(Clone)]
+struct ServedKey(String);
+
+// Body of a `POST /_purge` request
+#[derive(Deserialize)]
+struct PurgeRequest {
+    paths: Vec<String>,
+}
+
+// Command-line flags
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Validate configuration and S3 connectivity, then exit
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Benchmark concurrent GetObject requests for KEY, then exit
+    #[arg(long, value_name = "KEY")]
+    benchmark: Option<String>,
+
+    /// Validate configuration without contacting S3, then exit
+    #[arg(long)]
+    check_config: bool,
 
-// Configuration struct
-struct Config {
-    bucket_name: String,
-    account_id: String,
-    access_key_id: String,
-    secret_access_key: String,
-    port: u16,
+    /// Read configuration from an env file of KEY=VALUE lines, overriding the environment.
+    /// TOML files are not supported.
+    #[arg(long, value_name = "PATH")]
+    env_file: Option<PathBuf>,
+
+    /// Print a completion script for SHELL, then exit
+    #[arg(long, value_name = "SHELL")]
+    generate_completion: Option<completion::Shell>,
+}
+
+// The environment is only changed here, before the runtime starts any threads that could read it
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    if let Some(shell) = cli.generate_completion {
+        print!("{}", completion::generate(shell, Cli::command()));
+        return Ok(());
+    }
+
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .init();
+
+    if let Some(path) = &cli.env_file {
+        config::load_env_file(path)?;
+    }
+    let systemd_listener = systemd_listener()?;
+
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run(cli, systemd_listener))
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
+async fn run(cli: Cli, systemd_listener: Option<std::net::TcpListener>) -> Result<()> {
     // Initialize configuration
-    let config = Config {
-        bucket_name: std::env::var("R2_BUCKET_NAME").expect("R2_BUCKET_NAME must be set"),
-        account_id: std::env::var("R2_ACCOUNT_ID").expect("R2_ACCOUNT_ID must be set"),
-        access_key_id: std::env::var("AWS_ACCESS_KEY_ID").expect("AWS_ACCESS_KEY_ID must be set"),
-        secret_access_key: std::env::var("AWS_SECRET_ACCESS_KEY").expect("AWS_SECRET_ACCESS_KEY must be set"),
-        port: std::env::var("PORT")
-            .unwrap_or_else(|_| "5000".to_string())
-            .parse()
-            .expect("PORT must be a valid number"),
-    };
+    let config = Config::from_env()?;
+    if cli.check_config {
+        println!("Configuration OK");
+        return Ok(());
+    }
 
     // Initialize R2 client
-    let r2_endpoint = format!("https://{}.r2.cloudflarestorage.com", config.account_id);
-    let aws_config = aws_config::defaults(aws_config::BehaviorVersion::latest())
-        .endpoint_url(r2_endpoint)
-        .region(aws_sdk_s3::config::Region::new("auto"))
-        .credentials_provider(Credentials::new(
-            config.access_key_id,
-            config.secret_access_key,
-            None,
-            None,
+    let mut aws_config = aws_config::defaults(aws_config::BehaviorVersion::latest())
+        .region(aws_sdk_s3::config::Region::new(config.region.clone()));
+    if let Some(endpoint_url) = &config.endpoint_url {
+        aws_config = aws_config.endpoint_url(endpoint_url);
+    }
+    // Static keys take precedence; otherwise fall back to the default credential chain
+    if let (Some(access_key_id), Some(secret_access_key)) = (&config.access_key_id, &config.secret_access_key) {
+        // Validated along with the rest of the configuration
+        let expiry = config
+            .credential_expiration
+            .as_deref()
+            .and_then(|expiration| DateTime::from_str(expiration, DateTimeFormat::DateTime).ok())
+            .and_then(|expiration| SystemTime::try_from(expiration).ok());
+        aws_config = aws_config.credentials_provider(Credentials::new(
+            access_key_id,
+            secret_access_key,
+            config.session_token.clone(),
+            expiry,
             "R2",
-        ))
-        .load()
-        .await;
-    let s3_client = S3Client::new(&aws_config);
+        ));
+    }
+    let aws_config = aws_config.load().await;
+    warn_if_credentials_expiring(&aws_config).await;
+    // Path-style addressing is needed for MinIO, Ceph and similar self-hosted endpoints
+    let s3_config = aws_sdk_s3::config::Builder::from(&aws_config)
+        .force_path_style(config.force_path_style)
+        .accelerate(config.transfer_acceleration)
+        .stalled_stream_protection(
+            StalledStreamProtectionConfig::enabled()
+                .grace_period(Duration::from_secs(config.stalled_stream_grace_period_secs))
+                .build(),
+        )
+        .build();
+    let s3_client = S3Client::from_conf(s3_config);
+
+    if cli.dry_run {
+        println!("Effective configuration:\n{}", serde_json::to_string_pretty(&config)?);
+        check_s3_connectivity(&s3_client, &config.bucket_name).await?;
+        println!("Configuration and S3 connectivity OK");
+        return Ok(());
+    }
+    let objects = Arc::new(S3Source::new(s3_client.clone(), &config));
+    if let Some(key) = &cli.benchmark {
+        return benchmark::run(objects, &config.bucket_name, key).await;
+    }
+    if let Err(err) = check_s3_connectivity(&s3_client, &config.bucket_name).await {
+        warn!("{:#}", err);
+    }
+
+    let bucket_name = config.bucket_name.to_lowercase();
+    if !config.request_payer && (bucket_name.contains("requester") || bucket_name.contains("payer")) {
+        warn!("Bucket {} looks like a requester-pays bucket but S3_REQUEST_PAYER is not set", config.bucket_name);
+    }
+    if config.trust_forwarded_host && config.trusted_proxy_cidrs.is_empty() {
+        warn!("TRUST_FORWARDED_HOST is set without TRUSTED_PROXY_CIDRS; Forwarded and X-Forwarded-Host will be trusted from any client");
+    }
 
     // Create a TCP listener
     let addr = format!("localhost:{}", config.port);
-    let listener = TcpListener::bind(&addr).await?;
-    println!("Server running on http://{}", addr);
+    let listener = match systemd_listener {
+        Some(listener) => {
+            listener.set_nonblocking(true)?;
+            let listener =
+                TcpListener::from_std(listener).context("The socket passed by systemd is not a TCP listener")?;
+            info!("Server running on a socket passed by systemd ({})", listener.local_addr()?);
+            listener
+        }
+        None => {
+            let listener = bind_listener(&addr, &config).await?;
+            info!("Server running on http://{}", addr);
+            listener
+        }
+    };
+
+    // Metrics and other operational endpoints live on a separate port
+    let admin_listener = match config.admin_port {
+        Some(admin_port) => {
+            let admin_addr = format!("localhost:{}", admin_port);
+            let admin_listener = bind_listener(&admin_addr, &config).await?;
+            info!("Admin server running on http://{}", admin_addr);
+            Some(admin_listener)
+        }
+        None => None,
+    };
+
+    let state = Arc::new(AppState::new(config, s3_client, objects)?);
+
+    if state.cache.is_enabled() && state.config.cache_sweep_interval_secs > 0 {
+        let state = state.clone();
+        tokio::task::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(state.config.cache_sweep_interval_secs));
+            loop {
+                interval.tick().await;
+                let removed = state.cache.sweep();
+                if removed > 0 {
+                    debug!("Swept {} expired cache entries", removed);
+                }
+            }
+        });
+    }
+
+    if admin_listener.is_some() {
+        tokio::task::spawn(status::refresh(state.clone()));
+    }
+
+    if let Some(admin_listener) = admin_listener {
+        let state = state.clone();
+        tokio::task::spawn(async move {
+            if let Err(err) = admin::serve(admin_listener, state).await {
+                error!("Admin server stopped: {}", err);
+            }
+        });
+    }
 
     // Handle incoming connections
     loop {
-        let (stream, _) = listener.accept().await?;
+        let (stream, remote_addr) = listener.accept().await?;
         let io = TokioIo::new(stream);
-        let s3_client = s3_client.clone();
-        let bucket_name = config.bucket_name.clone();
+        let state = state.clone();
 
         // Spawn a new task for each connection
         tokio::task::spawn(async move {
+            METRICS.connections.inc();
+            METRICS.connections_active.add(1);
+            let requests = AtomicU64::new(0);
             if let Err(err) = http1::Builder::new()
                 .serve_connection(
                     io,
-                    service_fn(move |req| handle_request(req, s3_client.clone(), bucket_name.clone())),
+                    service_fn(|req| {
+                        requests.fetch_add(1, Ordering::Relaxed);
+                        handle_request(req, remote_addr, state.clone())
+                    }),
                 )
                 .await
             {
-                eprintln!("Error serving connection: {}", err);
+                error!("Error serving connection: {}", err);
             }
+            METRICS.connections_active.add(-1);
+            METRICS.requests_per_connection.observe(requests.load(Ordering::Relaxed));
         });
     }
 }
 
+// Temporary credentials that expire soon after startup will start failing requests unless the
+// provider refreshes them, which static keys can't
+async fn warn_if_credentials_expiring(aws_config: &aws_config::SdkConfig) {
+    const MIN_REMAINING: Duration = Duration::from_secs(5 * 60);
+    let Some(provider) = aws_config.credentials_provider() else {
+        return;
+    };
+    let credentials = match provider.provide_credentials().await {
+        Ok(credentials) => credentials,
+        Err(err) => {
+            debug!("Could not load S3 credentials at startup: {}", DisplayErrorContext(&err));
+            return;
+        }
+    };
+    let Some(expiry) = credentials.expiry() else {
+        return;
+    };
+    let remaining = expiry.duration_since(SystemTime::now()).unwrap_or_default();
+    if remaining < MIN_REMAINING {
+        warn!("S3 credentials expire in {}s", remaining.as_secs());
+    }
+}
+
+// Make sure the bucket is reachable with the configured credentials
+async fn check_s3_connectivity(s3_client: &S3Client, bucket_name: &str) -> Result<()> {
+    s3_client
+        .head_bucket()
+        .bucket(bucket_name)
+        .send()
+        .await
+        .with_context(|| format!("Could not reach bucket {}", bucket_name))?;
+    Ok(())
+}
+
+// Adopt the listener systemd passes under socket activation, which keeps accepting while the proxy restarts.
+// Called before the runtime starts, as the variables naming it are removed from the environment.
+#[cfg(unix)]
+fn systemd_listener() -> Result<Option<std::net::TcpListener>> {
+    use std::os::fd::FromRawFd;
+    // sd_listen_fds(3): passed sockets start at fd 3
+    const SD_LISTEN_FDS_START: i32 = 3;
+
+    let pid = std::env::var("LISTEN_PID").ok();
+    let fds = std::env::var("LISTEN_FDS").ok();
+    // The sockets are meant for this process, not for anything it spawns
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(name);
+    }
+    let (Some(pid), Some(fds)) = (pid, fds) else {
+        return Ok(None);
+    };
+    if pid.trim().parse::<u32>().ok() != Some(std::process::id()) {
+        return Ok(None);
+    }
+    let fds: u32 = fds.trim().parse().context("LISTEN_FDS must be a valid number")?;
+    if fds == 0 {
+        return Ok(None);
+    }
+    if fds > 1 {
+        warn!("systemd passed {} sockets; only the first is used", fds);
+    }
+
+    // SAFETY: LISTEN_PID names this process, so systemd handed it fd 3 and nothing else owns it
+    let socket = unsafe { Socket::from_raw_fd(SD_LISTEN_FDS_START) };
+    // systemd passes the socket inheritable, and it shouldn't leak into child processes either
+    socket.set_cloexec(true)?;
+    Ok(Some(socket.into()))
+}
+
+#[cfg(not(unix))]
+fn systemd_listener() -> Result<Option<std::net::TcpListener>> {
+    Ok(None)
+}
+
+// Bind the listening socket with the configured socket options
+async fn bind_listener(addr: &str, config: &Config) -> Result<TcpListener> {
+    let addr = tokio::net::lookup_host(addr)
+        .await?
+        .next()
+        .with_context(|| format!("Could not resolve listen address {}", addr))?;
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket
+        .set_reuse_address(config.reuse_address)
+        .context("SO_REUSEADDR is not supported on this platform")?;
+    if config.reuse_port {
+        #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+        socket
+            .set_reuse_port(true)
+            .context("SO_REUSEPORT is not supported on this platform")?;
+        #[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+        anyhow::bail!("SO_REUSEPORT is not supported on this platform");
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(config.listen_backlog)?;
+
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
 // Handle individual HTTP requests
 async fn handle_request(
     req: Request<hyper::body::Incoming>,
-    s3_client: S3Client,
-    bucket_name: String,
-) -> Result<Response<Full<Bytes>>> {
-    // Extract the host from the request headers, with better error handling
-    let host = req
+    remote_addr: SocketAddr,
+    state: Arc<AppState>,
+) -> Result<Response<DigestBody>> {
+    METRICS.requests.inc();
+    let _in_flight = GaugeGuard::new(&METRICS.requests_in_flight);
+    let started = Instant::now();
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let host = request_host(&req, remote_addr, &state.config);
+    let scheme = request_scheme(&req, remote_addr, &state.config);
+    let cors_request = CorsRequest::from_request(&method, req.headers());
+    let digest_trailer = state.config.digest_trailer && trailer::accepted(&req);
+
+    // Every log line for this request carries the trace ID, so origin logs line up with edge traces
+    let trace = TraceContext::from_headers(req.headers());
+    // Client retries can be correlated by their `Idempotency-Key`, which is only logged, never acted on
+    let idempotency_key = req
         .headers()
-        .get("host")
-        .and_then(|h| h.to_str().ok())
-        .unwrap_or_default()
-        .to_string();
+        .get("idempotency-key")
+        .and_then(|v| v.to_str().ok())
+        .filter(|key| key.len() <= 64 && key.bytes().all(|b| b.is_ascii_graphic() || b == b' '));
+    let span = info_span!(
+        "request",
+        trace_id = %trace.trace_id,
+        parent_id = trace.parent_id.as_deref(),
+        tracestate = trace.tracestate.as_deref(),
+        idempotency_key
+    );
 
-    // More robust subdomain extraction
-    let subdomain = host
-        .split('.')
-        .next()
-        .filter(|&s| !s.is_empty())
-        .unwrap_or_default();
-    
-    let path = req.uri().path().trim_start_matches('/');
-    // URL decode the path
-    let path = percent_decode_str(path)
-        .decode_utf8()
-        .unwrap_or_default()
-        .to_string();
+    let serve = serve_request(req, remote_addr, &state, &host, &trace.trace_id).instrument(span.clone());
+    // Work after S3 answers, like reading the body, is bounded by the same deadline
+    let mut response = match state.config.request_timeout_ms {
+        Some(ms) => match tokio::time::timeout(Duration::from_millis(ms), serve).await {
+            Ok(response) => response?,
+            Err(_) => gateway_timeout(),
+        },
+        None => serve.await?,
+    };
+    let _enter = span.enter();
 
-    // Handle directory paths, empty paths, and paths without extensions
-    let path = if path.is_empty() || path == "index.html" {
-        "index.html".to_string()
-    } else if path.ends_with('/') || (!path.contains('.') && !path.is_empty()) {
-        if path.ends_with('/') {
-            format!("{}index.html", path)
+    let cache_status = response
+        .extensions()
+        .get::<CacheStatus>()
+        .copied()
+        .unwrap_or(CacheStatus::Bypass);
+    if state.config.cache_status_header {
+        response
+            .headers_mut()
+            .insert("X-Cache", HeaderValue::from_static(cache_status.as_str()));
+    }
+    cors::apply(&state.config, &cors_request, response.headers_mut());
+    finalize_headers(response.headers_mut());
+    let x_robots_tag = x_robots_tag(&state.config, &host, &path);
+    insert_header(response.headers_mut(), "x-robots-tag", x_robots_tag.as_deref());
+    // Redirects from object metadata and to the public bucket alike
+    if let Some(location) = response.headers().get("location").and_then(|l| l.to_str().ok()) {
+        let location = build_redirect_location(scheme, &host, location, &state.config);
+        insert_header(response.headers_mut(), "location", Some(&location));
+    }
+
+    // Successful responses are sampled to cut log volume, everything else is always logged
+    let status = response.status();
+    let elapsed = started.elapsed();
+    if let Some(threshold) = state.config.slow_request_threshold_ms.filter(|&ms| elapsed.as_millis() > ms as u128) {
+        let key = response.extensions().get::<ServedKey>().map(|key| key.0.as_str());
+        warn!(
+            %method,
+            host,
+            path,
+            key,
+            status = status.as_u16(),
+            duration_ms = elapsed.as_millis() as u64,
+            threshold_ms = threshold,
+            "slow request"
+        );
+    } else if !status.is_success() || fastrand::f64() < state.config.log_sample_rate {
+        info!(
+            %method,
+            host,
+            path,
+            status = status.as_u16(),
+            cache = cache_status.as_str(),
+            "request"
+        );
+    }
+    if let Some(len) = hyper::body::Body::size_hint(response.body()).exact().filter(|_| method != Method::HEAD) {
+        let content_type = response.headers().get("content-type").and_then(|ct| ct.to_str().ok());
+        METRICS.observe_body(content_type, len);
+    }
+    Ok(trailer::apply(response, digest_trailer).await)
+}
+
+// Route a request to the purge endpoint or the site's objects
+async fn serve_request(
+    req: Request<hyper::body::Incoming>,
+    remote_addr: SocketAddr,
+    state: &Arc<AppState>,
+    host: &str,
+    request_id: &str,
+) -> Result<Response<Full<Bytes>>> {
+    let deadline = state.config.request_timeout_ms.map(|ms| Instant::now() + Duration::from_millis(ms));
+    let subdomain = subdomain_of(host, &state.config);
+    let custom_domain = is_custom_domain(host, &state.config);
+
+    // Without a subdomain the key would have no site prefix and could reach bucket root objects
+    if state.config.require_subdomain && subdomain.is_empty() && !custom_domain {
+        return Ok(Response::builder()
+            .status(400)
+            .body(Full::new(Bytes::from("Bad Request")))
+            .unwrap());
+    }
+
+    // `..` names no object, and would climb out of the site's prefix wherever keys are normalized
+    if percent_decode_str(req.uri().path()).decode_utf8_lossy().split('/').any(|segment| segment == "..") {
+        return Ok(Response::builder()
+            .status(400)
+            .body(Full::new(Bytes::from("Bad Request")))
+            .unwrap());
+    }
+
+    // Cache invalidation, either `PURGE /path` or `POST /_purge`
+    if req.method().as_str() == "PURGE" || (req.method() == Method::POST && req.uri().path() == "/_purge") {
+        return handle_purge(req, state, &subdomain).await;
+    }
+
+    let write_method = [Method::PUT, Method::PATCH, Method::DELETE].contains(req.method());
+    if state.config.enable_write_methods && write_method {
+        return handle_write(req, state, &subdomain, deadline).await;
+    }
+
+    // Answer OPTIONS probes directly instead of looking up an object
+    if req.method() == Method::OPTIONS {
+        let allow = if state.config.enable_write_methods {
+            "GET, HEAD, PUT, PATCH, DELETE, OPTIONS"
         } else {
-            format!("{}/index.html", path)
+            "GET, HEAD, OPTIONS"
+        };
+        return Ok(Response::builder()
+            .status(204)
+            .header("Allow", allow)
+            .body(Full::new(Bytes::new()))
+            .unwrap());
+    }
+
+    // A/B testing replaces the subdomain prefix with the selected variant's prefix
+    let ab_variant = state
+        .config
+        .ab_test
+        .as_ref()
+        .map(|ab| (ab, ab_test::select_variant(ab, req.headers(), client_ip(req.headers(), remote_addr, &state.config))));
+    let site_prefix = match ab_variant {
+        Some((ab, variant)) => variant.prefix(ab),
+        None => site_prefix_for(&state.config, &subdomain),
+    };
+    let localized = state
+        .config
+        .language_routing
+        .then(|| language::select(&state.config.language_routes, req.headers()))
+        .flatten()
+        .map(|language| join_key(&[site_prefix, language]));
+    let site_prefix = localized.as_deref().unwrap_or(site_prefix);
+
+    // `/_echo/<path>` shows how `/<path>` would be routed
+    if state.config.enable_echo_endpoint {
+        if let Some(echo_path) = req.uri().path().strip_prefix("/_echo").filter(|p| p.is_empty() || p.starts_with('/')) {
+            let variant = ab_variant.map(|(_, variant)| variant);
+            let client_ip = client_ip(req.headers(), remote_addr, &state.config);
+            return Ok(handle_echo(&req, state, client_ip, host, echo_path, site_prefix, variant));
+        }
+    }
+
+    // `/blog/index.html` has a canonical `/blog/`, so search engines see a single URL per page. Only
+    // INDEX_DOCUMENTS are stripped, as no other name is served for `/blog/`.
+    if state.config.strip_index_html && (req.method() == Method::GET || req.method() == Method::HEAD) {
+        let directory = state.config.index_documents.iter().find_map(|name| {
+            req.uri().path().strip_suffix(name.as_str()).filter(|dir| dir.ends_with('/'))
+        });
+        if let Some(directory) = directory {
+            let location = match req.uri().query() {
+                Some(query) => format!("{}?{}", directory, query),
+                None => directory.to_string(),
+            };
+            return Ok(Response::builder()
+                .status(301)
+                .header("Location", location)
+                .body(Full::new(Bytes::from("Redirecting...")))
+                .unwrap());
         }
+    }
+
+    // A specific object version can be pinned with `?version=<id>` when enabled
+    let version = if state.config.allow_version_query {
+        query_param(req.uri().query(), "version")
     } else {
-        path.to_string()
+        None
     };
-    
-    let key = if subdomain.is_empty() {
-        path.to_string()
-    } else {
-        format!("{}/{}", subdomain, path)
+
+    // HEAD must report the length and ETag GET would, so with injection it reads the page too
+    let rewrites_head = req.method() == Method::HEAD && inject::is_enabled(&state.config);
+    let get = Method::GET;
+    let fetch = Fetch {
+        method: if rewrites_head { &get } else { req.method() },
+        version: version.as_deref(),
+        accepts_brotli: encoding::accepts(req.headers(), "br"),
+        // Injected HTML needs the whole page, so ranges are then applied after injection
+        range: req
+            .headers()
+            .get("range")
+            .and_then(|r| r.to_str().ok())
+            .filter(|r| req.method() == Method::GET && range::is_single(r) && !inject::is_enabled(&state.config))
+            // `If-Range` is checked against the whole object's validators, and so is revalidation
+            .filter(|_| {
+                !["if-range", "if-none-match", "if-modified-since"]
+                    .iter()
+                    .any(|name| req.headers().contains_key(*name))
+            }),
+        deadline,
     };
 
-    // Determine the file extension
-    let extension = path.split('.').last().unwrap_or_default();
+    // Try each candidate object path in order, serving the first one found
+    let mut paths = resolve_paths(req.uri().path(), &state.config);
+    let negotiated = negotiated_paths(req.uri().path(), req.headers(), &state.config);
+    let negotiable = negotiated.is_some();
+    if let Some(negotiated) = negotiated {
+        paths.splice(0..0, negotiated);
+    }
+    let mut served = None;
+    for path in &paths {
+        let candidate = serve_object(state, &fetch, site_prefix, path).await?;
+        let found = candidate.status() != StatusCode::NOT_FOUND;
+        served = Some((path, candidate));
+        if found {
+            break;
+        }
+    }
+    // Only a file path with a stray trailing slash resolves to nothing
+    let no_path = String::new();
+    let (path, mut response) = served.unwrap_or_else(|| {
+        let mut response = build_response(StatusCode::NOT_FOUND, HeaderMap::new(), Bytes::from("Not Found"));
+        response.extensions_mut().insert(MissingObject);
+        (&no_path, response)
+    });
+    if negotiable {
+        response.headers_mut().append("vary", HeaderValue::from_static("Accept"));
+    }
+    // Objects whose type the client didn't ask for are refused. A request without `Accept` takes anything.
+    if state.config.enforce_accept && response.status().is_success() {
+        let accept = req.headers().get("accept").and_then(|a| a.to_str().ok());
+        let content_type = response.headers().get("content-type").and_then(|ct| ct.to_str().ok());
+        if let (Some(accept), Some(content_type)) = (accept, content_type) {
+            if !accept::allows(accept, content_type) {
+                response = Response::builder()
+                    .status(406)
+                    .body(Full::new(Bytes::from("Not Acceptable")))
+                    .unwrap();
+            }
+        }
+        if !negotiable {
+            response.headers_mut().append("vary", HeaderValue::from_static("Accept"));
+        }
+    }
+    if req.method() == Method::GET {
+        response = prefetch::apply(state, site_prefix, path, response).await;
+    }
+    if req.method() == Method::GET || rewrites_head {
+        response = inject::apply(&state.config, response).await;
+    }
+    if rewrites_head {
+        let (mut parts, body) = response.into_parts();
+        let content_length = body.collect().await.unwrap().to_bytes().len();
+        parts.headers.insert("content-length", HeaderValue::from(content_length));
+        response = Response::from_parts(parts, Full::new(Bytes::new()));
+    }
+    // Checked against the validators of the body as served, before taking a range of it
+    if req.method() == Method::GET || req.method() == Method::HEAD {
+        response = conditional::apply(req.headers(), response);
+    }
+    if req.method() == Method::GET {
+        response = range::apply(req.headers().get("range"), req.headers().get("if-range"), response).await;
+    }
+    if response.status().is_success() {
+        disposition::apply(&state.config, path, response.headers_mut());
+        preload::apply(&state.config.preload_links, &subdomain, path, response.headers_mut());
+        if let Some(header) = &state.config.cache_tag_header {
+            // Tags let a CDN purge groups of objects at once
+            let tags = rules::matching(&state.config.cache_tags, &subdomain, path);
+            if let (false, Ok(value)) = (tags.is_empty(), HeaderValue::from_str(&tags.join(","))) {
+                response.headers_mut().insert(HeaderName::from_bytes(header.as_bytes())?, value);
+            }
+        }
+        // Meant for the CDN in front of the proxy, which consumes them instead of passing them on
+        let surrogate_control = state.config.surrogate_control.for_site(&subdomain);
+        insert_header(response.headers_mut(), "surrogate-control", surrogate_control);
+        let cdn_cache_control = state.config.cdn_cache_control.for_site(&subdomain);
+        insert_header(response.headers_mut(), "cdn-cache-control", cdn_cache_control);
+    }
+
+    // Apex requests can fall back to a landing response instead of the plain 404
+    if subdomain.is_empty() && !custom_domain && ab_variant.is_none() && response.status() == StatusCode::NOT_FOUND {
+        match &state.config.apex_response {
+            Some(ApexResponse::Redirect(url)) => {
+                response = Response::builder()
+                    .status(302)
+                    .header("Location", url)
+                    .body(Full::new(Bytes::from("Redirecting...")))
+                    .unwrap();
+            }
+            Some(ApexResponse::Message(message)) => {
+                response = Response::builder()
+                    .status(200)
+                    .header("content-type", "text/plain; charset=utf-8")
+                    .body(Full::new(Bytes::from(message.clone())))
+                    .unwrap();
+            }
+            None => {}
+        }
+    }
+
+    // Error documents from the site itself, falling back to the plain text response when missing
+    let mut branded = false;
+    if let Some(document) = state.config.error_page(response.status()).filter(|_| req.method() == Method::GET) {
+        let page = serve_object(state, &Fetch { range: None, ..fetch }, site_prefix, document).await?;
+        if page.status() == StatusCode::OK {
+            let (mut parts, _) = response.into_parts();
+            let (page_parts, body) = page.into_parts();
+            if let Some(content_type) = page_parts.headers.get("content-type") {
+                parts.headers.insert("content-type", content_type.clone());
+            }
+            response = Response::from_parts(parts, body);
+            branded = true;
+        }
+    }
+
+    // A branded 404 page showing what was requested, so users can report exactly what they saw
+    if let Some(template) = &state.config.not_found_page {
+        if req.method() == Method::GET && response.status() == StatusCode::NOT_FOUND && !branded {
+            response = not_found::render(template, host, req.uri().path(), request_id, response);
+        }
+    }
+
+    if state.config.language_routing {
+        response.headers_mut().append("vary", HeaderValue::from_static("Accept-Language"));
+    }
+    if let Some((_, variant)) = ab_variant {
+        let headers = response.headers_mut();
+        headers.insert("X-AB-Variant", HeaderValue::from_static(variant.as_str()));
+        headers.insert("Set-Cookie", HeaderValue::from_str(&variant.cookie())?);
+        // The variant depends on the cookie or the client IP, so a shared cache would serve
+        // whichever it saw first to both groups
+        make_private(headers);
+    }
+
+    Ok(response)
+}
+
+// Serve an object path within a site, either from S3 or by redirecting to the public bucket
+async fn serve_object(
+    state: &Arc<AppState>,
+    fetch: &Fetch<'_>,
+    site_prefix: &str,
+    path: &str,
+) -> Result<Response<Full<Bytes>>> {
+    let key = object_key(&state.config, site_prefix, path);
 
-    // Check if the extension is html, htm, or js, or json
-    if extension != "html" && extension != "htm" && extension != "js" && extension != "json" {
+    // Only SERVED_EXTENSIONS pass through the proxy
+    if !state.config.serves_path(path) {
         // Redirect to the specified URL
-        let redirect_url = format!("https://r2.naru.pub/{}/{}", subdomain, path);
+        let redirect_url = format!("https://r2.naru.pub/{}", key);
         return Ok(Response::builder()
             .status(302) // HTTP status code for redirection
             .header("Location", redirect_url)
@@ -137,28 +822,2353 @@ async fn handle_request(
             .unwrap());
     }
 
+    let response = serve_encoded(state, fetch, &key, path).await?;
+
+    // Objects the site doesn't have may be shared assets. A pinned version belongs to the site's key.
+    match &state.config.shared_assets_prefix {
+        Some(shared_prefix) if fetch.version.is_none() && response.extensions().get::<MissingObject>().is_some() => {
+            let shared_key = object_key(&state.config, shared_prefix, path);
+            serve_encoded(state, fetch, &shared_key, path).await
+        }
+        _ => Ok(response),
+    }
+}
+
+// Serve a key, preferring a precompressed `{key}.br` object and falling back to the original when there is none
+async fn serve_encoded(
+    state: &Arc<AppState>,
+    fetch: &Fetch<'_>,
+    key: &str,
+    path: &str,
+) -> Result<Response<Full<Bytes>>> {
+    // A pinned version belongs to the original key, so it is always served as is
+    let precompressed = state.config.precompressed_brotli && fetch.version.is_none();
+    if precompressed && fetch.accepts_brotli {
+        let brotli_key = format!("{}.br", key);
+        let mut response = serve_key(state, fetch, &brotli_key, path).await?;
+        if response.status().is_success() {
+            response.extensions_mut().insert(ServedKey(brotli_key));
+            return Ok(override_content_type(&state.config, key, encoding::mark_brotli(response)));
+        }
+        if response.status() != StatusCode::NOT_FOUND {
+            return Ok(response);
+        }
+    }
+
+    let mut response = serve_key(state, fetch, key, path).await?;
+    if precompressed {
+        encoding::add_vary(response.headers_mut());
+    }
+    response.extensions_mut().insert(ServedKey(key.to_string()));
+    Ok(override_content_type(&state.config, key, response))
+}
+
+// Replace whatever type S3 stored or was guessed for a key listed in CONTENT_TYPE_OVERRIDES
+fn override_content_type(config: &Config, key: &str, mut response: Response<Full<Bytes>>) -> Response<Full<Bytes>> {
+    if response.status().is_success() {
+        if let Some(content_type) = config.content_type_override(key) {
+            response
+                .headers_mut()
+                .insert("content-type", HeaderValue::from_str(content_type).unwrap());
+        }
+    }
+    response
+}
+
+// Serve a single S3 key, from the cache when possible
+async fn serve_key(
+    state: &Arc<AppState>,
+    fetch: &Fetch<'_>,
+    key: &str,
+    path: &str,
+) -> Result<Response<Full<Bytes>>> {
+    // Pinned versions are cached separately from the current object
+    let cache_key = match fetch.version {
+        Some(version) => format!("{}?version={}", key, version),
+        None => key.to_string(),
+    };
+
+    // Serve from the cache when possible
+    let stale = match state.cache.get(&cache_key) {
+        Some((cached, Freshness::Fresh)) => return Ok(cached_response(fetch, cached, CacheStatus::Hit)),
+        Some((cached, Freshness::Stale)) => {
+            METRICS.cache_stale_hits.inc();
+            refresh_in_background(state, fetch.version, key, path, cache_key);
+            return Ok(cached_response(fetch, cached, CacheStatus::Stale));
+        }
+        Some((cached, Freshness::StaleIfError)) => Some(cached),
+        None => None,
+    };
+
+    let response = fetch_key(state, fetch, key, path, cache_key).await?;
+    match stale {
+        Some(cached) if is_failed_fetch(&response) => {
+            METRICS.cache_stale_error_hits.inc();
+            Ok(cached_response(fetch, cached, CacheStatus::Stale))
+        }
+        _ => Ok(response),
+    }
+}
+
+fn cached_response(fetch: &Fetch<'_>, cached: CachedResponse, cache_status: CacheStatus) -> Response<Full<Bytes>> {
+    let mut response = if fetch.method == Method::HEAD {
+        let content_length = cached.body.len() as u64;
+        head_response(cached.status, cached.headers, content_length)
+    } else {
+        build_response(cached.status, cached.headers, cached.body)
+    };
+    response.extensions_mut().insert(cache_status);
+    // Only missing keys are negatively cached
+    if cached.status == StatusCode::NOT_FOUND {
+        response.extensions_mut().insert(MissingObject);
+    }
+    response
+}
+
+// Fetch an expired entry again without making the client wait. A failed refresh leaves the
+// stale entry in place for CACHE_STALE_IF_ERROR_SECS.
+fn refresh_in_background(state: &Arc<AppState>, version: Option<&str>, key: &str, path: &str, cache_key: String) {
+    if !state.cache.start_refresh(&cache_key) {
+        return;
+    }
+    let state = state.clone();
+    let version = version.map(str::to_string);
+    let (key, path) = (key.to_string(), path.to_string());
+    tokio::spawn(async move {
+        let method = Method::GET;
+        let fetch = Fetch {
+            method: &method,
+            version: version.as_deref(),
+            accepts_brotli: false,
+            range: None,
+            deadline: None,
+        };
+        match fetch_key(&state, &fetch, &key, &path, cache_key.clone()).await {
+            Ok(response) if is_failed_fetch(&response) => {
+                warn!("Refreshing {} failed with {}", key, response.status());
+            }
+            // The object is no longer cacheable, so the stale copy must not outlive it
+            Ok(response) if response.extensions().get::<CacheStatus>().is_none() => {
+                state.cache.remove_all(std::slice::from_ref(&cache_key));
+            }
+            Ok(_) => {}
+            Err(err) => warn!("Refreshing {} failed: {}", key, err),
+        }
+        state.cache.finish_refresh(&cache_key);
+    });
+}
+
+// S3 failures other than a missing key are reported as 404s without `MissingObject`
+fn is_failed_fetch(response: &Response<Full<Bytes>>) -> bool {
+    response.status().is_server_error()
+        || (response.status() == StatusCode::NOT_FOUND && response.extensions().get::<MissingObject>().is_none())
+}
+
+// Fetch a key from S3, caching the response if allowed
+async fn fetch_key(
+    state: &Arc<AppState>,
+    fetch: &Fetch<'_>,
+    key: &str,
+    path: &str,
+    cache_key: String,
+) -> Result<Response<Full<Bytes>>> {
+    if fetch.method == Method::HEAD {
+        return serve_head(state, fetch, key, path).await;
+    }
+
+    // Fail fast while the upstream is known to be down
+    if !state.breaker.try_acquire() {
+        return Ok(service_unavailable());
+    }
+    // Held until the body has been read
+    let Some(permit) = state.s3_limiter.acquire().await else {
+        return Ok(throttled(state));
+    };
+
     // Get the object from S3
-    match s3_client
-        .get_object()
-        .bucket(&bucket_name)
-        .key(key)
-        .send()
-        .await
-    {
+    let request = ObjectRequest {
+        key,
+        version: fetch.version,
+        range: fetch.range,
+        deadline: fetch.deadline,
+    };
+
+    let mut retried = false;
+    let result = loop {
+        let result = state.objects.get_object(request).await;
+        METRICS.s3_requests.inc();
+        match &result {
+            Err(err) if is_upstream_failure(err) => {
+                METRICS.s3_errors.inc();
+                state.breaker.record_failure();
+            }
+            _ => state.breaker.record_success(),
+        }
+        let mut resp = match result {
+            Ok(resp) => resp,
+            Err(err) => break Err(err),
+        };
+
+        // With checksum mode enabled the SDK verifies the body as it is read, and
+        // stalled stream protection aborts a transfer that stops making progress.
+        // Either is likely transient, so the object is fetched once more.
+        // The whole body is read before any of it is sent, so a corrupted object never reaches the client.
+        match std::mem::take(&mut resp.body).collect().await {
+            Ok(data) => break Ok((resp, data.into_bytes())),
+            Err(err) if is_checksum_mismatch(&err) => {
+                error!("Checksum mismatch reading {} from S3: {}", key, DisplayErrorContext(&err));
+                METRICS.checksum_failures.inc();
+                if retried {
+                    return Ok(bad_gateway());
+                }
+                retried = true;
+            }
+            Err(err) if !retried => {
+                warn!("Aborted reading {} from S3, retrying: {}", key, DisplayErrorContext(&err));
+                retried = true;
+            }
+            Err(err) => {
+                warn!("Aborted reading {} from S3: {}", key, DisplayErrorContext(&err));
+                return Ok(bad_gateway());
+            }
+        }
+    };
+
+    match result {
+        Ok((resp, data)) => {
+            let mut headers = HeaderMap::new();
+            // Objects can carry a website redirect in their metadata
+            let (status, body) = match resp.website_redirect_location.as_deref().map(HeaderValue::from_str) {
+                Some(Ok(location)) => {
+                    headers.insert("location", location);
+                    (StatusCode::MOVED_PERMANENTLY, Bytes::from("Redirecting..."))
+                }
+                // Only part of the object was fetched
+                _ if resp.content_range.is_some() => {
+                    let content_type = content_type_for(&state.config, key, path, resp.content_type.as_deref(), None);
+                    insert_header(&mut headers, "content-type", content_type);
+                    insert_header(&mut headers, "content-disposition", resp.content_disposition.as_deref());
+                    insert_header(&mut headers, "etag", resp.e_tag.as_deref());
+                    insert_header(&mut headers, "last-modified", http_date(resp.last_modified.as_ref()).as_deref());
+                    insert_header(&mut headers, "content-range", resp.content_range.as_deref());
+                    headers.insert("accept-ranges", HeaderValue::from_static("bytes"));
+                    let cache_control = cache_control_for(&state.config, path, content_type, resp.cache_control.as_deref());
+                    insert_header(&mut headers, "cache-control", Some(&cache_control));
+                    (StatusCode::PARTIAL_CONTENT, data)
+                }
+                _ => {
+                    let content_type = content_type_for(&state.config, key, path, resp.content_type.as_deref(), Some(&data));
+                    insert_header(&mut headers, "content-type", content_type);
+                    insert_header(&mut headers, "content-disposition", resp.content_disposition.as_deref());
+                    insert_header(&mut headers, "etag", resp.e_tag.as_deref());
+                    insert_header(&mut headers, "last-modified", http_date(resp.last_modified.as_ref()).as_deref());
+                    insert_header(&mut headers, "x-amz-checksum-crc32", resp.checksum_crc32.as_deref());
+                    insert_header(&mut headers, "x-amz-checksum-crc32c", resp.checksum_crc32_c.as_deref());
+                    insert_header(&mut headers, "x-amz-checksum-sha1", resp.checksum_sha1.as_deref());
+                    insert_header(&mut headers, "x-amz-checksum-sha256", resp.checksum_sha256.as_deref());
+                    if state.config.emit_digest_header {
+                        digest::insert(&mut headers, resp.e_tag.as_deref());
+                    }
+                    let cache_control = cache_control_for(&state.config, path, content_type, resp.cache_control.as_deref());
+                    insert_header(&mut headers, "cache-control", Some(&cache_control));
+                    (StatusCode::OK, data)
+                }
+            };
+            limit_header_size(&state.config, key, &mut headers);
+            let cached = status != StatusCode::PARTIAL_CONTENT
+                && cache_response(state, cache_key, status, &headers, &body, resp.cache_control.as_deref());
+            let mut response = build_response(status, headers, body);
+            if cached {
+                response.extensions_mut().insert(CacheStatus::Miss);
+            }
+            Ok(response)
+        }
+        // Fetch the whole object so the range is rejected with its full length in `Content-Range`
+        Err(SdkError::ServiceError(err)) if fetch.range.is_some() && err.raw().status().as_u16() == 416 => {
+            drop(permit);
+            Box::pin(serve_key(state, &Fetch { range: None, ..*fetch }, key, path)).await
+        }
+        Err(SdkError::TimeoutError(_)) => {
+            warn!("Timed out fetching {} from S3", key);
+            Ok(gateway_timeout())
+        }
+        Err(err) if is_throttled(&err) => {
+            warn!("S3 throttled the request for {}", key);
+            METRICS.s3_throttled.inc();
+            Ok(throttled(state))
+        }
+        Err(err) => {
+            warn!("Error fetching from S3: {}", err);
+            let status = upstream_error_status(&state.config, &err);
+            let body = Bytes::from(status.canonical_reason().unwrap_or_default());
+            // Only a missing key is worth caching, not a transient failure
+            let missing = err.as_service_error().is_some_and(|e| e.is_no_such_key());
+            let cached = missing && cache_response(state, cache_key, status, &HeaderMap::new(), &body, None);
+            let mut response = build_response(status, HeaderMap::new(), body);
+            if cached {
+                response.extensions_mut().insert(CacheStatus::Miss);
+            }
+            if missing {
+                response.extensions_mut().insert(MissingObject);
+            }
+            Ok(response)
+        }
+    }
+}
+
+// Answer a HEAD request from object metadata without downloading the body
+async fn serve_head(state: &AppState, fetch: &Fetch<'_>, key: &str, path: &str) -> Result<Response<Full<Bytes>>> {
+    if !state.breaker.try_acquire() {
+        return Ok(service_unavailable());
+    }
+    let Some(_permit) = state.s3_limiter.acquire().await else {
+        return Ok(throttled(state));
+    };
+
+    let request = ObjectRequest {
+        key,
+        version: fetch.version,
+        range: None,
+        deadline: fetch.deadline,
+    };
+    let result = state.objects.head_object(request).await;
+    METRICS.s3_requests.inc();
+    match &result {
+        Err(err) if is_upstream_failure(err) => {
+            METRICS.s3_errors.inc();
+            state.breaker.record_failure();
+        }
+        _ => state.breaker.record_success(),
+    }
+
+    match result {
         Ok(resp) => {
-            let data = resp.body.collect().await?.into_bytes();
-            Ok(Response::builder()
-                .status(200)
-                .header("content-type", resp.content_type.unwrap_or_default())
-                .body(Full::new(data))
-                .unwrap())
+            let mut headers = HeaderMap::new();
+            if let Some(location) = resp.website_redirect_location.as_deref().and_then(|l| HeaderValue::from_str(l).ok()) {
+                headers.insert("location", location);
+                return Ok(head_response(StatusCode::MOVED_PERMANENTLY, headers, "Redirecting...".len() as u64));
+            }
+            let content_type = content_type_for(&state.config, key, path, resp.content_type.as_deref(), None);
+            insert_header(&mut headers, "content-type", content_type);
+            insert_header(&mut headers, "content-disposition", resp.content_disposition.as_deref());
+            insert_header(&mut headers, "etag", resp.e_tag.as_deref());
+            insert_header(&mut headers, "last-modified", http_date(resp.last_modified.as_ref()).as_deref());
+            insert_header(&mut headers, "x-amz-checksum-crc32", resp.checksum_crc32.as_deref());
+            insert_header(&mut headers, "x-amz-checksum-crc32c", resp.checksum_crc32_c.as_deref());
+            insert_header(&mut headers, "x-amz-checksum-sha1", resp.checksum_sha1.as_deref());
+            insert_header(&mut headers, "x-amz-checksum-sha256", resp.checksum_sha256.as_deref());
+            if state.config.emit_digest_header {
+                digest::insert(&mut headers, resp.e_tag.as_deref());
+            }
+            let cache_control = cache_control_for(&state.config, path, content_type, resp.cache_control.as_deref());
+            insert_header(&mut headers, "cache-control", Some(&cache_control));
+            limit_header_size(&state.config, key, &mut headers);
+            let content_length = resp.content_length.unwrap_or_default().max(0) as u64;
+            Ok(head_response(StatusCode::OK, headers, content_length))
+        }
+        Err(SdkError::TimeoutError(_)) => {
+            warn!("Timed out fetching metadata for {} from S3", key);
+            Ok(gateway_timeout())
+        }
+        Err(err) if is_throttled(&err) => {
+            warn!("S3 throttled the metadata request for {}", key);
+            METRICS.s3_throttled.inc();
+            Ok(throttled(state))
         }
         Err(err) => {
-            eprintln!("Error fetching from S3: {}", err);
-            Ok(Response::builder()
-                .status(404)
-                .body(Full::new(Bytes::from("Not Found")))
-                .unwrap())
+            warn!("Error fetching metadata from S3: {}", err);
+            let status = upstream_error_status(&state.config, &err);
+            let length = status.canonical_reason().unwrap_or_default().len() as u64;
+            let mut response = head_response(status, HeaderMap::new(), length);
+            if err.as_service_error().is_some_and(|e| e.is_not_found()) {
+                response.extensions_mut().insert(MissingObject);
+            }
+            Ok(response)
         }
     }
 }
+
+// The stored type, unless S3 only has a generic one. Then the key's extension decides, and only
+// an unknown extension is sniffed from the body, with SNIFF_CONTENT_TYPE. A precompressed
+// `{key}.br` object is typed by the key it stands in for, never by its own metadata or bytes.
+fn content_type_for<'a>(
+    config: &Config,
+    key: &str,
+    path: &str,
+    stored: Option<&'a str>,
+    body: Option<&[u8]>,
+) -> Option<&'a str> {
+    let identity = key.strip_suffix(".br").filter(|_| !path.ends_with(".br"));
+    let stored = stored.filter(|_| identity.is_none());
+    match stored {
+        Some(content_type) if !sniff::is_generic(content_type) => Some(content_type),
+        stored => sniff::for_extension(identity.unwrap_or(key))
+            .or_else(|| body.filter(|_| config.sniff_content_type && identity.is_none()).and_then(sniff::content_type))
+            .or(stored),
+    }
+}
+
+// HEAD responses report the full length and never apply a `Range`, so clients can plan range requests
+fn head_response(status: StatusCode, mut headers: HeaderMap, content_length: u64) -> Response<Full<Bytes>> {
+    headers.insert("accept-ranges", HeaderValue::from_static("bytes"));
+    headers.insert("content-length", HeaderValue::from(content_length));
+    build_response(status, headers, Bytes::new())
+}
+
+// Whether an S3 error means the upstream itself is failing, rather than e.g. a missing object
+fn is_upstream_failure<E>(err: &SdkError<E, HttpResponse>) -> bool {
+    match err {
+        SdkError::ServiceError(service_err) => service_err.raw().status().is_server_error(),
+        SdkError::ConstructionFailure(_) => false,
+        _ => true,
+    }
+}
+
+// Whether S3 asked us to slow down, e.g. R2's `SlowDown`
+fn is_throttled<E: ProvideErrorMetadata>(err: &SdkError<E, HttpResponse>) -> bool {
+    match err {
+        SdkError::ServiceError(service_err) => {
+            service_err.raw().status().as_u16() == 429 || matches!(err.code(), Some("SlowDown" | "TooManyRequests"))
+        }
+        _ => false,
+    }
+}
+
+// The status shown to clients for an S3 error, 404 unless UPSTREAM_ERROR_STATUSES exposes it
+fn upstream_error_status<E: ProvideErrorMetadata>(config: &Config, err: &SdkError<E, HttpResponse>) -> StatusCode {
+    let SdkError::ServiceError(service_err) = err else {
+        return StatusCode::NOT_FOUND;
+    };
+    let upstream = service_err.raw().status().as_u16().to_string();
+    err.code()
+        .and_then(|code| config.upstream_error_statuses.get(code))
+        .or_else(|| config.upstream_error_statuses.get(&upstream))
+        .and_then(|&status| StatusCode::from_u16(status).ok())
+        .unwrap_or(StatusCode::NOT_FOUND)
+}
+
+// Tell clients and CDNs to back off instead of retrying straight away
+fn throttled(state: &AppState) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(503)
+        .header("Retry-After", state.config.throttle_retry_after_secs)
+        .body(Full::new(Bytes::from("Service Unavailable")))
+        .unwrap()
+}
+
+// Whether reading a body failed because it didn't match the checksum S3 sent
+fn is_checksum_mismatch(err: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if err.is::<aws_smithy_checksums::body::validate::Error>() {
+            return true;
+        }
+        source = err.source();
+    }
+    false
+}
+
+fn bad_gateway() -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(502)
+        .body(Full::new(Bytes::from("Bad Gateway")))
+        .unwrap()
+}
+
+fn gateway_timeout() -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(504)
+        .body(Full::new(Bytes::from("Gateway Timeout")))
+        .unwrap()
+}
+
+fn service_unavailable() -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(503)
+        .body(Full::new(Bytes::from("Service Unavailable")))
+        .unwrap()
+}
+
+// Cache a response unless its status or upstream `Cache-Control` rules it out, returning whether it was cached
+fn cache_response(
+    state: &AppState,
+    key: String,
+    status: StatusCode,
+    headers: &HeaderMap,
+    body: &Bytes,
+    cache_control: Option<&str>,
+) -> bool {
+    if !state.cache.is_enabled() || !state.config.cacheable_statuses.contains(&status.as_u16()) {
+        return false;
+    }
+    let max_ttl = state.config.cache_max_ttl_secs.map_or(Duration::MAX, Duration::from_secs);
+    match cache::ttl_for(cache_control, Duration::from_secs(state.config.cache_ttl_secs)).map(|ttl| ttl.min(max_ttl)) {
+        Some(ttl) => {
+            state.cache.insert(key, status, headers.clone(), body.clone(), ttl);
+            true
+        }
+        None => false,
+    }
+}
+
+// Content-hashed filenames never change, so they can be cached forever regardless of S3 metadata.
+// Pages are exempt: a date or hex word in an HTML filename is not a content hash, and pages get updated.
+fn cache_control_for(config: &Config, path: &str, content_type: Option<&str>, upstream: Option<&str>) -> String {
+    let filename = path.rsplit('/').next().unwrap_or_default();
+    let html = content_type.is_some_and(|ct| ct.trim_start().to_ascii_lowercase().starts_with("text/html"));
+    if !html && config.immutable_asset_pattern.is_match(filename) {
+        return "public, max-age=31536000, immutable".to_string();
+    }
+    match (upstream, config.max_cache_control_age) {
+        (Some(upstream), Some(ceiling)) => cache::clamp_max_age(upstream, ceiling),
+        (Some(upstream), None) => upstream.to_string(),
+        (None, _) => "public, max-age=3600".to_string(),
+    }
+}
+
+fn http_date(time: Option<&DateTime>) -> Option<String> {
+    time.and_then(|time| time.fmt(DateTimeFormat::HttpDate).ok())
+}
+
+// Indexing rules for a request, applied to error responses as well as objects
+fn x_robots_tag(config: &Config, host: &str, path: &str) -> Option<String> {
+    if let Some(staging) = config.x_robots_tag_staging.as_ref().filter(|_| config.staging) {
+        return Some(staging.clone());
+    }
+    let subdomain = subdomain_of(host, config);
+    let path = resolve_paths(path, config).into_iter().next().unwrap_or_default();
+    let overrides = rules::matching(&config.x_robots_tag_rules, &subdomain, &path);
+    if !overrides.is_empty() {
+        return Some(overrides.join(", "));
+    }
+    config.x_robots_tag.clone()
+}
+
+// Copy an object metadata value into a response header, skipping values that aren't valid headers
+fn insert_header(headers: &mut HeaderMap, name: &'static str, value: Option<&str>) {
+    if let Some(value) = value.and_then(|v| HeaderValue::from_str(v).ok()) {
+        headers.insert(name, value);
+    }
+}
+
+// Drop headers taken from object metadata, least important first, until they fit
+// MAX_RESPONSE_HEADER_SIZE_BYTES. `Content-Type`, `Location` and range headers are always kept.
+fn limit_header_size(config: &Config, key: &str, headers: &mut HeaderMap) {
+    const DROP_ORDER: [&str; 10] = [
+        "x-amz-checksum-crc32",
+        "x-amz-checksum-crc32c",
+        "x-amz-checksum-sha1",
+        "x-amz-checksum-sha256",
+        "digest",
+        "content-md5",
+        "content-disposition",
+        "last-modified",
+        "etag",
+        "cache-control",
+    ];
+    // As written on the wire: `name: value\r\n`
+    let size = |headers: &HeaderMap| -> usize {
+        headers.iter().map(|(name, value)| name.as_str().len() + value.len() + 4).sum()
+    };
+    if size(headers) <= config.max_response_header_size_bytes {
+        return;
+    }
+
+    let mut dropped = Vec::new();
+    for name in DROP_ORDER {
+        if size(headers) <= config.max_response_header_size_bytes {
+            break;
+        }
+        if headers.remove(name).is_some() {
+            dropped.push(name);
+        }
+    }
+    warn!(
+        "Headers for {} exceeded {} bytes; dropped {:?}, leaving {} bytes",
+        key,
+        config.max_response_header_size_bytes,
+        dropped,
+        size(headers)
+    );
+}
+
+fn build_response(status: StatusCode, headers: HeaderMap, body: Bytes) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(body));
+    *response.status_mut() = status;
+    *response.headers_mut() = headers;
+    response
+}
+
+// Evict cached objects for a single path (`PURGE`) or a list of paths (`POST /_purge`)
+async fn handle_purge(
+    req: Request<hyper::body::Incoming>,
+    state: &AppState,
+    subdomain: &str,
+) -> Result<Response<Full<Bytes>>> {
+    let Some(api_key) = &state.config.purge_api_key else {
+        return Ok(Response::builder()
+            .status(405)
+            .body(Full::new(Bytes::from("Method Not Allowed")))
+            .unwrap());
+    };
+
+    if !has_bearer_token(&req, api_key) {
+        return Ok(unauthorized());
+    }
+
+    let paths = if req.method() == Method::POST {
+        let body = req.into_body().collect().await?.to_bytes();
+        match serde_json::from_slice::<PurgeRequest>(&body) {
+            Ok(purge) => purge.paths,
+            Err(_) => {
+                return Ok(Response::builder()
+                    .status(400)
+                    .body(Full::new(Bytes::from("Bad Request")))
+                    .unwrap());
+            }
+        }
+    } else {
+        vec![req.uri().path().to_string()]
+    };
+
+    // Under A/B testing a path may be cached under either variant's prefix, and under language
+    // routing within each language's prefix. Missing objects may have been served as shared assets.
+    let site_prefixes: Vec<&str> = match &state.config.ab_test {
+        Some(ab) => vec![&ab.prefix_a, &ab.prefix_b],
+        None => vec![site_prefix_for(&state.config, subdomain)],
+    };
+    let mut prefixes: Vec<String> = site_prefixes.iter().map(|prefix| prefix.to_string()).collect();
+    if state.config.language_routing {
+        for prefix in &site_prefixes {
+            prefixes.extend(state.config.language_routes.values().map(|language| join_key(&[prefix, language])));
+        }
+    }
+    prefixes.extend(state.config.shared_assets_prefix.clone());
+    let keys: Vec<String> = paths
+        .iter()
+        .flat_map(|path| resolve_paths(path, &state.config))
+        .flat_map(|path| prefixes.iter().map(move |prefix| object_key(&state.config, prefix, &path)))
+        .flat_map(|key| [format!("{}.br", key), key])
+        .collect();
+    // Versions pinned with `?version=` are cached under `{key}?version=...`
+    let versioned: Vec<String> = keys.iter().map(|key| format!("{}?version=", key)).collect();
+    let purged = state.cache.remove_all(&keys) + state.cache.remove_prefixes(&versioned);
+
+    Ok(Response::builder()
+        .status(if purged > 0 { 200 } else { 404 })
+        .header("content-type", "application/json")
+        .body(Full::new(Bytes::from(serde_json::json!({ "purged": purged }).to_string())))
+        .unwrap())
+}
+
+// Store (`PUT`, or `PATCH`, which replaces the whole object as S3 has no partial writes) or
+// delete (`DELETE`) an object under WRITE_PATH_PREFIX within the site. Bodies are streamed to
+// S3 as they arrive, so they must declare their length.
+async fn handle_write(
+    req: Request<hyper::body::Incoming>,
+    state: &AppState,
+    subdomain: &str,
+    deadline: Option<Instant>,
+) -> Result<Response<Full<Bytes>>> {
+    let (Some(api_key), Some(write_prefix)) = (&state.config.write_api_key, &state.config.write_path_prefix) else {
+        return Ok(Response::builder()
+            .status(405)
+            .body(Full::new(Bytes::from("Method Not Allowed")))
+            .unwrap());
+    };
+    if !has_bearer_token(&req, api_key) {
+        return Ok(unauthorized());
+    }
+
+    let path = percent_decode_str(req.uri().path()).decode_utf8_lossy();
+    let path = path.trim_start_matches('/');
+    let valid = !path.is_empty() && !path.ends_with('/') && !path.split('/').any(|segment| segment == "..");
+    if !valid || !is_within_prefix(path, write_prefix) {
+        return Ok(Response::builder()
+            .status(403)
+            .body(Full::new(Bytes::from("Forbidden")))
+            .unwrap());
+    }
+    let key = object_key(&state.config, site_prefix_for(&state.config, subdomain), path);
+
+    let content_length = req
+        .headers()
+        .get("content-length")
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.parse::<i64>().ok());
+    let deleting = req.method() == Method::DELETE;
+    if !deleting && content_length.is_none() {
+        return Ok(Response::builder()
+            .status(411)
+            .body(Full::new(Bytes::from("Length Required")))
+            .unwrap());
+    }
+
+    // Fail fast while the upstream is known to be down
+    if !state.breaker.try_acquire() {
+        return Ok(service_unavailable());
+    }
+    let Some(_permit) = state.s3_limiter.acquire().await else {
+        return Ok(throttled(state));
+    };
+    let request_payer = state.config.request_payer.then_some(RequestPayer::Requester);
+    let status = if deleting {
+        let builder = state
+            .s3_client
+            .delete_object()
+            .bucket(&state.config.bucket_name)
+            .key(&key)
+            .set_request_payer(request_payer);
+        let result = match deadline {
+            Some(deadline) => builder.customize().config_override(operation_timeout(deadline)).send().await,
+            None => builder.send().await,
+        };
+        record_upstream_result(state, &result);
+        result.map(|_| StatusCode::NO_CONTENT).map_err(|err| DisplayErrorContext(err).to_string())
+    } else {
+        let content_type = req
+            .headers()
+            .get("content-type")
+            .and_then(|ct| ct.to_str().ok())
+            .map(str::to_string);
+        let builder = state
+            .s3_client
+            .put_object()
+            .bucket(&state.config.bucket_name)
+            .key(&key)
+            .set_content_type(content_type)
+            .set_content_length(content_length)
+            .set_request_payer(request_payer)
+            .body(ByteStream::from_body_1_x(req.into_body()));
+        let result = match deadline {
+            Some(deadline) => builder.customize().config_override(operation_timeout(deadline)).send().await,
+            None => builder.send().await,
+        };
+        record_upstream_result(state, &result);
+        result.map(|_| StatusCode::CREATED).map_err(|err| DisplayErrorContext(err).to_string())
+    };
+
+    match status {
+        Ok(status) => {
+            state.cache.remove_all(&[key]);
+            Ok(build_response(status, HeaderMap::new(), Bytes::new()))
+        }
+        Err(err) => {
+            warn!("Error writing {} to S3: {}", key, err);
+            Ok(Response::builder()
+                .status(502)
+                .body(Full::new(Bytes::from("Bad Gateway")))
+                .unwrap())
+        }
+    }
+}
+
+// `uploads/a.txt` is within `uploads`, but `uploads-evil/a.txt` isn't. An empty prefix is the whole site.
+fn is_within_prefix(path: &str, prefix: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+    prefix.is_empty() || path.strip_prefix(prefix).is_some_and(|rest| rest.starts_with('/'))
+}
+
+// Count an S3 call towards the metrics and the circuit breaker, as reads do
+fn record_upstream_result<T, E>(state: &AppState, result: &Result<T, SdkError<E, HttpResponse>>) {
+    METRICS.s3_requests.inc();
+    match result {
+        Err(err) if is_upstream_failure(err) => {
+            METRICS.s3_errors.inc();
+            state.breaker.record_failure();
+        }
+        _ => state.breaker.record_success(),
+    }
+}
+
+// Describe how a request is routed, without calling S3
+fn handle_echo(
+    req: &Request<hyper::body::Incoming>,
+    state: &AppState,
+    client_ip: IpAddr,
+    host: &str,
+    path: &str,
+    site_prefix: &str,
+    ab_variant: Option<ab_test::Variant>,
+) -> Response<Full<Bytes>> {
+    let authorized = state
+        .config
+        .echo_api_key
+        .as_deref()
+        .is_some_and(|api_key| has_bearer_token(req, api_key));
+    if !authorized {
+        return unauthorized();
+    }
+
+    let mut headers = serde_json::Map::new();
+    for (name, value) in req.headers() {
+        // Don't reflect the API key
+        let value = if name == "authorization" {
+            "***".into()
+        } else {
+            String::from_utf8_lossy(value.as_bytes()).into_owned()
+        };
+        match headers.get_mut(name.as_str()) {
+            Some(serde_json::Value::String(existing)) => *existing = format!("{}, {}", existing, value),
+            _ => {
+                headers.insert(name.to_string(), value.into());
+            }
+        }
+    }
+    let path = if path.is_empty() { "/" } else { path };
+    let resolved_path = resolve_paths(path, &state.config)
+        .into_iter()
+        .next()
+        .unwrap_or_default();
+    let echo = serde_json::json!({
+        "method": req.method().as_str(),
+        "host": host,
+        "subdomain": subdomain_of(host, &state.config),
+        "raw_path": path,
+        "s3_key": object_key(&state.config, site_prefix, &resolved_path),
+        "resolved_path": resolved_path,
+        "headers": headers,
+        "client_ip": client_ip.to_string(),
+        "ab_variant": ab_variant.map(ab_test::Variant::as_str),
+    });
+
+    Response::builder()
+        .status(200)
+        .header("content-type", "application/json")
+        .body(Full::new(Bytes::from(echo.to_string())))
+        .unwrap()
+}
+
+// Keep a response out of shared caches, e.g. a CDN, while browsers may still cache it for its `max-age`
+fn make_private(headers: &mut HeaderMap) {
+    let directives: Vec<String> = headers
+        .get("cache-control")
+        .and_then(|cc| cc.to_str().ok())
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|directive| {
+            let name = directive.split('=').next().unwrap_or_default().to_ascii_lowercase();
+            !directive.is_empty() && !matches!(name.as_str(), "public" | "private" | "s-maxage")
+        })
+        .map(str::to_string)
+        .collect();
+    let cache_control = std::iter::once("private".to_string()).chain(directives).collect::<Vec<_>>().join(", ");
+    insert_header(headers, "cache-control", Some(&cache_control));
+    // CDN-only directives would otherwise override `private`
+    headers.remove("surrogate-control");
+    headers.remove("cdn-cache-control");
+    headers.append("vary", HeaderValue::from_static("Cookie"));
+}
+
+// Headers every response carries, whichever port it is served on. Browsers must not sniff a
+// body into a type we didn't send, such as HTML inside an image.
+fn finalize_headers(headers: &mut HeaderMap) {
+    headers.insert("X-Content-Type-Options", HeaderValue::from_static("nosniff"));
+}
+
+// Whether the request carries `Authorization: Bearer <api_key>`
+fn has_bearer_token(req: &Request<hyper::body::Incoming>, api_key: &str) -> bool {
+    req.headers()
+        .get("authorization")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .is_some_and(|token| token == api_key)
+}
+
+fn unauthorized() -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(401)
+        .header("WWW-Authenticate", "Bearer")
+        .body(Full::new(Bytes::from("Unauthorized")))
+        .unwrap()
+}
+
+// The first label of the host, or empty for apex requests and hosts without a registrable
+// domain (LOCAL_HOSTS, single labels and IP addresses), so local testing can use a flat bucket
+fn subdomain_of(host: &str, config: &Config) -> String {
+    if is_custom_domain(host, config) {
+        return String::new();
+    }
+    let hostname = hostname_of(host);
+    let is_local = config.local_hosts.iter().any(|local| local.eq_ignore_ascii_case(hostname))
+        || !hostname.contains('.')
+        || hostname.parse::<IpAddr>().is_ok();
+    if is_local {
+        return String::new();
+    }
+
+    // Everything before a known base domain is the subdomain
+    let hostname = hostname.trim_end_matches('.');
+    let lowercase = hostname.to_ascii_lowercase();
+    let labels = config.allowed_domains.iter().find_map(|domain| {
+        let labels = &hostname[..lowercase.strip_suffix(domain.as_str())?.len()];
+        match labels {
+            "" => Some(""),
+            labels => labels.strip_suffix('.'),
+        }
+    });
+    match (labels, &config.subdomain_mode) {
+        (Some(labels), SubdomainMode::Full) => labels.replace('.', &config.subdomain_separator),
+        (Some(labels), SubdomainMode::FirstLabel) => labels.split('.').next().unwrap_or_default().to_string(),
+        // Without ALLOWED_DOMAINS, a registrable domain like `example.com` is taken to be the apex.
+        // `alice.localhost` still names a site, the usual way to test one locally.
+        (None, _) if hostname.split('.').count() == 2 && !lowercase.ends_with(".localhost") => String::new(),
+        (None, _) => hostname.split('.').next().unwrap_or_default().to_string(),
+    }
+}
+
+// The host without its port, e.g. `::1` from `[::1]:8080`
+fn hostname_of(host: &str) -> &str {
+    match host.strip_prefix('[') {
+        Some(ipv6) => ipv6.split(']').next().unwrap_or_default(),
+        None => host.split(':').next().unwrap_or_default(),
+    }
+}
+
+fn is_custom_domain(host: &str, config: &Config) -> bool {
+    config
+        .custom_domain
+        .as_deref()
+        .is_some_and(|domain| hostname_of(host).trim_end_matches('.').eq_ignore_ascii_case(domain))
+}
+
+fn is_trusted_peer(config: &Config, remote_addr: SocketAddr) -> bool {
+    config.trusted_proxy_cidrs.iter().any(|cidr| cidr.contains(&remote_addr.ip()))
+}
+
+// With TRUST_FORWARDED_HOST, whether the peer's `Forwarded` and `X-Forwarded-*` headers are honored
+fn trusts_forwarded_headers(config: &Config, remote_addr: SocketAddr) -> bool {
+    config.trust_forwarded_host && (config.trusted_proxy_cidrs.is_empty() || is_trusted_peer(config, remote_addr))
+}
+
+// Extract the host from the request headers, honoring `Forwarded` or `X-Forwarded-Host` from trusted proxies
+fn request_host(req: &Request<hyper::body::Incoming>, remote_addr: SocketAddr, config: &Config) -> String {
+    let forwarded_host = trusts_forwarded_headers(config, remote_addr)
+        .then(|| {
+            forwarded::param(req.headers(), "host").or_else(|| {
+                req.headers()
+                    .get("x-forwarded-host")
+                    .and_then(|h| h.to_str().ok())
+                    .and_then(|h| h.split(',').next())
+                    .map(|h| h.trim().to_string())
+            })
+        })
+        .flatten()
+        .filter(|h| !h.is_empty());
+
+    forwarded_host
+        .or_else(|| req.headers().get("host").and_then(|h| h.to_str().ok()).map(str::to_string))
+        .unwrap_or_default()
+}
+
+// The scheme the client used. The proxy itself only speaks plain HTTP, so `https` comes from a
+// TLS-terminating proxy's `Forwarded` or `X-Forwarded-Proto`.
+fn request_scheme(req: &Request<hyper::body::Incoming>, remote_addr: SocketAddr, config: &Config) -> &'static str {
+    let forwarded_proto = trusts_forwarded_headers(config, remote_addr)
+        .then(|| {
+            forwarded::param(req.headers(), "proto").or_else(|| {
+                req.headers()
+                    .get("x-forwarded-proto")
+                    .and_then(|p| p.to_str().ok())
+                    .and_then(|p| p.split(',').next())
+                    .map(|p| p.trim().to_string())
+            })
+        })
+        .flatten();
+    match forwarded_proto {
+        Some(proto) if proto.eq_ignore_ascii_case("https") => "https",
+        _ => "http",
+    }
+}
+
+// The `Location` of a redirect. Absolute URLs lose a default port, and with ABSOLUTE_REDIRECTS a
+// path on the site gets the client's scheme and host, e.g. `/docs/` from `https://site.naru.pub/docs`.
+fn build_redirect_location(scheme: &str, host: &str, location: &str, config: &Config) -> String {
+    if location.starts_with('/') && !location.starts_with("//") {
+        if !config.absolute_redirects || host.is_empty() {
+            return location.to_string();
+        }
+        return format!("{}://{}{}", scheme, without_default_port(scheme, host), location);
+    }
+    let Some((location_scheme, rest)) = location.split_once("://") else {
+        return location.to_string();
+    };
+    let (authority, path) = rest.split_at(rest.find(['/', '?', '#']).unwrap_or(rest.len()));
+    format!("{}://{}{}", location_scheme, without_default_port(location_scheme, authority), path)
+}
+
+// `example.com:443` as `example.com` for https, and `:80` for http
+fn without_default_port<'a>(scheme: &str, authority: &'a str) -> &'a str {
+    let default_port = if scheme.eq_ignore_ascii_case("https") {
+        ":443"
+    } else if scheme.eq_ignore_ascii_case("http") {
+        ":80"
+    } else {
+        return authority;
+    };
+    authority.strip_suffix(default_port).unwrap_or(authority)
+}
+
+// The client's address, taken from `Forwarded` or `X-Forwarded-For` only when the peer is listed in
+// TRUSTED_PROXY_CIDRS. Each proxy appends the address it saw, so the chain is read from the right
+// and the first hop that isn't a trusted proxy is the client; anything left of it is the client's own say.
+fn client_ip(headers: &HeaderMap, remote_addr: SocketAddr, config: &Config) -> IpAddr {
+    if !is_trusted_peer(config, remote_addr) {
+        return remote_addr.ip();
+    }
+    let mut nodes = forwarded::for_nodes(headers);
+    if nodes.is_empty() {
+        nodes = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(|node| node.trim().to_string())
+            .collect();
+    }
+    let mut client = remote_addr.ip();
+    for node in nodes.iter().rev() {
+        // An obfuscated node hides everything before it
+        let Some(ip) = forwarded::node_ip(node) else {
+            break;
+        };
+        client = ip;
+        if !config.trusted_proxy_cidrs.iter().any(|cidr| cidr.contains(&ip)) {
+            break;
+        }
+    }
+    client
+}
+
+// Find and decode a query string parameter
+fn query_param(query: Option<&str>, name: &str) -> Option<String> {
+    query?
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .and_then(|(_, value)| percent_decode_str(value).decode_utf8().ok())
+        .map(|value| value.to_string())
+        .filter(|value| !value.is_empty())
+}
+
+// With CONTENT_NEGOTIATION, the served variants of an extensionless path that `Accept` names,
+// tried before its usual lookup, or `None` for paths that aren't negotiated
+fn negotiated_paths(path: &str, headers: &HeaderMap, config: &Config) -> Option<Vec<String>> {
+    if !config.content_negotiation {
+        return None;
+    }
+    let path = percent_decode_str(path.trim_start_matches('/')).decode_utf8().ok()?;
+    let name = path.rsplit('/').next().unwrap_or_default();
+    if name.is_empty() || name.contains('.') {
+        return None;
+    }
+    let accept = headers.get("accept").and_then(|a| a.to_str().ok()).unwrap_or_default();
+    Some(
+        accept::preferred_extensions(accept, &config.content_negotiation_types)
+            .into_iter()
+            .map(|extension| format!("{}.{}", path, extension))
+            .filter(|variant| config.serves_path(variant))
+            .collect(),
+    )
+}
+
+// Map a request path to candidate object paths within a site, in lookup order
+fn resolve_paths(path: &str, config: &Config) -> Vec<String> {
+    let path = path.trim_start_matches('/');
+    // URL decode the path
+    let mut path = percent_decode_str(path)
+        .decode_utf8()
+        .unwrap_or_default()
+        .to_string();
+
+    // A trailing slash after a file name like `style.css/` is usually a typo
+    let is_file_with_slash = path.strip_suffix('/').is_some_and(|dir| {
+        let name = dir.rsplit('/').next().unwrap_or_default();
+        name.contains('.') && !name.starts_with('.')
+    });
+    if is_file_with_slash {
+        match config.trailing_slash_files {
+            TrailingSlashFiles::Index => {}
+            TrailingSlashFiles::Strip => {
+                path.pop();
+            }
+            TrailingSlashFiles::NotFound => return Vec::new(),
+        }
+    }
+
+    // Handle directory paths, empty paths, and paths without extensions
+    let index_documents = |directory: &str| -> Vec<String> {
+        config.index_documents.iter().map(|name| format!("{}{}", directory, name)).collect()
+    };
+    if path.is_empty() || path.ends_with('/') {
+        index_documents(&path)
+    } else if !path.contains('.') {
+        config
+            .clean_urls
+            .iter()
+            .flat_map(|clean_url| match clean_url {
+                CleanUrl::Html => vec![format!("{}.html", path)],
+                CleanUrl::Index => index_documents(&format!("{}/", path)),
+            })
+            .collect()
+    } else {
+        vec![path.to_string()]
+    }
+}
+
+// Key prefix for a site outside of A/B testing. Apex requests have an empty subdomain, so their
+// keys start at the bucket root: `/` is `index.html` and `/blog/` is `blog/index.html`.
+fn site_prefix_for<'a>(config: &'a Config, subdomain: &'a str) -> &'a str {
+    match &config.fixed_key_prefix {
+        Some(fixed_key_prefix) => fixed_key_prefix,
+        None if config.ignore_subdomain => "",
+        None => subdomain,
+    }
+}
+
+// Build the S3 key for an object path, skipping empty prefixes, then apply KEY_REWRITE_RULES
+fn object_key(config: &Config, site_prefix: &str, path: &str) -> String {
+    let key = join_key(&[&config.key_prefix, site_prefix, path]);
+    config.key_rewrites.iter().fold(key, |key, rewrite| {
+        rewrite.pattern.replace(&key, rewrite.replacement.as_str()).into_owned()
+    })
+}
+
+fn join_key(segments: &[&str]) -> String {
+    segments
+        .iter()
+        .filter(|segment| !segment.is_empty())
+        .copied()
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use source::{MockObject, MockObjectSource};
+    use std::net::Ipv4Addr;
+
+    const REMOTE_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 40000);
+
+    // State reading from an in-memory bucket of `(key, content type, body)` objects
+    fn state(vars: &[(&str, &str)], objects: &[(&str, &str, &str)]) -> (Arc<AppState>, Arc<MockObjectSource>) {
+        let config = Config::for_test(vars).unwrap();
+        let mut s3_config = aws_sdk_s3::Config::builder()
+            .behavior_version(aws_sdk_s3::config::BehaviorVersion::latest())
+            .region(aws_sdk_s3::config::Region::new("auto"));
+        s3_config.set_endpoint_url(config.endpoint_url.clone());
+        let s3_config = s3_config.build();
+        let source = Arc::new(MockObjectSource::with(objects));
+        let state = AppState::new(config, S3Client::from_conf(s3_config), source.clone()).unwrap();
+        (Arc::new(state), source)
+    }
+
+    // Serve a request over an in-memory connection and read the whole response
+    async fn send(state: &Arc<AppState>, request: Request<Full<Bytes>>) -> Response<Bytes> {
+        let (client, server) = tokio::io::duplex(1 << 20);
+        let state = state.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |req| handle_request(req, REMOTE_ADDR, state.clone()));
+            http1::Builder::new().serve_connection(TokioIo::new(server), service).await
+        });
+        let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(client)).await.unwrap();
+        tokio::spawn(connection);
+        let response = sender.send_request(request).await.unwrap();
+        let (parts, body) = response.into_parts();
+        Response::from_parts(parts, body.collect().await.unwrap().to_bytes())
+    }
+
+    // Serve a request on the admin port, bound for just this request
+    async fn send_admin(state: &Arc<AppState>, request: Request<Full<Bytes>>) -> Response<Bytes> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(admin::serve(listener, state.clone()));
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await.unwrap();
+        tokio::spawn(connection);
+        let response = sender.send_request(request).await.unwrap();
+        let (parts, body) = response.into_parts();
+        Response::from_parts(parts, body.collect().await.unwrap().to_bytes())
+    }
+
+    fn get(host: &str, path: &str) -> Request<Full<Bytes>> {
+        Request::get(path).header("host", host).body(Full::new(Bytes::new())).unwrap()
+    }
+
+    #[tokio::test]
+    async fn serves_index_html_for_directories_and_clean_urls() {
+        let (state, _) = state(&[], &[("alice/blog/index.html", "text/html", "<h1>Blog</h1>")]);
+        for path in ["/blog/", "/blog"] {
+            let response = send(&state, get("alice.naru.pub", path)).await;
+            assert_eq!(response.status(), StatusCode::OK, "{}", path);
+            assert_eq!(response.headers()["content-type"], "text/html");
+            assert_eq!(response.body(), "<h1>Blog</h1>");
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_path_traversal_without_a_lookup() {
+        let (state, source) = state(&[], &[("bob/secret.json", "application/json", "{}")]);
+        for path in ["/../bob/secret.json", "/docs/%2e%2e/%2e%2e/bob/secret.json"] {
+            let response = send(&state, get("alice.naru.pub", path)).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", path);
+        }
+        assert!(source.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn routes_each_subdomain_to_its_own_prefix() {
+        let objects = [
+            ("alice/index.html", "text/html", "alice"),
+            ("bob/index.html", "text/html", "bob"),
+        ];
+        let (state, source) = state(&[], &objects);
+        assert_eq!(send(&state, get("alice.naru.pub", "/")).await.body(), "alice");
+        assert_eq!(send(&state, get("bob.naru.pub:8080", "/")).await.body(), "bob");
+        assert_eq!(source.requests(), ["alice/index.html", "bob/index.html"]);
+    }
+
+    #[tokio::test]
+    async fn missing_objects_are_404() {
+        let (state, source) = state(&[], &[]);
+        let response = send(&state, get("alice.naru.pub", "/missing.html")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.body(), "Not Found");
+
+        // Every index document is tried before giving up on a directory
+        let response = send(&state, get("alice.naru.pub", "/docs/")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            source.requests(),
+            ["alice/missing.html", "alice/docs/index.html", "alice/docs/index.htm"]
+        );
+    }
+
+    #[tokio::test]
+    async fn head_reports_the_object_length_without_a_body() {
+        let (state, source) = state(&[], &[("alice/app.js", "text/javascript", "console.log(1)")]);
+        let request = Request::head("/app.js").header("host", "alice.naru.pub").body(Full::new(Bytes::new())).unwrap();
+        let response = send(&state, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-length"], "14");
+        assert!(response.body().is_empty());
+        assert_eq!(source.requests(), ["HEAD alice/app.js"]);
+    }
+
+    #[tokio::test]
+    async fn follows_website_redirects_in_object_metadata() {
+        let (state, source) = state(&[], &[]);
+        let object = MockObject {
+            website_redirect_location: Some("/blog/".to_string()),
+            ..MockObject::new("text/html", "")
+        };
+        source.insert("alice/old.html", object);
+        let response = send(&state, get("alice.naru.pub", "/old.html")).await;
+        assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(response.headers()["location"], "/blog/");
+    }
+
+    #[tokio::test]
+    async fn upstream_failures_are_masked_and_open_the_breaker() {
+        let (state, source) = state(&[("CIRCUIT_BREAKER_THRESHOLD", "2")], &[]);
+        source.fail_with(Some(500));
+        for _ in 0..2 {
+            assert_eq!(send(&state, get("alice.naru.pub", "/app.js")).await.status(), StatusCode::NOT_FOUND);
+        }
+        assert_eq!(send(&state, get("alice.naru.pub", "/app.js")).await.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(source.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn redirects_extensions_that_are_not_served() {
+        let (state, source) = state(&[], &[("alice/clip.mp4", "video/mp4", "0123456789")]);
+        let response = send(&state, get("alice.naru.pub", "/clip.mp4")).await;
+        assert_eq!(response.status(), StatusCode::FOUND);
+        assert_eq!(response.headers()["location"], "https://r2.naru.pub/alice/clip.mp4");
+        assert!(source.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn seeks_in_served_video() {
+        let vars = [("SERVED_EXTENSIONS", "html,htm,mp4")];
+        let (state, source) = state(&vars, &[("alice/clip.mp4", "video/mp4", "0123456789")]);
+
+        // A `<video>` element first asks for everything from the start, then seeks
+        let request = Request::get("/clip.mp4")
+            .header("host", "alice.naru.pub")
+            .header("range", "bytes=0-")
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+        let response = send(&state, request).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()["accept-ranges"], "bytes");
+        assert_eq!(response.headers()["content-range"], "bytes 0-9/10");
+
+        let request = Request::get("/clip.mp4")
+            .header("host", "alice.naru.pub")
+            .header("range", "bytes=6-")
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+        let response = send(&state, request).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()["content-type"], "video/mp4");
+        assert_eq!(response.headers()["content-range"], "bytes 6-9/10");
+        assert_eq!(response.headers()["content-length"], "4");
+        assert_eq!(response.body(), "6789");
+        assert_eq!(source.requests(), ["alice/clip.mp4 bytes=0-", "alice/clip.mp4 bytes=6-"]);
+    }
+
+    #[tokio::test]
+    async fn forces_downloads_of_served_extensions() {
+        let vars = [("SERVED_EXTENSIONS", "html,htm,csv"), ("FORCE_DOWNLOAD_EXTENSIONS", "csv")];
+        let (state, _) = state(&vars, &[("alice/data.csv", "text/csv", "a,b"), ("alice/index.html", "text/html", "")]);
+        let response = send(&state, get("alice.naru.pub", "/data.csv")).await;
+        assert_eq!(response.headers()["content-disposition"], "attachment; filename=\"data.csv\"");
+        let response = send(&state, get("alice.naru.pub", "/")).await;
+        assert!(!response.headers().contains_key("content-disposition"));
+    }
+
+    #[tokio::test]
+    async fn missing_objects_fall_back_to_shared_assets() {
+        let vars = [("SHARED_ASSETS_PREFIX", "/shared/"), ("SERVED_EXTENSIONS", "html,htm,js,css")];
+        let objects = [
+            ("alice/lib.js", "text/javascript", "alice's"),
+            ("shared/lib.js", "text/javascript", "shared"),
+            ("shared/theme.css", "text/css", "body{}"),
+        ];
+        let (state, source) = state(&vars, &objects);
+        assert_eq!(send(&state, get("alice.naru.pub", "/lib.js")).await.body(), "alice's");
+        assert_eq!(send(&state, get("bob.naru.pub", "/lib.js")).await.body(), "shared");
+        assert_eq!(send(&state, get("bob.naru.pub", "/theme.css")).await.body(), "body{}");
+        let response = send(&state, get("bob.naru.pub", "/missing.js")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            source.requests(),
+            [
+                "alice/lib.js",
+                "bob/lib.js",
+                "shared/lib.js",
+                "bob/theme.css",
+                "shared/theme.css",
+                "bob/missing.js",
+                "shared/missing.js",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn only_missing_keys_fall_back_to_shared_assets() {
+        let (state, source) = state(&[("SHARED_ASSETS_PREFIX", "shared")], &[("shared/lib.js", "text/javascript", "")]);
+        source.fail_with(Some(500));
+        let response = send(&state, get("bob.naru.pub", "/lib.js")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(source.requests(), ["bob/lib.js"]);
+    }
+
+    #[tokio::test]
+    async fn fetches_only_the_requested_range() {
+        let body = "x".repeat(1000);
+        let (state, source) = state(&[("SERVED_EXTENSIONS", "html,htm,webm")], &[("alice/talk.webm", "video/webm", &body)]);
+        let request = Request::get("/talk.webm")
+            .header("host", "alice.naru.pub")
+            .header("range", "bytes=100-199")
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+        let response = send(&state, request).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()["content-range"], "bytes 100-199/1000");
+        assert_eq!(response.body().len(), 100);
+        assert_eq!(source.requests(), ["alice/talk.webm bytes=100-199"]);
+    }
+
+    #[tokio::test]
+    async fn unsatisfiable_ranges_report_the_full_length() {
+        let (state, source) = state(&[], &[("alice/data.json", "application/json", "0123456789")]);
+        let request = Request::get("/data.json")
+            .header("host", "alice.naru.pub")
+            .header("range", "bytes=50-")
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+        let response = send(&state, request).await;
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers()["content-range"], "bytes */10");
+        assert_eq!(source.requests(), ["alice/data.json bytes=50-", "alice/data.json"]);
+    }
+
+    #[tokio::test]
+    async fn overrides_stored_content_types() {
+        let vars = [
+            ("SERVED_EXTENSIONS", "html,htm,js,wasm"),
+            ("CONTENT_TYPE_OVERRIDES", r#"{"*.wasm": "application/wasm"}"#),
+        ];
+        let objects = [
+            ("alice/app.wasm", "application/octet-stream", "\0asm"),
+            ("alice/app.js", "text/plain", "1"),
+        ];
+        let (state, _) = state(&vars, &objects);
+        let response = send(&state, get("alice.naru.pub", "/app.wasm")).await;
+        assert_eq!(response.headers()["content-type"], "application/wasm");
+        let response = send(&state, get("alice.naru.pub", "/app.js")).await;
+        assert_eq!(response.headers()["content-type"], "text/plain");
+    }
+
+    #[tokio::test]
+    async fn guesses_generic_types_from_the_extension_before_sniffing() {
+        let vars = [("SERVED_EXTENSIONS", "*"), ("SNIFF_CONTENT_TYPE", "true")];
+        let objects = [
+            ("alice/app.js", "binary/octet-stream", "console.log(1)"),
+            // Text, to show the extension wins over sniffing
+            ("alice/photo.png", "binary/octet-stream", "not really a png"),
+            ("alice/notes.dat", "binary/octet-stream", "plain words"),
+        ];
+        let (proxy, _) = state(&vars, &objects);
+        let content_type = |response: Response<Bytes>| response.headers()["content-type"].to_str().unwrap().to_string();
+        assert_eq!(content_type(send(&proxy, get("alice.naru.pub", "/app.js")).await), "text/javascript; charset=utf-8");
+        assert_eq!(content_type(send(&proxy, get("alice.naru.pub", "/photo.png")).await), "image/png");
+        assert_eq!(content_type(send(&proxy, get("alice.naru.pub", "/notes.dat")).await), "text/plain; charset=utf-8");
+
+        // Without SNIFF_CONTENT_TYPE an unknown extension keeps the stored type
+        let (proxy, _) = state(&[("SERVED_EXTENSIONS", "*")], &[("alice/notes.dat", "binary/octet-stream", "plain words")]);
+        assert_eq!(content_type(send(&proxy, get("alice.naru.pub", "/notes.dat")).await), "binary/octet-stream");
+    }
+
+    #[tokio::test]
+    async fn negotiates_variants_in_accept_order() {
+        let types = r#"{"application/json": "json", "text/csv": "csv"}"#;
+        let vars = [
+            ("CONTENT_NEGOTIATION", "true"),
+            ("CONTENT_NEGOTIATION_TYPES", types),
+            ("SERVED_EXTENSIONS", "html,htm,json,csv"),
+        ];
+        let objects = [("alice/data.json", "application/json", "{}"), ("alice/data.csv", "text/csv", "a,b")];
+        let (proxy, source) = state(&vars, &objects);
+
+        let mut request = get("alice.naru.pub", "/data");
+        request.headers_mut().insert("accept", HeaderValue::from_static("text/csv, application/json;q=0.5"));
+        let response = send(&proxy, request).await;
+        assert_eq!(response.body(), "a,b");
+        assert_eq!(response.headers()["vary"], "Accept");
+
+        let mut request = get("alice.naru.pub", "/data");
+        request.headers_mut().insert("accept", HeaderValue::from_static("application/json"));
+        assert_eq!(send(&proxy, request).await.body(), "{}");
+        assert_eq!(source.requests(), ["alice/data.csv", "alice/data.json"]);
+    }
+
+    #[tokio::test]
+    async fn strips_only_index_documents() {
+        let vars = [("STRIP_INDEX_HTML", "true"), ("INDEX_DOCUMENTS", "default.htm")];
+        let (proxy, _) = state(&vars, &[("alice/blog/index.html", "text/html", "not an index")]);
+
+        let response = send(&proxy, get("alice.naru.pub", "/blog/default.htm?page=2")).await;
+        assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(response.headers()["location"], "/blog/?page=2");
+
+        let response = send(&proxy, get("alice.naru.pub", "/blog/index.html")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body(), "not an index");
+    }
+
+    #[tokio::test]
+    async fn prefetches_linked_assets_once() {
+        let vars = [("CACHE_MAX_ENTRIES", "100"), ("CACHE_STATUS_HEADER", "true"), ("PREFETCH_LINKED_ASSETS", "true")];
+        let page = r#"<script src="/app.js"></script><script src="missing.js"></script>"#;
+        let objects = [
+            ("alice/index.html", "text/html", page),
+            ("alice/other.html", "text/html", page),
+            ("alice/app.js", "text/javascript", "1"),
+        ];
+        let (proxy, source) = state(&vars, &objects);
+        let prefetched = || async {
+            // Prefetches run in the background
+            for _ in 0..100 {
+                if proxy.prefetcher.is_idle() {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            source.requests()
+        };
+
+        send(&proxy, get("alice.naru.pub", "/")).await;
+        let mut requests = prefetched().await;
+        requests[1..].sort();
+        assert_eq!(requests, ["alice/index.html", "alice/app.js", "alice/missing.js"]);
+        assert_eq!(send(&proxy, get("alice.naru.pub", "/app.js")).await.headers()["x-cache"], "HIT");
+
+        // A cached page and the missing asset fetch nothing more
+        send(&proxy, get("alice.naru.pub", "/")).await;
+        send(&proxy, get("alice.naru.pub", "/other.html")).await;
+        assert_eq!(prefetched().await.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn scopes_writes_to_the_prefix_directory() {
+        let vars = [("ENABLE_WRITE_METHODS", "true"), ("WRITE_API_KEY", "secret"), ("WRITE_PATH_PREFIX", "uploads")];
+        let (proxy, _) = state(&vars, &[]);
+        let write = |method: Method, path: &str, token: Option<&str>| {
+            let mut request = Request::builder().method(method).uri(path).header("host", "alice.naru.pub");
+            if let Some(token) = token {
+                request = request.header("authorization", format!("Bearer {}", token));
+            }
+            request.body(Full::new(Bytes::from("{}"))).unwrap()
+        };
+
+        for path in ["/uploads-evil/a.json", "/uploads", "/a.json"] {
+            let response = send(&proxy, write(Method::PUT, path, Some("secret"))).await;
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "{}", path);
+        }
+        let response = send(&proxy, write(Method::PATCH, "/uploads/a.json", Some("wrong"))).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let options = Request::options("/").header("host", "alice.naru.pub").body(Full::new(Bytes::new())).unwrap();
+        assert_eq!(send(&proxy, options).await.headers()["allow"], "GET, HEAD, PUT, PATCH, DELETE, OPTIONS");
+    }
+
+    #[test]
+    fn write_prefixes_end_at_a_segment() {
+        assert!(is_within_prefix("uploads/a.json", "uploads"));
+        assert!(is_within_prefix("uploads/a.json", "uploads/"));
+        assert!(!is_within_prefix("uploads-evil/a.json", "uploads"));
+        assert!(!is_within_prefix("uploads", "uploads"));
+        assert!(is_within_prefix("a.json", ""));
+    }
+
+    #[tokio::test]
+    async fn types_precompressed_objects_by_the_original_key() {
+        let vars = [("PRECOMPRESSED_BROTLI", "true"), ("SNIFF_CONTENT_TYPE", "true"), ("SERVED_EXTENSIONS", "*")];
+        let objects = [
+            ("alice/app.js", "text/javascript", "console.log(1)"),
+            ("alice/app.js.br", "application/x-brotli", "compressed"),
+            ("alice/notes.dat.br", "binary/octet-stream", "plain words"),
+        ];
+        let (proxy, _) = state(&vars, &objects);
+        let brotli = |path: &str| {
+            let mut request = get("alice.naru.pub", path);
+            request.headers_mut().insert("accept-encoding", HeaderValue::from_static("br"));
+            request
+        };
+
+        let identity = send(&proxy, get("alice.naru.pub", "/app.js")).await;
+        let response = send(&proxy, brotli("/app.js")).await;
+        assert_eq!(response.body(), "compressed");
+        assert_eq!(response.headers()["content-encoding"], "br");
+        assert_eq!(response.headers()["content-type"], "text/javascript; charset=utf-8");
+        assert_ne!(response.headers()["etag"], identity.headers()["etag"]);
+        assert_eq!(response.headers()["vary"], "Accept-Encoding");
+
+        // Compressed bytes aren't sniffed, and the `.br` object's own type describes them, not the file
+        let response = send(&proxy, brotli("/notes.dat")).await;
+        assert_eq!(response.headers()["content-encoding"], "br");
+        assert!(!response.headers().contains_key("content-type"));
+    }
+
+    #[tokio::test]
+    async fn head_reports_the_injected_page() {
+        let vars = [("HTML_INJECT_BEFORE_CLOSING_BODY", "<script src=\"/a.js\"></script>")];
+        let (proxy, source) = state(&vars, &[("alice/index.html", "text/html", "<body>Alice</body>")]);
+        let head = Request::head("/").header("host", "alice.naru.pub").body(Full::new(Bytes::new())).unwrap();
+
+        let page = send(&proxy, get("alice.naru.pub", "/")).await;
+        let response = send(&proxy, head).await;
+        assert!(response.body().is_empty());
+        assert_eq!(response.headers()["content-length"], page.body().len().to_string());
+        assert_eq!(response.headers()["etag"], page.headers()["etag"]);
+        assert_eq!(source.requests(), ["alice/index.html", "alice/index.html"]);
+    }
+
+    #[tokio::test]
+    async fn head_and_get_share_recomputed_etags() {
+        let vars = [("HTML_INJECT_BEFORE_CLOSING_BODY", "<p>Hi</p>"), ("TRANSFORMED_ETAG", "recompute")];
+        let (proxy, _) = state(&vars, &[("alice/index.html", "text/html", "<body>Alice</body>")]);
+        let head = Request::head("/").header("host", "alice.naru.pub").body(Full::new(Bytes::new())).unwrap();
+
+        let page = send(&proxy, get("alice.naru.pub", "/")).await;
+        assert_ne!(page.headers()["etag"], MockObject::new("text/html", "<body>Alice</body>").e_tag.unwrap());
+        assert_eq!(send(&proxy, head).await.headers()["etag"], page.headers()["etag"]);
+
+        let mut revalidate = get("alice.naru.pub", "/");
+        revalidate.headers_mut().insert("if-none-match", page.headers()["etag"].clone());
+        assert_eq!(send(&proxy, revalidate).await.status(), StatusCode::NOT_MODIFIED);
+    }
+
+    #[tokio::test]
+    async fn logs_slow_requests_with_their_key() {
+        let (proxy, source) = state(&[("SLOW_REQUEST_THRESHOLD_MS", "10")], &[("alice/app.js", "text/javascript", "1")]);
+        source.delay_by(Duration::from_millis(20));
+        let logs = CapturedLogs::default();
+        let _default = logs.capture();
+
+        send(&proxy, get("alice.naru.pub", "/app.js")).await;
+        let logs = logs.text();
+        let line = logs.lines().find(|line| line.contains("slow request")).expect("a slow request warning");
+        assert!(line.contains("WARN") && line.contains("key=\"alice/app.js\""), "{}", line);
+    }
+
+    // Log output written on this thread, while the guard from `capture` is held
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl CapturedLogs {
+        fn capture(&self) -> tracing::subscriber::DefaultGuard {
+            let writer = self.clone();
+            let subscriber = tracing_subscriber::fmt()
+                .with_ansi(false)
+                .with_writer(move || writer.clone())
+                .finish();
+            tracing::subscriber::set_default(subscriber)
+        }
+
+        fn text(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn purges_every_cached_form_of_a_path() {
+        let vars = [
+            ("CACHE_MAX_ENTRIES", "100"),
+            ("PURGE_API_KEY", "secret"),
+            ("PRECOMPRESSED_BROTLI", "true"),
+            ("ALLOW_VERSION_QUERY", "true"),
+            ("LANGUAGE_ROUTING", "true"),
+            ("LANGUAGE_ROUTES", r#"{"fr": "fr"}"#),
+            ("SHARED_ASSETS_PREFIX", "shared"),
+        ];
+        let objects = [
+            ("alice/app.js", "text/javascript", "1"),
+            ("alice/app.js?version=v1", "text/javascript", "v1"),
+            ("alice/app.js.br", "text/javascript", "br"),
+            ("alice/fr/app.js", "text/javascript", "fr"),
+            ("shared/lib.js", "text/javascript", "lib"),
+        ];
+        let (proxy, _) = state(&vars, &objects);
+        let with_header = |path: &str, name: &'static str, value: &'static str| {
+            let mut request = get("alice.naru.pub", path);
+            request.headers_mut().insert(name, HeaderValue::from_static(value));
+            request
+        };
+        send(&proxy, get("alice.naru.pub", "/app.js")).await;
+        send(&proxy, with_header("/app.js", "accept-encoding", "br")).await;
+        send(&proxy, get("alice.naru.pub", "/app.js?version=v1")).await;
+        send(&proxy, with_header("/app.js", "accept-language", "fr")).await;
+        send(&proxy, get("alice.naru.pub", "/lib.js")).await;
+
+        let purge = |path: &str| {
+            Request::builder()
+                .method("PURGE")
+                .uri(path)
+                .header("host", "alice.naru.pub")
+                .header("authorization", "Bearer secret")
+                .body(Full::new(Bytes::new()))
+                .unwrap()
+        };
+        assert_eq!(send(&proxy, purge("/app.js")).await.body(), r#"{"purged":4}"#);
+        assert_eq!(send(&proxy, purge("/lib.js")).await.body(), r#"{"purged":1}"#);
+    }
+
+    #[test]
+    fn client_ip_is_the_rightmost_untrusted_hop() {
+        let config = Config::for_test(&[("TRUSTED_PROXY_CIDRS", "10.0.0.0/8")]).unwrap();
+        let proxy: SocketAddr = "10.0.0.1:443".parse().unwrap();
+        let client_ip = |name: &'static str, values: &[&'static str], peer: SocketAddr| {
+            let mut headers = HeaderMap::new();
+            for value in values {
+                headers.append(name, HeaderValue::from_static(value));
+            }
+            client_ip(&headers, peer, &config).to_string()
+        };
+
+        // The client wrote `1.1.1.1` itself, and the trusted proxy appended what it saw
+        assert_eq!(client_ip("x-forwarded-for", &["1.1.1.1, 203.0.113.9"], proxy), "203.0.113.9");
+        assert_eq!(client_ip("x-forwarded-for", &["1.1.1.1", "203.0.113.9, 10.0.0.2"], proxy), "203.0.113.9");
+        assert_eq!(client_ip("forwarded", &["for=1.1.1.1, for=\"[2001:db8::1]:4711\""], proxy), "2001:db8::1");
+        assert_eq!(client_ip("forwarded", &["for=_hidden, for=10.0.0.2"], proxy), "10.0.0.2");
+        assert_eq!(client_ip("x-forwarded-for", &["10.0.0.3"], proxy), "10.0.0.3");
+        // Only trusted peers are believed
+        assert_eq!(client_ip("x-forwarded-for", &["1.1.1.1"], REMOTE_ADDR), "127.0.0.1");
+    }
+
+    #[tokio::test]
+    async fn caches_only_the_configured_statuses() {
+        // `app.js` is fetched once either way, and `missing.js` again unless 404s are cached
+        for (statuses, lookups) in [("200", 3), ("200,404", 2)] {
+            let vars = [("CACHE_MAX_ENTRIES", "10"), ("CACHEABLE_STATUS_CODES", statuses)];
+            let (proxy, source) = state(&vars, &[("alice/app.js", "text/javascript", "1")]);
+            for _ in 0..2 {
+                send(&proxy, get("alice.naru.pub", "/app.js")).await;
+                let response = send(&proxy, get("alice.naru.pub", "/missing.js")).await;
+                assert_eq!(response.status(), StatusCode::NOT_FOUND);
+            }
+            assert_eq!(source.requests().len(), lookups, "{}", statuses);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_fixed_prefix_ignores_the_host() {
+        let vars = [("KEY_PREFIX", "sites"), ("FIXED_KEY_PREFIX", "/docs/")];
+        let (proxy, source) = state(&vars, &[("sites/docs/index.html", "text/html", "docs")]);
+        for host in ["alice.naru.pub", "bob.naru.pub", "naru.pub"] {
+            let response = send(&proxy, get(host, "/")).await;
+            assert_eq!(response.body(), "docs", "{}", host);
+        }
+        assert_eq!(source.requests(), ["sites/docs/index.html"; 3]);
+    }
+
+    #[tokio::test]
+    async fn head_ignores_ranges() {
+        let (proxy, source) = state(&[], &[("alice/data.json", "application/json", "0123456789")]);
+        let request = Request::head("/data.json")
+            .header("host", "alice.naru.pub")
+            .header("range", "bytes=2-5")
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+        let response = send(&proxy, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["accept-ranges"], "bytes");
+        assert_eq!(response.headers()["content-length"], "10");
+        assert!(!response.headers().contains_key("content-range"));
+        assert!(response.body().is_empty());
+        assert_eq!(source.requests(), ["HEAD alice/data.json"]);
+    }
+
+    #[tokio::test]
+    async fn ignoring_the_subdomain_maps_paths_to_keys() {
+        let (proxy, source) = state(&[("IGNORE_SUBDOMAIN", "true")], &[("index.html", "text/html", "site")]);
+        for host in ["example.com", "alice.naru.pub"] {
+            let response = send(&proxy, get(host, "/index.html")).await;
+            assert_eq!(response.body(), "site", "{}", host);
+        }
+        assert_eq!(source.requests(), ["index.html"; 2]);
+
+        let vars = [("IGNORE_SUBDOMAIN", "true"), ("KEY_PREFIX", "site")];
+        let (proxy, source) = state(&vars, &[("site/index.html", "text/html", "site")]);
+        assert_eq!(send(&proxy, get("example.com", "/index.html")).await.body(), "site");
+        assert_eq!(source.requests(), ["site/index.html"]);
+    }
+
+    #[tokio::test]
+    async fn clean_urls_try_html_files_in_the_configured_order() {
+        let objects = [("alice/about.html", "text/html", "file"), ("alice/about/index.html", "text/html", "index")];
+        for (clean_urls, body, lookups) in [
+            ("html", "file", vec!["alice/about.html"]),
+            ("html,index", "file", vec!["alice/about.html"]),
+            ("index,html", "index", vec!["alice/about/index.html"]),
+        ] {
+            let (proxy, source) = state(&[("CLEAN_URLS", clean_urls)], &objects);
+            assert_eq!(send(&proxy, get("alice.naru.pub", "/about")).await.body(), body, "{}", clean_urls);
+            assert_eq!(source.requests(), lookups, "{}", clean_urls);
+        }
+
+        // Falls back to the directory index when there is no file
+        let (proxy, source) = state(&[("CLEAN_URLS", "html,index")], &[("alice/blog/index.html", "text/html", "blog")]);
+        assert_eq!(send(&proxy, get("alice.naru.pub", "/blog")).await.body(), "blog");
+        assert_eq!(source.requests(), ["alice/blog.html", "alice/blog/index.html"]);
+    }
+
+    #[tokio::test]
+    async fn reports_the_cache_result_in_a_header_and_the_log() {
+        let vars = [
+            ("CACHE_MAX_ENTRIES", "10"),
+            ("CACHE_STATUS_HEADER", "true"),
+            ("CACHE_TTL_SECS", "0"),
+            ("CACHE_STALE_WHILE_REVALIDATE_SECS", "60"),
+            ("LOG_SAMPLE_RATE", "1"),
+        ];
+        let (proxy, _) = state(&vars, &[("alice/app.js", "text/javascript", "1")]);
+        let logs = CapturedLogs::default();
+        let _default = logs.capture();
+        for expected in ["MISS", "STALE"] {
+            let response = send(&proxy, get("alice.naru.pub", "/app.js")).await;
+            assert_eq!(response.headers()["x-cache"], expected);
+        }
+        let lines: Vec<String> = logs.text().lines().filter(|line| line.contains(" request ")).map(String::from).collect();
+        assert!(lines[0].contains("cache=\"MISS\"") && lines[1].contains("cache=\"STALE\""), "{:?}", lines);
+
+        let vars = [("CACHE_MAX_ENTRIES", "10"), ("CACHE_STATUS_HEADER", "true")];
+        let (proxy, _) = state(&vars, &[("alice/app.js", "text/javascript", "1")]);
+        for expected in ["MISS", "HIT"] {
+            assert_eq!(send(&proxy, get("alice.naru.pub", "/app.js")).await.headers()["x-cache"], expected);
+        }
+
+        // Nothing is cached without entries, and the header is opt-in
+        let (proxy, _) = state(&[("CACHE_STATUS_HEADER", "true")], &[("alice/app.js", "text/javascript", "1")]);
+        assert_eq!(send(&proxy, get("alice.naru.pub", "/app.js")).await.headers()["x-cache"], "BYPASS");
+        let (proxy, _) = state(&[("CACHE_MAX_ENTRIES", "10")], &[("alice/app.js", "text/javascript", "1")]);
+        assert!(!send(&proxy, get("alice.naru.pub", "/app.js")).await.headers().contains_key("x-cache"));
+    }
+
+    #[tokio::test]
+    async fn pins_versions_only_when_allowed() {
+        let objects = [("alice/app.js", "text/javascript", "current"), ("alice/app.js?version=v1", "text/javascript", "v1")];
+        let (proxy, source) = state(&[("ALLOW_VERSION_QUERY", "true")], &objects);
+        assert_eq!(send(&proxy, get("alice.naru.pub", "/app.js?version=v1")).await.body(), "v1");
+        assert_eq!(send(&proxy, get("alice.naru.pub", "/app.js")).await.body(), "current");
+        let response = send(&proxy, get("alice.naru.pub", "/app.js?version=v2")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(source.requests(), ["alice/app.js?version=v1", "alice/app.js", "alice/app.js?version=v2"]);
+
+        // Otherwise the query is ignored, and isn't part of the key
+        let (proxy, source) = state(&[], &objects);
+        assert_eq!(send(&proxy, get("alice.naru.pub", "/app.js?version=v1")).await.body(), "current");
+        assert_eq!(source.requests(), ["alice/app.js"]);
+    }
+
+    #[tokio::test]
+    async fn logs_carry_the_incoming_trace_id() {
+        let (proxy, _) = state(&[("LOG_SAMPLE_RATE", "1")], &[("alice/app.js", "text/javascript", "1")]);
+        let logs = CapturedLogs::default();
+        let _default = logs.capture();
+        let mut request = get("alice.naru.pub", "/app.js");
+        let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        request.headers_mut().insert("traceparent", HeaderValue::from_static(traceparent));
+        send(&proxy, request).await;
+        let logs = logs.text();
+        let line = logs.lines().find(|line| line.contains(" request ")).expect("a request log line");
+        assert!(line.contains("trace_id=4bf92f3577b34da6a3ce929d0e0e4736"), "{}", line);
+        assert!(line.contains("parent_id=\"00f067aa0ba902b7\""), "{}", line);
+    }
+
+    #[tokio::test]
+    async fn requiring_a_subdomain_rejects_apex_requests() {
+        let objects = [("index.html", "text/html", "root"), ("alice/index.html", "text/html", "alice")];
+        let (proxy, source) = state(&[("REQUIRE_SUBDOMAIN", "true")], &objects);
+        for host in ["naru.pub", "localhost"] {
+            let response = send(&proxy, get(host, "/")).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", host);
+        }
+        assert_eq!(send(&proxy, get("alice.naru.pub", "/")).await.body(), "alice");
+        assert_eq!(source.requests(), ["alice/index.html"]);
+
+        let (proxy, _) = state(&[], &objects);
+        assert_eq!(send(&proxy, get("naru.pub", "/")).await.body(), "root");
+    }
+
+    #[tokio::test]
+    async fn errors_are_logged_whatever_the_sample_rate() {
+        let (proxy, _) = state(&[("LOG_SAMPLE_RATE", "0")], &[("alice/app.js", "text/javascript", "1")]);
+        let logs = CapturedLogs::default();
+        let _default = logs.capture();
+        send(&proxy, get("alice.naru.pub", "/app.js")).await;
+        send(&proxy, get("alice.naru.pub", "/missing.js")).await;
+        let logs = logs.text();
+        let lines: Vec<&str> = logs.lines().filter(|line| line.contains(" request ")).collect();
+        assert_eq!(lines.len(), 1, "{:?}", lines);
+        assert!(lines[0].contains("status=404"), "{}", lines[0]);
+        assert!(Config::for_test(&[("LOG_SAMPLE_RATE", "1.5")]).is_err());
+    }
+
+    #[tokio::test]
+    async fn throttling_asks_clients_to_retry_later() {
+        let (proxy, source) = state(&[("THROTTLE_RETRY_AFTER_SECS", "7")], &[("alice/app.js", "text/javascript", "1")]);
+        let logs = CapturedLogs::default();
+        let _default = logs.capture();
+        let throttled = METRICS.s3_throttled.get();
+        // R2's `SlowDown`, and a bare 429
+        for status in [503, 429] {
+            source.fail_with(Some(status));
+            let head = Request::head("/app.js").header("host", "alice.naru.pub").body(Full::new(Bytes::new())).unwrap();
+            for request in [get("alice.naru.pub", "/app.js"), head] {
+                let response = send(&proxy, request).await;
+                assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE, "{}", status);
+                assert_eq!(response.headers()["retry-after"], "7");
+            }
+        }
+        assert!(METRICS.s3_throttled.get() >= throttled + 4);
+        assert_eq!(logs.text().lines().filter(|line| line.contains("WARN") && line.contains("throttled")).count(), 4);
+    }
+
+    #[tokio::test]
+    async fn options_lists_the_allowed_methods_without_a_lookup() {
+        let options = || Request::options("/app.js").header("host", "alice.naru.pub").body(Full::new(Bytes::new())).unwrap();
+        let (proxy, source) = state(&[], &[("alice/app.js", "text/javascript", "1")]);
+        let response = send(&proxy, options()).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(response.headers()["allow"], "GET, HEAD, OPTIONS");
+        assert!(response.body().is_empty());
+        assert!(source.requests().is_empty());
+
+        let vars = [("ENABLE_WRITE_METHODS", "true"), ("WRITE_API_KEY", "secret")];
+        let (proxy, _) = state(&vars, &[]);
+        let response = send(&proxy, options()).await;
+        assert_eq!(response.headers()["allow"], "GET, HEAD, PUT, PATCH, DELETE, OPTIONS");
+    }
+
+    #[test]
+    fn hosts_without_a_registrable_domain_have_no_subdomain() {
+        let config = Config::for_test(&[]).unwrap();
+        for host in ["localhost:5000", "LOCALHOST", "devbox", "127.0.0.1:8080", "[::1]:8080", "naru.pub"] {
+            assert_eq!(subdomain_of(host, &config), "", "{}", host);
+        }
+        for (host, subdomain) in [("alice.naru.pub", "alice"), ("alice.naru.pub:8080", "alice"), ("alice.localhost", "alice")] {
+            assert_eq!(subdomain_of(host, &config), subdomain, "{}", host);
+        }
+
+        let config = Config::for_test(&[("LOCAL_HOSTS", "dev.internal")]).unwrap();
+        assert_eq!(subdomain_of("dev.internal:3000", &config), "");
+        assert_eq!(subdomain_of("alice.naru.pub", &config), "alice");
+    }
+
+    #[tokio::test]
+    async fn invalidates_cached_keys_and_prefixes() {
+        let vars = [
+            ("CACHE_MAX_ENTRIES", "10"),
+            ("CACHE_STATUS_HEADER", "true"),
+            ("CACHEABLE_STATUS_CODES", "200,404"),
+            ("ADMIN_API_KEY", "secret"),
+        ];
+        let objects = [("alice/app.js", "text/javascript", "1"), ("bob/app.js", "text/javascript", "2")];
+        let (proxy, _) = state(&vars, &objects);
+        let requests = [("alice.naru.pub", "/app.js"), ("alice.naru.pub", "/missing.js"), ("bob.naru.pub", "/app.js")];
+        for (host, path) in requests {
+            send(&proxy, get(host, path)).await;
+        }
+        let invalidate = |body: &str, token: &str| {
+            Request::post("/_cache/invalidate")
+                .header("authorization", format!("Bearer {}", token))
+                .body(Full::new(Bytes::from(body.to_string())))
+                .unwrap()
+        };
+        let response = send_admin(&proxy, invalidate(r#"{"keys": ["alice/app.js"]}"#, "wrong")).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        // An exact key, then a whole site, including its cached 404s
+        let response = send_admin(&proxy, invalidate(r#"{"keys": ["alice/app.js"]}"#, "secret")).await;
+        assert_eq!(response.body(), r#"{"invalidated":1}"#);
+        let cache_results = || async {
+            let mut results = Vec::new();
+            for (host, path) in requests {
+                let response = send(&proxy, get(host, path)).await;
+                results.push(response.headers()["x-cache"].to_str().unwrap().to_string());
+            }
+            results
+        };
+        assert_eq!(cache_results().await, ["MISS", "HIT", "HIT"]);
+        let response = send_admin(&proxy, invalidate(r#"{"prefixes": ["alice/"]}"#, "secret")).await;
+        assert_eq!(response.body(), r#"{"invalidated":2}"#);
+        assert_eq!(cache_results().await, ["MISS", "MISS", "HIT"]);
+    }
+
+    #[tokio::test]
+    async fn clamps_stored_cache_control_to_the_ceiling() {
+        let (proxy, source) = state(&[("MAX_CACHE_CONTROL_AGE", "3600")], &[]);
+        for (key, cache_control) in [("alice/index.html", "public, max-age=31536000"), ("alice/app.js", "max-age=60")] {
+            let object = MockObject {
+                cache_control: Some(cache_control.to_string()),
+                ..MockObject::new("text/html", "")
+            };
+            source.insert(key, object);
+        }
+        let response = send(&proxy, get("alice.naru.pub", "/")).await;
+        assert_eq!(response.headers()["cache-control"], "public, max-age=3600");
+        let response = send(&proxy, get("alice.naru.pub", "/app.js")).await;
+        assert_eq!(response.headers()["cache-control"], "max-age=60");
+    }
+
+    #[tokio::test]
+    async fn never_marks_pages_immutable() {
+        let objects = [
+            ("alice/20240101.html", "text/html", "<h1>New year</h1>"),
+            ("alice/deadbeef-notes.html", "text/html; charset=utf-8", "<h1>Notes</h1>"),
+            ("alice/app.abcd1234.js", "text/javascript", "1"),
+        ];
+        let (proxy, _) = state(&[], &objects);
+        for path in ["/20240101.html", "/deadbeef-notes.html"] {
+            let response = send(&proxy, get("alice.naru.pub", path)).await;
+            assert_eq!(response.headers()["cache-control"], "public, max-age=3600", "{path}");
+        }
+        let response = send(&proxy, get("alice.naru.pub", "/app.abcd1234.js")).await;
+        assert_eq!(response.headers()["cache-control"], "public, max-age=31536000, immutable");
+    }
+
+    #[tokio::test]
+    async fn serves_error_documents_for_configured_statuses() {
+        let vars = [
+            ("UPSTREAM_ERROR_STATUSES", r#"{"AccessDenied": 403, "InternalError": 500}"#),
+            ("ERROR_PAGES", r#"{"403": "/403.html", "4xx": "oops.html"}"#),
+        ];
+        let objects = [("alice/403.html", "text/html", "<h1>Private</h1>"), ("bob/app.js", "text/javascript", "1")];
+        let (proxy, source) = state(&vars, &objects);
+        source.fail_key_with("alice/private.js", 403);
+        source.fail_key_with("bob/private.js", 403);
+        source.fail_key_with("alice/broken.js", 500);
+
+        let response = send(&proxy, get("alice.naru.pub", "/private.js")).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(response.headers()["content-type"], "text/html");
+        assert_eq!(response.body(), "<h1>Private</h1>");
+
+        // A site without the document, and statuses without one configured, keep the plain text body
+        let response = send(&proxy, get("bob.naru.pub", "/private.js")).await;
+        assert_eq!((response.status(), response.body().as_ref()), (StatusCode::FORBIDDEN, &b"Forbidden"[..]));
+        let response = send(&proxy, get("alice.naru.pub", "/broken.js")).await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(response.body(), "Internal Server Error");
+        assert_eq!(
+            source.requests(),
+            ["alice/private.js", "alice/403.html", "bob/private.js", "bob/403.html", "alice/broken.js"]
+        );
+    }
+
+    #[test]
+    fn subdomains_of_multi_level_hosts_follow_the_mode() {
+        let domains = ("ALLOWED_DOMAINS", "example.com,naru.pub");
+        let first_label = Config::for_test(&[domains]).unwrap();
+        let full = Config::for_test(&[domains, ("SUBDOMAIN_MODE", "full")]).unwrap();
+        let nested = Config::for_test(&[domains, ("SUBDOMAIN_MODE", "full"), ("SUBDOMAIN_SEPARATOR", "/")]).unwrap();
+        for (host, expected) in [
+            ("staging.app.example.com", ["staging", "staging-app", "staging/app"]),
+            ("Alice.Naru.Pub.", ["Alice", "Alice", "Alice"]),
+            ("example.com", ["", "", ""]),
+            // Not under an allowed domain, nor a lookalike of one
+            ("a.badexample.com", ["a", "a", "a"]),
+        ] {
+            let subdomains = [&first_label, &full, &nested].map(|config| subdomain_of(host, config));
+            assert_eq!(subdomains, expected, "{}", host);
+        }
+        assert!(Config::for_test(&[("SUBDOMAIN_MODE", "full")]).is_err());
+    }
+
+    #[tokio::test]
+    async fn full_subdomains_keep_sites_apart() {
+        let vars = [("ALLOWED_DOMAINS", "example.com"), ("SUBDOMAIN_MODE", "full")];
+        let objects = [("staging-app/index.html", "text/html", "staging"), ("app/index.html", "text/html", "app")];
+        let (proxy, source) = state(&vars, &objects);
+        assert_eq!(send(&proxy, get("staging.app.example.com", "/")).await.body(), "staging");
+        assert_eq!(send(&proxy, get("app.example.com", "/")).await.body(), "app");
+        assert_eq!(source.requests(), ["staging-app/index.html", "app/index.html"]);
+    }
+
+    #[tokio::test]
+    async fn dumps_the_config_with_secrets_redacted() {
+        let vars = [
+            ("ADMIN_API_KEY", "admin-secret"),
+            ("AWS_ACCESS_KEY_ID", "AKIDSECRET"),
+            ("AWS_SECRET_ACCESS_KEY", "aws-secret"),
+            ("PURGE_API_KEY", "purge-secret"),
+            ("CACHE_MAX_ENTRIES", "10"),
+        ];
+        let (proxy, _) = state(&vars, &[]);
+        let dump = |token: &str| {
+            Request::get("/_config")
+                .header("authorization", format!("Bearer {}", token))
+                .body(Full::new(Bytes::new()))
+                .unwrap()
+        };
+        assert_eq!(send_admin(&proxy, dump("wrong")).await.status(), StatusCode::UNAUTHORIZED);
+
+        let response = send_admin(&proxy, dump("admin-secret")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = std::str::from_utf8(response.body()).unwrap();
+        for secret in ["admin-secret", "AKIDSECRET", "aws-secret", "purge-secret"] {
+            assert!(!body.contains(secret), "{} in {}", secret, body);
+        }
+        let config: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(config["admin_api_key"], "***");
+        assert_eq!(config["secret_access_key"], "***");
+        assert_eq!(config["write_api_key"], serde_json::Value::Null);
+        assert_eq!(config["cache_max_entries"], 10);
+
+        // Without an admin key there is no way in
+        let (proxy, _) = state(&[], &[]);
+        assert_eq!(send_admin(&proxy, dump("")).await.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[test]
+    fn trailing_slashes_after_file_names_follow_the_config() {
+        let paths = |mode: &str, path: &str| resolve_paths(path, &Config::for_test(&[("TRAILING_SLASH_FILES", mode)]).unwrap());
+        assert_eq!(paths("index", "/style.css/"), ["style.css/index.html", "style.css/index.htm"]);
+        assert_eq!(paths("strip", "/style.css/"), ["style.css"]);
+        assert!(paths("not-found", "/style.css/").is_empty());
+        // Directories, dot directories and files without a slash are never affected
+        for mode in ["index", "strip", "not-found"] {
+            assert_eq!(paths(mode, "/dir/"), ["dir/index.html", "dir/index.htm"], "{}", mode);
+            assert_eq!(paths(mode, "/.well-known/"), [".well-known/index.html", ".well-known/index.htm"], "{}", mode);
+            assert_eq!(paths(mode, "/file.js"), ["file.js"], "{}", mode);
+        }
+    }
+
+    #[tokio::test]
+    async fn trailing_slashes_after_file_names_are_a_clean_404() {
+        let (proxy, source) = state(&[("TRAILING_SLASH_FILES", "not-found")], &[("alice/app.js", "text/javascript", "1")]);
+        assert_eq!(send(&proxy, get("alice.naru.pub", "/app.js/")).await.status(), StatusCode::NOT_FOUND);
+        assert!(source.requests().is_empty());
+
+        let (proxy, source) = state(&[("TRAILING_SLASH_FILES", "strip")], &[("alice/app.js", "text/javascript", "1")]);
+        assert_eq!(send(&proxy, get("alice.naru.pub", "/app.js/")).await.body(), "1");
+        assert_eq!(source.requests(), ["alice/app.js"]);
+    }
+
+    #[tokio::test]
+    async fn date_if_ranges_apply_only_while_unmodified() {
+        let (proxy, source) = state(&[], &[]);
+        let object = MockObject {
+            // Wed, 01 Jan 2025 00:00:00 GMT
+            last_modified: Some(aws_sdk_s3::primitives::DateTime::from_secs(1735689600)),
+            ..MockObject::new("application/json", "0123456789")
+        };
+        source.insert("alice/data.json", object);
+        let ranged = |if_range: &'static str| {
+            let mut request = get("alice.naru.pub", "/data.json");
+            request.headers_mut().insert("range", HeaderValue::from_static("bytes=2-5"));
+            request.headers_mut().insert("if-range", HeaderValue::from_static(if_range));
+            request
+        };
+
+        let response = send(&proxy, ranged("Wed, 01 Jan 2025 00:00:00 GMT")).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()["content-range"], "bytes 2-5/10");
+        assert_eq!(response.body(), "2345");
+
+        // Modified since the client's copy, so the whole object is sent
+        let response = send(&proxy, ranged("Tue, 31 Dec 2024 00:00:00 GMT")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body(), "0123456789");
+    }
+
+    #[tokio::test]
+    async fn rewrites_keys_with_the_rules_in_order() {
+        let rules = r#"[["^([^/]+)/v[0-9]+/", "$1/"], ["^alice/app\\.js$", "alice/app.min.js"]]"#;
+        let objects = [("alice/app.min.js", "text/javascript", "min"), ("alice/data.json", "application/json", "{}")];
+        let (proxy, source) = state(&[("KEY_REWRITE_RULES", rules)], &objects);
+        assert_eq!(send(&proxy, get("alice.naru.pub", "/v3/app.js")).await.body(), "min");
+        // Keys no rule matches are fetched as they are
+        assert_eq!(send(&proxy, get("alice.naru.pub", "/data.json")).await.body(), "{}");
+        assert_eq!(source.requests(), ["alice/app.min.js", "alice/data.json"]);
+
+        let err = Config::for_test(&[("KEY_REWRITE_RULES", r#"[["^ok$", ""], ["(unclosed", ""]]"#)]).err().unwrap();
+        assert!(format!("{:#}", err).contains("rule 2 has an invalid pattern (unclosed"), "{:#}", err);
+    }
+
+    #[tokio::test]
+    async fn strips_index_html_to_the_directory() {
+        let objects = [("alice/index.html", "text/html", "home"), ("alice/blog/index.html", "text/html", "blog")];
+        let (proxy, source) = state(&[("STRIP_INDEX_HTML", "true")], &objects);
+        let redirects = [
+            ("/index.html", "/"),
+            ("/blog/index.html", "/blog/"),
+            ("/blog/index.htm?a=1&b=2", "/blog/?a=1&b=2"),
+        ];
+        for (path, location) in redirects {
+            let response = send(&proxy, get("alice.naru.pub", path)).await;
+            assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY, "{}", path);
+            assert_eq!(response.headers()["location"], location);
+        }
+        let head = Request::head("/index.html").header("host", "alice.naru.pub").body(Full::new(Bytes::new())).unwrap();
+        assert_eq!(send(&proxy, head).await.status(), StatusCode::MOVED_PERMANENTLY);
+        // Only whole file names count, so `/myindex.html` is served as is
+        assert_eq!(send(&proxy, get("alice.naru.pub", "/myindex.html")).await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(source.requests(), ["alice/myindex.html"]);
+
+        let (proxy, _) = state(&[], &objects);
+        assert_eq!(send(&proxy, get("alice.naru.pub", "/blog/index.html")).await.body(), "blog");
+    }
+
+    #[tokio::test]
+    async fn upstream_access_denied_is_masked_as_missing() {
+        let head = || Request::head("/private.js").header("host", "alice.naru.pub").body(Full::new(Bytes::new())).unwrap();
+        for (statuses, expected) in [
+            (None, StatusCode::NOT_FOUND),
+            (Some(r#"{"AccessDenied": 404}"#), StatusCode::NOT_FOUND),
+            (Some(r#"{"AccessDenied": 403}"#), StatusCode::FORBIDDEN),
+            // By upstream status when the error code isn't listed
+            (Some(r#"{"403": 410}"#), StatusCode::GONE),
+        ] {
+            let vars: Vec<(&str, &str)> = statuses.iter().map(|statuses| ("UPSTREAM_ERROR_STATUSES", *statuses)).collect();
+            let (proxy, source) = state(&vars, &[]);
+            source.fail_key_with("alice/private.js", 403);
+            let response = send(&proxy, get("alice.naru.pub", "/private.js")).await;
+            assert_eq!(response.status(), expected, "{:?}", statuses);
+            assert_eq!(send(&proxy, head()).await.status(), expected, "{:?}", statuses);
+        }
+        assert!(Config::for_test(&[("UPSTREAM_ERROR_STATUSES", r#"{"AccessDenied": 200}"#)]).is_err());
+    }
+
+    #[tokio::test]
+    async fn apex_keys_start_at_the_bucket_root() {
+        let objects = [
+            ("index.html", "text/html", "home"),
+            ("blog/index.html", "text/html", "blog"),
+            ("about/index.html", "text/html", "about"),
+            ("alice/index.html", "text/html", "alice"),
+        ];
+        let (proxy, source) = state(&[], &objects);
+        for (host, path, body) in [
+            ("example.com", "/", "home"),
+            ("example.com", "/blog/", "blog"),
+            ("example.com:8080", "/about", "about"),
+            ("alice.example.com", "/", "alice"),
+        ] {
+            assert_eq!(send(&proxy, get(host, path)).await.body(), body, "{}{}", host, path);
+        }
+        assert_eq!(
+            source.requests(),
+            ["index.html", "blog/index.html", "about/index.html", "alice/index.html"]
+        );
+
+        // A missing apex page falls back to the landing response, while a site's stays a 404
+        let (proxy, _) = state(&[("APEX_MESSAGE", "Welcome")], &[]);
+        let response = send(&proxy, get("example.com", "/")).await;
+        assert_eq!((response.status(), response.body().as_ref()), (StatusCode::OK, &b"Welcome"[..]));
+        assert_eq!(send(&proxy, get("alice.example.com", "/")).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn enforcing_accept_refuses_unwanted_types() {
+        let objects = [("alice/data.json", "application/json", "{}")];
+        let with_accept = |accept: &'static str| {
+            let mut request = get("alice.naru.pub", "/data.json");
+            request.headers_mut().insert("accept", HeaderValue::from_static(accept));
+            request
+        };
+        let (proxy, _) = state(&[("ENFORCE_ACCEPT", "true")], &objects);
+        for (accept, expected) in [
+            ("application/json", StatusCode::OK),
+            ("text/html, */*;q=0.1", StatusCode::OK),
+            ("text/html", StatusCode::NOT_ACCEPTABLE),
+        ] {
+            let response = send(&proxy, with_accept(accept)).await;
+            assert_eq!(response.status(), expected, "{}", accept);
+            assert_eq!(response.headers()["vary"], "Accept");
+        }
+        assert_eq!(send(&proxy, get("alice.naru.pub", "/data.json")).await.status(), StatusCode::OK);
+
+        // Off by default
+        let (proxy, _) = state(&[], &objects);
+        assert_eq!(send(&proxy, with_accept("text/html")).await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn saturating_the_s3_cap_queues_or_rejects() {
+        let objects = [("alice/a.js", "text/javascript", "a"), ("alice/b.js", "text/javascript", "b")];
+        let cases = [
+            ("0", [StatusCode::OK, StatusCode::SERVICE_UNAVAILABLE]),
+            ("1000", [StatusCode::OK; 2]),
+        ];
+        for (queue_timeout_ms, statuses) in cases {
+            let vars = [
+                ("S3_MAX_CONCURRENT_REQUESTS", "1"),
+                ("S3_QUEUE_TIMEOUT_MS", queue_timeout_ms),
+                ("THROTTLE_RETRY_AFTER_SECS", "3"),
+            ];
+            let (proxy, source) = state(&vars, &objects);
+            source.delay_by(Duration::from_millis(50));
+            let first = send(&proxy, get("alice.naru.pub", "/a.js"));
+            let second = async {
+                // Once the first request holds the only permit
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                send(&proxy, get("alice.naru.pub", "/b.js")).await
+            };
+            let (first, second) = tokio::join!(first, second);
+            assert_eq!([first.status(), second.status()], statuses, "{}", queue_timeout_ms);
+            if second.status() == StatusCode::SERVICE_UNAVAILABLE {
+                assert_eq!(second.headers()["retry-after"], "3");
+                assert_eq!(source.requests(), ["alice/a.js"]);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn every_response_forbids_sniffing() {
+        let vars = [("UPSTREAM_ERROR_STATUSES", r#"{"AccessDenied": 403}"#)];
+        let (proxy, source) = state(&vars, &[("alice/index.html", "text/html", "home")]);
+        source.fail_key_with("alice/private.js", 403);
+        source.fail_key_with("alice/broken.js", 503);
+        let mut revalidate = get("alice.naru.pub", "/");
+        revalidate.headers_mut().insert("if-none-match", HeaderValue::from_static("\"4\""));
+        let options = Request::options("/").header("host", "alice.naru.pub").body(Full::new(Bytes::new())).unwrap();
+        let requests = [
+            (get("alice.naru.pub", "/"), StatusCode::OK),
+            (revalidate, StatusCode::NOT_MODIFIED),
+            (options, StatusCode::NO_CONTENT),
+            (get("alice.naru.pub", "/index.html/"), StatusCode::NOT_FOUND),
+            (get("alice.naru.pub", "/../bob/"), StatusCode::BAD_REQUEST),
+            (get("alice.naru.pub", "/private.js"), StatusCode::FORBIDDEN),
+            (get("alice.naru.pub", "/broken.js"), StatusCode::SERVICE_UNAVAILABLE),
+            (get("alice.naru.pub", "/clip.mp4"), StatusCode::FOUND),
+        ];
+        for (request, status) in requests {
+            let path = request.uri().to_string();
+            let response = send(&proxy, request).await;
+            assert_eq!(response.status(), status, "{}", path);
+            assert_eq!(response.headers()["x-content-type-options"], "nosniff", "{}", path);
+        }
+
+        // The admin port too, errors included
+        let admin = |path: &str| Request::get(path).body(Full::new(Bytes::new())).unwrap();
+        let requests = [
+            (admin("/_health"), StatusCode::OK),
+            (admin("/_metrics"), StatusCode::OK),
+            (admin("/_config"), StatusCode::METHOD_NOT_ALLOWED),
+            (admin("/_missing"), StatusCode::NOT_FOUND),
+        ];
+        for (request, status) in requests {
+            let path = request.uri().to_string();
+            let response = send_admin(&proxy, request).await;
+            assert_eq!(response.status(), status, "{}", path);
+            assert_eq!(response.headers()["x-content-type-options"], "nosniff", "{}", path);
+        }
+        let (proxy, _) = state(&[("ADMIN_API_KEY", "secret")], &[]);
+        let response = send_admin(&proxy, admin("/_status")).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()["x-content-type-options"], "nosniff");
+    }
+
+    #[tokio::test]
+    async fn ab_variants_stay_out_of_shared_caches() {
+        let vars = [
+            ("AB_TEST_PREFIX_A", "site-a"),
+            ("AB_TEST_PREFIX_B", "site-b"),
+            ("AB_TEST_PERCENTAGE_B", "50"),
+            ("SURROGATE_CONTROL", "max-age=86400"),
+        ];
+        let objects = [("site-a/index.html", "text/html", "a"), ("site-b/index.html", "text/html", "b")];
+        let (proxy, source) = state(&vars, &objects);
+        source.insert(
+            "site-b/app.js",
+            MockObject {
+                cache_control: Some("public, max-age=600, s-maxage=3600".to_string()),
+                ..MockObject::new("text/javascript", "1")
+            },
+        );
+        let mut pinned = get("alice.naru.pub", "/app.js");
+        pinned.headers_mut().insert("cookie", HeaderValue::from_static("__ab_variant=B"));
+        let response = send(&proxy, pinned).await;
+        assert_eq!(response.headers()["x-ab-variant"], "B");
+        assert_eq!(response.headers()["cache-control"], "private, max-age=600");
+        assert!(response.headers().get_all("vary").iter().any(|vary| vary == "Cookie"));
+        assert!(!response.headers().contains_key("surrogate-control"));
+
+        // Chosen by client IP, and missing objects alike
+        for path in ["/", "/missing.js"] {
+            let response = send(&proxy, get("alice.naru.pub", path)).await;
+            assert!(response.headers()["cache-control"].to_str().unwrap().starts_with("private"), "{}", path);
+            assert!(response.headers().get_all("vary").iter().any(|vary| vary == "Cookie"), "{}", path);
+        }
+
+        // Without A/B testing the stored value is kept
+        let (proxy, source) = state(&[], &[]);
+        source.insert(
+            "alice/app.js",
+            MockObject {
+                cache_control: Some("public, max-age=600".to_string()),
+                ..MockObject::new("text/javascript", "1")
+            },
+        );
+        let response = send(&proxy, get("alice.naru.pub", "/app.js")).await;
+        assert_eq!(response.headers()["cache-control"], "public, max-age=600");
+    }
+}
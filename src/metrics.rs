@@ -0,0 +1,382 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    const fn new() -> Self {
+        Gauge(AtomicI64::new(0))
+    }
+
+    pub fn set(&self, value: i64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    pub fn add(&self, delta: i64) {
+        self.0.fetch_add(delta, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// Counts itself in a gauge for as long as it lives
+pub struct GaugeGuard(&'static Gauge);
+
+impl GaugeGuard {
+    pub fn new(gauge: &'static Gauge) -> Self {
+        gauge.add(1);
+        GaugeGuard(gauge)
+    }
+}
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.add(-1);
+    }
+}
+
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    const fn new() -> Self {
+        Counter(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn reset(&self) {
+        self.0.store(0, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// Cumulative histogram of integer observations over fixed upper bounds
+pub struct Histogram<const N: usize> {
+    bounds: [u64; N],
+    buckets: [AtomicU64; N],
+    sum: AtomicU64,
+    count: AtomicU64,
+}
+
+impl<const N: usize> Histogram<N> {
+    const fn new(bounds: [u64; N]) -> Self {
+        Histogram {
+            bounds,
+            buckets: [const { AtomicU64::new(0) }; N],
+            sum: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, value: u64) {
+        if let Some(i) = self.bounds.iter().position(|&bound| value <= bound) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum.fetch_add(value, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn reset(&self) {
+        for bucket in &self.buckets {
+            bucket.store(0, Ordering::Relaxed);
+        }
+        self.sum.store(0, Ordering::Relaxed);
+        self.count.store(0, Ordering::Relaxed);
+    }
+}
+
+// Process-wide metrics, rendered in the Prometheus text format
+pub struct Metrics {
+    // 0 = closed, 1 = open, 2 = half-open
+    pub circuit_breaker_state: Gauge,
+    // Requests waiting for an S3 concurrency permit
+    pub s3_queue_depth: Gauge,
+    pub s3_requests_in_flight: Gauge,
+    // Requests turned away because no permit freed up within S3_QUEUE_TIMEOUT_MS
+    pub s3_queue_rejections: Counter,
+    pub requests_in_flight: Gauge,
+    pub requests: Counter,
+    // GetObject and HeadObject calls, and those that failed upstream (5xx, timeouts, network errors)
+    pub s3_requests: Counter,
+    pub s3_errors: Counter,
+    pub s3_throttled: Counter,
+    // Object bodies that didn't match the checksum S3 sent with them
+    pub checksum_failures: Counter,
+    // Expired cache entries served while refreshing, and because a refresh failed
+    pub cache_stale_hits: Counter,
+    pub cache_stale_error_hits: Counter,
+    // Eviction notices dropped because the queue was full or the webhook didn't accept them in time
+    pub eviction_webhook_failures: Counter,
+    pub connections: Counter,
+    pub connections_active: Gauge,
+    // Requests served over each closed connection
+    pub requests_per_connection: Histogram<8>,
+    // Sizes of bodies sent to clients, split by `Content-Type` to keep cardinality at two series
+    pub response_body_bytes_text: Histogram<4>,
+    pub response_body_bytes_binary: Histogram<4>,
+}
+
+pub static METRICS: Metrics = Metrics {
+    circuit_breaker_state: Gauge::new(),
+    s3_queue_depth: Gauge::new(),
+    s3_requests_in_flight: Gauge::new(),
+    s3_queue_rejections: Counter::new(),
+    requests_in_flight: Gauge::new(),
+    requests: Counter::new(),
+    s3_requests: Counter::new(),
+    s3_errors: Counter::new(),
+    s3_throttled: Counter::new(),
+    checksum_failures: Counter::new(),
+    cache_stale_hits: Counter::new(),
+    cache_stale_error_hits: Counter::new(),
+    eviction_webhook_failures: Counter::new(),
+    connections: Counter::new(),
+    connections_active: Gauge::new(),
+    requests_per_connection: Histogram::new([1, 2, 5, 10, 25, 50, 100, 1000]),
+    response_body_bytes_text: Histogram::new(BODY_SIZE_BOUNDS),
+    response_body_bytes_binary: Histogram::new(BODY_SIZE_BOUNDS),
+};
+
+// 1 KB, 10 KB, 100 KB and 1 MB
+const BODY_SIZE_BOUNDS: [u64; 4] = [1024, 10 * 1024, 100 * 1024, 1024 * 1024];
+
+impl Metrics {
+    // Zero counters and histograms between test cases. Gauges reflect live state and are kept.
+    pub fn reset(&self) {
+        self.s3_throttled.reset();
+        self.s3_queue_rejections.reset();
+        self.requests.reset();
+        self.s3_requests.reset();
+        self.s3_errors.reset();
+        self.checksum_failures.reset();
+        self.cache_stale_hits.reset();
+        self.cache_stale_error_hits.reset();
+        self.eviction_webhook_failures.reset();
+        self.connections.reset();
+        self.requests_per_connection.reset();
+        self.response_body_bytes_text.reset();
+        self.response_body_bytes_binary.reset();
+    }
+
+    pub fn observe_body(&self, content_type: Option<&str>, len: u64) {
+        match is_text(content_type) {
+            true => self.response_body_bytes_text.observe(len),
+            false => self.response_body_bytes_binary.observe(len),
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        write_gauge(
+            &mut out,
+            "proxy_circuit_breaker_state",
+            "State of the S3 circuit breaker (0 = closed, 1 = open, 2 = half-open)",
+            &self.circuit_breaker_state,
+        );
+        write_gauge(
+            &mut out,
+            "proxy_s3_queue_depth",
+            "Requests waiting for an S3 concurrency permit",
+            &self.s3_queue_depth,
+        );
+        write_gauge(
+            &mut out,
+            "proxy_s3_requests_in_flight",
+            "S3 requests holding a concurrency permit",
+            &self.s3_requests_in_flight,
+        );
+        write_counter(
+            &mut out,
+            "proxy_s3_queue_rejections_total",
+            "Requests answered with 503 because no S3 concurrency permit freed up in time",
+            &self.s3_queue_rejections,
+        );
+        write_counter(
+            &mut out,
+            "proxy_requests_total",
+            "Client requests received",
+            &self.requests,
+        );
+        write_counter(
+            &mut out,
+            "proxy_s3_requests_total",
+            "GetObject and HeadObject calls sent to S3",
+            &self.s3_requests,
+        );
+        write_counter(
+            &mut out,
+            "proxy_s3_errors_total",
+            "S3 calls that failed with a server error, timeout or network error",
+            &self.s3_errors,
+        );
+        write_gauge(
+            &mut out,
+            "proxy_requests_in_flight",
+            "Client requests currently being handled",
+            &self.requests_in_flight,
+        );
+        write_counter(
+            &mut out,
+            "proxy_s3_throttled_total",
+            "S3 requests rejected with a throttling error",
+            &self.s3_throttled,
+        );
+        write_counter(
+            &mut out,
+            "proxy_checksum_failures_total",
+            "Object bodies from S3 that failed checksum verification",
+            &self.checksum_failures,
+        );
+        write_counter(
+            &mut out,
+            "proxy_eviction_webhook_failures_total",
+            "Cache eviction notices that couldn't be delivered to EVICTION_WEBHOOK_URL",
+            &self.eviction_webhook_failures,
+        );
+        write_counter(
+            &mut out,
+            "proxy_cache_stale_hits_total",
+            "Expired cache entries served while being refreshed in the background",
+            &self.cache_stale_hits,
+        );
+        write_counter(
+            &mut out,
+            "proxy_cache_stale_error_hits_total",
+            "Expired cache entries served because S3 failed to return a fresh copy",
+            &self.cache_stale_error_hits,
+        );
+        write_counter(
+            &mut out,
+            "proxy_connections_total",
+            "Client connections accepted",
+            &self.connections,
+        );
+        write_gauge(
+            &mut out,
+            "proxy_connections_active",
+            "Client connections currently open",
+            &self.connections_active,
+        );
+        write_histogram(
+            &mut out,
+            "proxy_requests_per_connection",
+            "Requests served over each client connection, recorded when it closes",
+            &self.requests_per_connection,
+        );
+        write_labeled_histograms(
+            &mut out,
+            "proxy_response_body_bytes",
+            "Sizes of response bodies sent to clients, by text or binary content type",
+            "class",
+            &[("text", &self.response_body_bytes_text), ("binary", &self.response_body_bytes_binary)],
+        );
+        out
+    }
+}
+
+// Whether a body counts as text for the size histograms, e.g. HTML, CSS or JSON
+fn is_text(content_type: Option<&str>) -> bool {
+    let essence = content_type.unwrap_or_default().split(';').next().unwrap_or_default().trim();
+    essence.starts_with("text/")
+        || essence.ends_with("+json")
+        || essence.ends_with("+xml")
+        || matches!(essence, "application/json" | "application/javascript" | "application/xml")
+}
+
+fn write_gauge(out: &mut String, name: &str, help: &str, gauge: &Gauge) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    let _ = writeln!(out, "{} {}", name, gauge.get());
+}
+
+fn write_counter(out: &mut String, name: &str, help: &str, counter: &Counter) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{} {}", name, counter.get());
+}
+
+fn write_histogram<const N: usize>(out: &mut String, name: &str, help: &str, histogram: &Histogram<N>) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} histogram", name);
+    write_histogram_series(out, name, "", histogram);
+}
+
+fn write_labeled_histograms<const N: usize>(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    label: &str,
+    histograms: &[(&str, &Histogram<N>)],
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} histogram", name);
+    for (value, histogram) in histograms {
+        write_histogram_series(out, name, &format!("{}=\"{}\"", label, value), histogram);
+    }
+}
+
+// One series of a histogram, with `labels` like `class="text"` added to each sample
+fn write_histogram_series<const N: usize>(out: &mut String, name: &str, labels: &str, histogram: &Histogram<N>) {
+    let (labels, bucket_labels) = match labels {
+        "" => (String::new(), String::new()),
+        labels => (format!("{{{}}}", labels), format!("{},", labels)),
+    };
+    let mut cumulative = 0;
+    for (bound, bucket) in histogram.bounds.iter().zip(&histogram.buckets) {
+        cumulative += bucket.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{{}le=\"{}\"}} {}", name, bucket_labels, bound, cumulative);
+    }
+    let count = histogram.count.load(Ordering::Relaxed);
+    let _ = writeln!(out, "{}_bucket{{{}le=\"+Inf\"}} {}", name, bucket_labels, count);
+    let _ = writeln!(out, "{}_sum{} {}", name, labels, histogram.sum.load(Ordering::Relaxed));
+    let _ = writeln!(out, "{}_count{} {}", name, labels, count);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_bodies_into_text_and_binary() {
+        for text in ["text/html; charset=utf-8", "application/json", "application/ld+json", "image/svg+xml"] {
+            assert!(is_text(Some(text)), "{}", text);
+        }
+        for binary in ["image/png", "application/octet-stream", "video/mp4", ""] {
+            assert!(!is_text(Some(binary)), "{}", binary);
+        }
+        assert!(!is_text(None));
+    }
+
+    #[test]
+    fn renders_cumulative_buckets_for_each_class() {
+        let (text, binary) = (Histogram::new([10, 100]), Histogram::new([10, 100]));
+        for len in [5, 50, 500] {
+            text.observe(len);
+        }
+        binary.observe(10);
+        let mut out = String::new();
+        write_labeled_histograms(&mut out, "body_bytes", "Body sizes", "class", &[("text", &text), ("binary", &binary)]);
+        let expected = [
+            "# HELP body_bytes Body sizes",
+            "# TYPE body_bytes histogram",
+            r#"body_bytes_bucket{class="text",le="10"} 1"#,
+            r#"body_bytes_bucket{class="text",le="100"} 2"#,
+            r#"body_bytes_bucket{class="text",le="+Inf"} 3"#,
+            r#"body_bytes_sum{class="text"} 555"#,
+            r#"body_bytes_count{class="text"} 3"#,
+            r#"body_bytes_bucket{class="binary",le="10"} 1"#,
+            r#"body_bytes_bucket{class="binary",le="100"} 1"#,
+            r#"body_bytes_bucket{class="binary",le="+Inf"} 1"#,
+            r#"body_bytes_sum{class="binary"} 10"#,
+            r#"body_bytes_count{class="binary"} 1"#,
+        ];
+        assert_eq!(out.lines().collect::<Vec<_>>(), expected);
+    }
+}
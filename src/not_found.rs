@@ -0,0 +1,87 @@
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::header::HeaderValue;
+use hyper::Response;
+use percent_encoding::percent_decode_str;
+
+// Fill in a 404 page template, keeping the original status and extensions.
+// Supported tokens: `{{path}}`, `{{host}}` and `{{request_id}}`.
+pub fn render(
+    template: &str,
+    host: &str,
+    path: &str,
+    request_id: &str,
+    response: Response<Full<Bytes>>,
+) -> Response<Full<Bytes>> {
+    let path = percent_decode_str(path).decode_utf8_lossy();
+    // Every value comes from the request, so all of them are escaped. Tokens are replaced in one
+    // pass, so a path like `/{{host}}` is shown as is.
+    let values = [("{{path}}", &*path), ("{{host}}", host), ("{{request_id}}", request_id)];
+    let mut page = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        page.push_str(&rest[..start]);
+        rest = &rest[start..];
+        match values.iter().find(|(token, _)| rest.starts_with(token)) {
+            Some((token, value)) => {
+                page.push_str(&escape_html(value));
+                rest = &rest[token.len()..];
+            }
+            None => {
+                page.push_str("{{");
+                rest = &rest[2..];
+            }
+        }
+    }
+    page.push_str(rest);
+
+    let (mut parts, _) = response.into_parts();
+    parts.headers.insert("content-type", HeaderValue::from_static("text/html; charset=utf-8"));
+    Response::from_parts(parts, Full::new(Bytes::from(page)))
+}
+
+fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+    use hyper::StatusCode;
+
+    fn not_found() -> Response<Full<Bytes>> {
+        Response::builder().status(404).body(Full::new(Bytes::from("Not Found"))).unwrap()
+    }
+
+    async fn page(template: &str, host: &str, path: &str) -> String {
+        let response = render(template, host, path, "req-1", not_found());
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()["content-type"], "text/html; charset=utf-8");
+        let bytes = response.into_body().collect().await.unwrap().to_bytes();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn fills_in_every_token() {
+        let template = "<p>{{host}}{{path}} ({{request_id}}) {{unknown}} {{</p>";
+        assert_eq!(page(template, "alice.naru.pub", "/a%20b").await, "<p>alice.naru.pub/a b (req-1) {{unknown}} {{</p>");
+    }
+
+    #[tokio::test]
+    async fn escapes_request_values_once() {
+        let page = page("{{path}} {{host}}", "a\"b", "/%3Cscript%3E'&/{{host}}").await;
+        assert_eq!(page, "/&lt;script&gt;&#39;&amp;/{{host}} a&quot;b");
+    }
+}
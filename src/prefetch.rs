@@ -0,0 +1,202 @@
+use crate::cache::CacheStatus;
+use crate::{object_key, resolve_paths, serve_object, AppState, Fetch};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::{Method, Response, StatusCode};
+use lru::LruCache;
+use percent_encoding::percent_decode_str;
+use regex_lite::Regex;
+use std::collections::HashSet;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tracing::debug;
+
+// Enough for a page's scripts and styles without flooding S3 from one oversized page
+const MAX_ASSETS_PER_PAGE: usize = 32;
+// Assets waiting for or holding a permit. Past this, new ones are dropped rather than queued.
+const MAX_PENDING: usize = 256;
+// Assets that weren't found aren't fetched again for a while, even if 404s aren't cached
+const MISS_MEMORY: usize = 1024;
+const MISS_TTL: Duration = Duration::from_secs(60);
+
+// Bounds background cache warming for assets referenced by HTML pages
+pub struct Prefetcher {
+    permits: Semaphore,
+    pending: AtomicUsize,
+    // Keys of assets found missing, with when
+    misses: Mutex<LruCache<String, Instant>>,
+}
+
+impl Prefetcher {
+    pub fn new(concurrency: usize) -> Self {
+        Self {
+            permits: Semaphore::new(concurrency),
+            pending: AtomicUsize::new(0),
+            misses: Mutex::new(LruCache::new(NonZeroUsize::new(MISS_MEMORY).unwrap())),
+        }
+    }
+
+    // Claim a place for another asset, or false when MAX_PENDING are already waiting or running
+    fn try_enqueue(&self) -> bool {
+        self.pending
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |pending| {
+                (pending < MAX_PENDING).then_some(pending + 1)
+            })
+            .is_ok()
+    }
+
+    fn finish(&self) {
+        self.pending.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    #[cfg(test)]
+    pub fn is_idle(&self) -> bool {
+        self.pending.load(Ordering::Relaxed) == 0
+    }
+
+    fn missed_recently(&self, key: &str) -> bool {
+        let mut misses = self.misses.lock().unwrap();
+        match misses.peek(key) {
+            Some(missed) if missed.elapsed() < MISS_TTL => true,
+            Some(_) => {
+                misses.pop(key);
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn record_miss(&self, key: String) {
+        self.misses.lock().unwrap().put(key, Instant::now());
+    }
+}
+
+static ASSET_REFERENCE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"(?i)<(?:link|script)\b[^>]*?\s(?:href|src)\s*=\s*["']([^"']+)["']"#).unwrap()
+});
+
+// Warm the cache with the same-origin scripts and styles an HTML page references, so the
+// browser's follow-up requests are cache hits. Fetches run in the background and never delay the page.
+pub async fn apply(
+    state: &Arc<AppState>,
+    site_prefix: &str,
+    page_path: &str,
+    response: Response<Full<Bytes>>,
+) -> Response<Full<Bytes>> {
+    if !state.config.prefetch_linked_assets || !state.cache.is_enabled() {
+        return response;
+    }
+    let is_html = response
+        .headers()
+        .get("content-type")
+        .and_then(|ct| ct.to_str().ok())
+        .is_some_and(|ct| ct.trim_start().to_ascii_lowercase().starts_with("text/html"));
+    // Encoded bodies can't be searched for tags
+    let is_encoded = response.headers().contains_key("content-encoding");
+    if response.status() != StatusCode::OK || !is_html || is_encoded {
+        return response;
+    }
+    // A page served from the cache had its assets warmed when it was fetched
+    let cache_status = response.extensions().get::<CacheStatus>().copied();
+    if matches!(cache_status, Some(CacheStatus::Hit | CacheStatus::Stale)) {
+        return response;
+    }
+
+    let (parts, body) = response.into_parts();
+    let body = body.collect().await.unwrap().to_bytes();
+    spawn(state, site_prefix, page_path, &body);
+    Response::from_parts(parts, Full::new(body))
+}
+
+fn spawn(state: &Arc<AppState>, site_prefix: &str, page_path: &str, html: &Bytes) {
+    let html = String::from_utf8_lossy(html);
+    let mut seen = HashSet::new();
+    let assets: Vec<String> = ASSET_REFERENCE
+        .captures_iter(&html)
+        .filter_map(|captures| resolve(page_path, &captures[1]))
+        .filter(|asset| seen.insert(asset.clone()))
+        .take(MAX_ASSETS_PER_PAGE)
+        .collect();
+
+    for asset in assets {
+        let paths = resolve_paths(&asset, &state.config);
+        // Checked without counting as a cache lookup, so warming doesn't skew the hit rate
+        let keys: Vec<String> = paths
+            .iter()
+            .map(|path| object_key(&state.config, site_prefix, path))
+            .collect();
+        let cached = keys.iter().any(|key| state.cache.contains(key));
+        if cached || keys.iter().all(|key| state.prefetcher.missed_recently(key)) {
+            continue;
+        }
+        if !state.prefetcher.try_enqueue() {
+            debug!("Prefetch queue full, dropping {}", asset);
+            break;
+        }
+        let state = state.clone();
+        let site_prefix = site_prefix.to_string();
+        tokio::spawn(async move {
+            prefetch(&state, &site_prefix, &paths, keys).await;
+            state.prefetcher.finish();
+        });
+    }
+}
+
+async fn prefetch(state: &Arc<AppState>, site_prefix: &str, paths: &[String], keys: Vec<String>) {
+    // Waiting here holds no S3 permit, only a place in line
+    let Ok(_permit) = state.prefetcher.permits.acquire().await else {
+        return;
+    };
+    let method = Method::GET;
+    let fetch = Fetch {
+        method: &method,
+        version: None,
+        accepts_brotli: false,
+        range: None,
+        deadline: None,
+    };
+    for path in paths {
+        match serve_object(state, &fetch, site_prefix, path).await {
+            Ok(response) if response.status().is_success() => return,
+            Ok(_) => {}
+            Err(err) => debug!("Prefetching {} failed: {}", path, err),
+        }
+    }
+    for key in keys {
+        state.prefetcher.record_miss(key);
+    }
+}
+
+// Resolve a reference against the page, or `None` if it points at another origin
+fn resolve(page_path: &str, reference: &str) -> Option<String> {
+    let reference = reference.trim();
+    let reference = reference.split(['?', '#']).next().unwrap_or_default();
+    if reference.is_empty() || reference.starts_with("//") || reference.contains(':') {
+        return None;
+    }
+    let reference = percent_decode_str(reference).decode_utf8().ok()?;
+
+    let joined = match reference.strip_prefix('/') {
+        Some(absolute) => absolute.to_string(),
+        None => match page_path.rsplit_once('/') {
+            Some((dir, _)) => format!("{}/{}", dir, reference),
+            None => reference.to_string(),
+        },
+    };
+
+    // Collapse `.` and `..` segments without climbing above the site root
+    let mut segments = Vec::new();
+    for segment in joined.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    Some(format!("/{}", segments.join("/")))
+}
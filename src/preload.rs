@@ -0,0 +1,24 @@
+use crate::config::PathRule;
+use crate::rules;
+use hyper::header::HeaderValue;
+use hyper::HeaderMap;
+
+// Send a single `Link` header listing every preload that applies to an HTML page, global or for its site.
+// Browsers preload from it, and CDNs can turn it into `103 Early Hints`.
+pub fn apply(rules: &[PathRule], subdomain: &str, path: &str, headers: &mut HeaderMap) {
+    let is_html = headers
+        .get("content-type")
+        .and_then(|ct| ct.to_str().ok())
+        .is_some_and(|ct| ct.trim_start().to_ascii_lowercase().starts_with("text/html"));
+    if !is_html {
+        return;
+    }
+
+    let links = rules::matching(rules, subdomain, path);
+    if links.is_empty() {
+        return;
+    }
+    if let Ok(value) = HeaderValue::from_str(&links.join(", ")) {
+        headers.insert("link", value);
+    }
+}
@@ -0,0 +1,152 @@
+use crate::digest;
+use aws_sdk_s3::primitives::{DateTime, DateTimeFormat};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::header::HeaderValue;
+use hyper::{HeaderMap, Response, StatusCode};
+
+// Browsers seeking in a `<video>` send `Range: bytes=<start>-` and need, for each chunk:
+// - `206 Partial Content`
+// - `Accept-Ranges: bytes` (advertised on the full response too, or seeking is disabled)
+// - `Content-Range: bytes <start>-<end>/<total>`
+// - `Content-Length` of the chunk, not of the whole object
+//
+// With `If-Range`, the range only applies while the object still matches the client's copy.
+// Video is only served by the proxy when SERVED_EXTENSIONS lists it, e.g. `mp4`; otherwise the
+// redirect leaves seeking to the public bucket.
+pub async fn apply(
+    range: Option<&HeaderValue>,
+    if_range: Option<&HeaderValue>,
+    response: Response<Full<Bytes>>,
+) -> Response<Full<Bytes>> {
+    if response.status() != StatusCode::OK {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    parts.headers.insert("accept-ranges", HeaderValue::from_static("bytes"));
+    let Some(range) = range.and_then(|r| r.to_str().ok()) else {
+        return Response::from_parts(parts, body);
+    };
+    if if_range.is_some_and(|if_range| !is_unchanged(if_range, &parts.headers)) {
+        return Response::from_parts(parts, body);
+    }
+
+    let body = body.collect().await.unwrap().to_bytes();
+    let total = body.len() as u64;
+    match parse(range, total) {
+        Some(Ok((start, end))) => {
+            parts.status = StatusCode::PARTIAL_CONTENT;
+            digest::remove(&mut parts.headers);
+            let content_range = format!("bytes {}-{}/{}", start, end, total);
+            parts.headers.insert("content-range", HeaderValue::from_str(&content_range).unwrap());
+            Response::from_parts(parts, Full::new(body.slice(start as usize..=end as usize)))
+        }
+        Some(Err(())) => {
+            parts.status = StatusCode::RANGE_NOT_SATISFIABLE;
+            parts.headers.remove("content-type");
+            digest::remove(&mut parts.headers);
+            let content_range = format!("bytes */{}", total);
+            parts.headers.insert("content-range", HeaderValue::from_str(&content_range).unwrap());
+            Response::from_parts(parts, Full::new(Bytes::new()))
+        }
+        // Unsupported or malformed ranges are ignored and the full body is served
+        None => Response::from_parts(parts, Full::new(body)),
+    }
+}
+
+// Whether an `If-Range` ETag or HTTP-date still matches the object
+fn is_unchanged(if_range: &HeaderValue, headers: &HeaderMap) -> bool {
+    let Ok(if_range) = if_range.to_str().map(str::trim) else {
+        return false;
+    };
+    if if_range.starts_with('"') || if_range.starts_with("W/") {
+        // Only strong ETags can validate a range
+        let etag = headers.get("etag").and_then(|etag| etag.to_str().ok());
+        return !if_range.starts_with("W/") && etag.is_some_and(|etag| !etag.starts_with("W/") && etag == if_range);
+    }
+
+    // HTTP-dates have second granularity, like `Last-Modified`
+    let date = DateTime::from_str(if_range, DateTimeFormat::HttpDate);
+    let last_modified = headers
+        .get("last-modified")
+        .and_then(|lm| lm.to_str().ok())
+        .and_then(|lm| DateTime::from_str(lm, DateTimeFormat::HttpDate).ok());
+    match (date, last_modified) {
+        (Ok(date), Some(last_modified)) => last_modified.secs() <= date.secs(),
+        _ => false,
+    }
+}
+
+// Whether a `Range` asks for a single byte range, which S3 can serve directly
+pub fn is_single(range: &str) -> bool {
+    range.trim().starts_with("bytes=") && !range.contains(',')
+}
+
+// Parse a single `bytes=` range into inclusive offsets, or `Err` if it can't be satisfied
+pub fn parse(range: &str, total: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = range.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+
+    let (start, end) = if start.is_empty() {
+        // Suffix range: the last `end` bytes
+        let suffix: u64 = end.parse().ok()?;
+        if suffix == 0 {
+            return Some(Err(()));
+        }
+        (total.saturating_sub(suffix), total.checked_sub(1)?)
+    } else {
+        let start: u64 = start.parse().ok()?;
+        let end = if end.is_empty() {
+            total.saturating_sub(1)
+        } else {
+            end.parse::<u64>().ok()?.min(total.saturating_sub(1))
+        };
+        (start, end)
+    };
+
+    if start >= total || start > end {
+        return Some(Err(()));
+    }
+    Some(Ok((start, end)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LAST_MODIFIED: &str = "Wed, 01 Jan 2025 00:00:00 GMT";
+
+    fn headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("etag", HeaderValue::from_static("\"abc\""));
+        headers.insert("last-modified", HeaderValue::from_static(LAST_MODIFIED));
+        headers
+    }
+
+    #[test]
+    fn if_range_dates_compare_by_the_second() {
+        for (if_range, unchanged) in [
+            (LAST_MODIFIED, true),
+            ("Thu, 02 Jan 2025 00:00:00 GMT", true),
+            ("Tue, 31 Dec 2024 23:59:59 GMT", false),
+            ("yesterday", false),
+        ] {
+            assert_eq!(is_unchanged(&HeaderValue::from_static(if_range), &headers()), unchanged, "{}", if_range);
+        }
+        let mut without_date = headers();
+        without_date.remove("last-modified");
+        assert!(!is_unchanged(&HeaderValue::from_static(LAST_MODIFIED), &without_date));
+    }
+
+    #[test]
+    fn if_range_etags_must_match_strongly() {
+        for (if_range, unchanged) in [("\"abc\"", true), ("\"other\"", false), ("W/\"abc\"", false)] {
+            assert_eq!(is_unchanged(&HeaderValue::from_static(if_range), &headers()), unchanged, "{}", if_range);
+        }
+    }
+}
@@ -0,0 +1,31 @@
+use crate::config::PathRule;
+
+// Values of every rule that applies to a path, whether global or for its site
+pub fn matching<'a>(rules: &'a [PathRule], subdomain: &str, path: &str) -> Vec<&'a str> {
+    rules
+        .iter()
+        .filter(|rule| rule.subdomain.as_deref().is_none_or(|s| s.eq_ignore_ascii_case(subdomain)))
+        .filter(|rule| glob_match(&rule.pattern, path))
+        .flat_map(|rule| rule.values.iter().map(String::as_str))
+        .collect()
+}
+
+// Match a glob where `*` stands for any run of characters, including `/`
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let Some((first, rest)) = pattern.split_once('*') else {
+        return pattern == text;
+    };
+    let Some(mut text) = text.strip_prefix(first) else {
+        return false;
+    };
+
+    let mut parts: Vec<&str> = rest.split('*').collect();
+    let last = parts.pop().unwrap_or_default();
+    for part in parts {
+        match text.find(part) {
+            Some(at) => text = &text[at + part.len()..],
+            None => return false,
+        }
+    }
+    text.ends_with(last)
+}
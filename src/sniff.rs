@@ -0,0 +1,130 @@
+// Leading bytes of common binary formats and the type they identify
+const SIGNATURES: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"%PDF-", "application/pdf"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"\x1f\x8b", "application/gzip"),
+    (b"\x00asm", "application/wasm"),
+    (b"wOFF", "font/woff"),
+    (b"wOF2", "font/woff2"),
+    (b"OggS", "audio/ogg"),
+    (b"ID3", "audio/mpeg"),
+    (b"\x1aE\xdf\xa3", "video/webm"),
+];
+
+// Types for common static files, tried before any sniffing
+const EXTENSION_TYPES: &[(&str, &str)] = &[
+    ("html", "text/html; charset=utf-8"),
+    ("htm", "text/html; charset=utf-8"),
+    ("js", "text/javascript; charset=utf-8"),
+    ("mjs", "text/javascript; charset=utf-8"),
+    ("json", "application/json"),
+    ("map", "application/json"),
+    ("css", "text/css; charset=utf-8"),
+    ("txt", "text/plain; charset=utf-8"),
+    ("csv", "text/csv; charset=utf-8"),
+    ("xml", "application/xml"),
+    ("svg", "image/svg+xml"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("ico", "image/x-icon"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("wasm", "application/wasm"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+    ("mp3", "audio/mpeg"),
+    ("ogg", "audio/ogg"),
+];
+
+// Only the start of the body is ever inspected
+const PEEK_LEN: usize = 512;
+
+// Types S3 assigns when an upload didn't say what it was
+pub fn is_generic(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    essence.eq_ignore_ascii_case("binary/octet-stream") || essence.eq_ignore_ascii_case("application/octet-stream")
+}
+
+// The usual type for a key's extension, matched case-insensitively
+pub fn for_extension(key: &str) -> Option<&'static str> {
+    let filename = key.rsplit('/').next().unwrap_or_default();
+    let (_, extension) = filename.rsplit_once('.')?;
+    EXTENSION_TYPES
+        .iter()
+        .find(|(known, _)| known.eq_ignore_ascii_case(extension))
+        .map(|(_, content_type)| *content_type)
+}
+
+// Guess a type from magic numbers, falling back to plain text for bodies without control bytes.
+// Markup is never guessed, so an upload can't become HTML that runs scripts.
+pub fn content_type(body: &[u8]) -> Option<&'static str> {
+    let peek = &body[..body.len().min(PEEK_LEN)];
+    if let Some((_, content_type)) = SIGNATURES.iter().find(|(magic, _)| peek.starts_with(magic)) {
+        return Some(content_type);
+    }
+    if peek.len() >= 12 && &peek[..4] == b"RIFF" && &peek[8..12] == b"WEBP" {
+        return Some("image/webp");
+    }
+    if peek.len() >= 12 && &peek[4..8] == b"ftyp" {
+        return Some(match &peek[8..12] {
+            b"avif" | b"avis" => "image/avif",
+            _ => "video/mp4",
+        });
+    }
+
+    // The peek may end partway through a character
+    let text = match std::str::from_utf8(peek) {
+        Ok(text) => text,
+        Err(err) if err.error_len().is_none() => std::str::from_utf8(&peek[..err.valid_up_to()]).ok()?,
+        Err(_) => return None,
+    };
+    let is_text = !text.is_empty() && !text.chars().any(|c| c.is_control() && !matches!(c, '\n' | '\r' | '\t' | '\x0c'));
+    is_text.then_some("text/plain; charset=utf-8")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniffs_png_signatures() {
+        let png = b"\x89PNG\r\n\x1a\n\x00\x00\x00\rIHDR";
+        assert_eq!(content_type(png), Some("image/png"));
+        assert_eq!(content_type(b"RIFF\x00\x00\x00\x00WEBPVP8 "), Some("image/webp"));
+    }
+
+    #[test]
+    fn sniffs_plain_text_but_never_markup() {
+        assert_eq!(content_type(b"hello\nworld\n"), Some("text/plain; charset=utf-8"));
+        assert_eq!(content_type(b"<html><script>alert(1)</script>"), Some("text/plain; charset=utf-8"));
+        // Cut off in the middle of `é`
+        assert_eq!(content_type(&"é".repeat(PEEK_LEN)[..].as_bytes()[..PEEK_LEN + 1]), Some("text/plain; charset=utf-8"));
+        assert_eq!(content_type(b"\x00\x01\x02binary"), None);
+        assert_eq!(content_type(b""), None);
+    }
+
+    #[test]
+    fn guesses_types_from_extensions() {
+        assert_eq!(for_extension("alice/app.JS"), Some("text/javascript; charset=utf-8"));
+        assert_eq!(for_extension("alice/photo.png"), Some("image/png"));
+        assert_eq!(for_extension("alice/v1.2/LICENSE"), None);
+        assert_eq!(for_extension("alice/archive.tar.unknown"), None);
+    }
+
+    #[test]
+    fn only_octet_streams_are_generic() {
+        assert!(is_generic("binary/octet-stream"));
+        assert!(is_generic("Application/Octet-Stream; charset=binary"));
+        assert!(!is_generic("text/html"));
+    }
+}
@@ -0,0 +1,282 @@
+use crate::config::Config;
+use aws_sdk_s3::config::http::HttpResponse;
+use aws_sdk_s3::config::timeout::TimeoutConfig;
+use aws_sdk_s3::error::SdkError;
+use aws_sdk_s3::operation::get_object::{GetObjectError, GetObjectOutput};
+use aws_sdk_s3::operation::head_object::{HeadObjectError, HeadObjectOutput};
+use aws_sdk_s3::types::{ChecksumMode, RequestPayer};
+use aws_sdk_s3::Client as S3Client;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Instant;
+
+pub type GetObjectResult = Result<GetObjectOutput, SdkError<GetObjectError, HttpResponse>>;
+pub type HeadObjectResult = Result<HeadObjectOutput, SdkError<HeadObjectError, HttpResponse>>;
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+// A single GetObject or HeadObject call. HeadObject ignores the range.
+#[derive(Clone, Copy)]
+pub struct ObjectRequest<'a> {
+    pub key: &'a str,
+    pub version: Option<&'a str>,
+    pub range: Option<&'a str>,
+    // When the call is aborted, from REQUEST_TIMEOUT_MS
+    pub deadline: Option<Instant>,
+}
+
+// Where objects are read from: the bucket, or an in-memory map in tests
+pub trait ObjectSource: Send + Sync {
+    fn get_object<'a>(&'a self, request: ObjectRequest<'a>) -> BoxFuture<'a, GetObjectResult>;
+    fn head_object<'a>(&'a self, request: ObjectRequest<'a>) -> BoxFuture<'a, HeadObjectResult>;
+}
+
+// The configured bucket, read with the same checksum mode and requester-pays setting on every call
+pub struct S3Source {
+    client: S3Client,
+    bucket_name: String,
+    verify_checksums: bool,
+    request_payer: bool,
+}
+
+impl S3Source {
+    pub fn new(client: S3Client, config: &Config) -> Self {
+        Self {
+            client,
+            bucket_name: config.bucket_name.clone(),
+            verify_checksums: config.verify_checksums,
+            request_payer: config.request_payer,
+        }
+    }
+}
+
+impl ObjectSource for S3Source {
+    fn get_object<'a>(&'a self, request: ObjectRequest<'a>) -> BoxFuture<'a, GetObjectResult> {
+        Box::pin(async move {
+            let mut builder = self
+                .client
+                .get_object()
+                .bucket(&self.bucket_name)
+                .key(request.key)
+                .set_version_id(request.version.map(str::to_string))
+                .set_range(request.range.map(str::to_string));
+            if self.verify_checksums {
+                builder = builder.checksum_mode(ChecksumMode::Enabled);
+            }
+            if self.request_payer {
+                builder = builder.request_payer(RequestPayer::Requester);
+            }
+            match request.deadline {
+                Some(deadline) => builder.customize().config_override(operation_timeout(deadline)).send().await,
+                None => builder.send().await,
+            }
+        })
+    }
+
+    fn head_object<'a>(&'a self, request: ObjectRequest<'a>) -> BoxFuture<'a, HeadObjectResult> {
+        Box::pin(async move {
+            let mut builder = self
+                .client
+                .head_object()
+                .bucket(&self.bucket_name)
+                .key(request.key)
+                .set_version_id(request.version.map(str::to_string));
+            if self.verify_checksums {
+                builder = builder.checksum_mode(ChecksumMode::Enabled);
+            }
+            if self.request_payer {
+                builder = builder.request_payer(RequestPayer::Requester);
+            }
+            match request.deadline {
+                Some(deadline) => builder.customize().config_override(operation_timeout(deadline)).send().await,
+                None => builder.send().await,
+            }
+        })
+    }
+}
+
+// Have the SDK abort an S3 request itself at the deadline, so its connection is freed promptly
+pub fn operation_timeout(deadline: Instant) -> aws_sdk_s3::config::Builder {
+    let timeout = deadline.saturating_duration_since(Instant::now());
+    aws_sdk_s3::config::Builder::new().timeout_config(TimeoutConfig::builder().operation_timeout(timeout).build())
+}
+
+#[cfg(test)]
+pub use mock::{MockObject, MockObjectSource};
+
+#[cfg(test)]
+mod mock {
+    use super::*;
+    use aws_sdk_s3::error::ErrorMetadata;
+    use aws_sdk_s3::primitives::{ByteStream, SdkBody};
+    use aws_sdk_s3::types::error::{NoSuchKey, NotFound};
+    use bytes::Bytes;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    #[derive(Clone, Default)]
+    pub struct MockObject {
+        pub body: Bytes,
+        pub content_type: Option<String>,
+        pub e_tag: Option<String>,
+        pub cache_control: Option<String>,
+        pub website_redirect_location: Option<String>,
+        pub last_modified: Option<aws_sdk_s3::primitives::DateTime>,
+    }
+
+    impl MockObject {
+        pub fn new(content_type: &str, body: &str) -> Self {
+            Self {
+                body: Bytes::from(body.to_string()),
+                content_type: Some(content_type.to_string()),
+                e_tag: Some(format!("\"{:x}\"", body.len())),
+                ..Default::default()
+            }
+        }
+    }
+
+    // Objects by key, answering like S3 does for missing keys and unsatisfiable ranges. Older
+    // versions are stored as `{key}?version={id}`.
+    #[derive(Default)]
+    pub struct MockObjectSource {
+        objects: Mutex<HashMap<String, MockObject>>,
+        // Every key asked for, in order, with `HEAD ` before metadata requests and any version and
+        // range after the key
+        requests: Mutex<Vec<String>>,
+        // Upstream status returned for every call instead of an object, e.g. 500
+        failure: Mutex<Option<u16>>,
+        // Upstream statuses returned instead of particular objects, e.g. 403 for a private one
+        key_failures: Mutex<HashMap<String, u16>>,
+        // How long every call takes
+        latency: Mutex<Duration>,
+    }
+
+    impl MockObjectSource {
+        // Objects as `(key, content type, body)`
+        pub fn with(objects: &[(&str, &str, &str)]) -> Self {
+            let source = Self::default();
+            for (key, content_type, body) in objects {
+                source.insert(key, MockObject::new(content_type, body));
+            }
+            source
+        }
+
+        pub fn insert(&self, key: &str, object: MockObject) {
+            self.objects.lock().unwrap().insert(key.to_string(), object);
+        }
+
+        pub fn fail_with(&self, status: Option<u16>) {
+            *self.failure.lock().unwrap() = status;
+        }
+
+        pub fn fail_key_with(&self, key: &str, status: u16) {
+            self.key_failures.lock().unwrap().insert(key.to_string(), status);
+        }
+
+        pub fn delay_by(&self, latency: Duration) {
+            *self.latency.lock().unwrap() = latency;
+        }
+
+        pub fn requests(&self) -> Vec<String> {
+            self.requests.lock().unwrap().clone()
+        }
+
+        // The object, or the raw status S3 would answer with instead
+        async fn lookup(&self, request: &ObjectRequest<'_>, logged: String) -> Result<MockObject, u16> {
+            let key = match request.version {
+                Some(version) => format!("{}?version={}", request.key, version),
+                None => request.key.to_string(),
+            };
+            let logged = logged.replacen(request.key, &key, 1);
+            self.requests.lock().unwrap().push(logged);
+            let latency = *self.latency.lock().unwrap();
+            tokio::time::sleep(latency).await;
+            if let Some(status) = *self.failure.lock().unwrap() {
+                return Err(status);
+            }
+            if let Some(&status) = self.key_failures.lock().unwrap().get(&key) {
+                return Err(status);
+            }
+            self.objects.lock().unwrap().get(&key).cloned().ok_or(404)
+        }
+    }
+
+    fn raw(status: u16) -> HttpResponse {
+        HttpResponse::new(status.try_into().unwrap(), SdkBody::empty())
+    }
+
+    fn error_metadata(status: u16) -> ErrorMetadata {
+        let code = match status {
+            403 => "AccessDenied",
+            416 => "InvalidRange",
+            503 => "SlowDown",
+            _ => "InternalError",
+        };
+        ErrorMetadata::builder().code(code).build()
+    }
+
+    impl ObjectSource for MockObjectSource {
+        fn get_object<'a>(&'a self, request: ObjectRequest<'a>) -> BoxFuture<'a, GetObjectResult> {
+            Box::pin(async move {
+                let logged = match request.range {
+                    Some(range) => format!("{} {}", request.key, range),
+                    None => request.key.to_string(),
+                };
+                let object = match self.lookup(&request, logged).await {
+                    Ok(object) => object,
+                    Err(404) => {
+                        let err = GetObjectError::NoSuchKey(NoSuchKey::builder().build());
+                        return Err(SdkError::service_error(err, raw(404)));
+                    }
+                    Err(status) => {
+                        return Err(SdkError::service_error(GetObjectError::generic(error_metadata(status)), raw(status)))
+                    }
+                };
+                let total = object.body.len() as u64;
+                let (body, content_range) = match request.range.map(|range| crate::range::parse(range, total)) {
+                    Some(Some(Ok((start, end)))) => (
+                        object.body.slice(start as usize..=end as usize),
+                        Some(format!("bytes {}-{}/{}", start, end, total)),
+                    ),
+                    Some(Some(Err(()))) => {
+                        return Err(SdkError::service_error(GetObjectError::generic(error_metadata(416)), raw(416)))
+                    }
+                    _ => (object.body.clone(), None),
+                };
+                Ok(GetObjectOutput::builder()
+                    .content_length(body.len() as i64)
+                    .set_content_range(content_range)
+                    .set_content_type(object.content_type)
+                    .set_e_tag(object.e_tag)
+                    .set_cache_control(object.cache_control)
+                    .set_website_redirect_location(object.website_redirect_location)
+                    .set_last_modified(object.last_modified)
+                    .body(ByteStream::from(body))
+                    .build())
+            })
+        }
+
+        fn head_object<'a>(&'a self, request: ObjectRequest<'a>) -> BoxFuture<'a, HeadObjectResult> {
+            Box::pin(async move {
+                let object = match self.lookup(&request, format!("HEAD {}", request.key)).await {
+                    Ok(object) => object,
+                    Err(404) => {
+                        let err = HeadObjectError::NotFound(NotFound::builder().build());
+                        return Err(SdkError::service_error(err, raw(404)));
+                    }
+                    Err(status) => {
+                        return Err(SdkError::service_error(HeadObjectError::generic(error_metadata(status)), raw(status)))
+                    }
+                };
+                Ok(HeadObjectOutput::builder()
+                    .content_length(object.body.len() as i64)
+                    .set_content_type(object.content_type)
+                    .set_e_tag(object.e_tag)
+                    .set_cache_control(object.cache_control)
+                    .set_website_redirect_location(object.website_redirect_location)
+                    .set_last_modified(object.last_modified)
+                    .build())
+            })
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use crate::config::Config;
+use crate::metrics::METRICS;
+use crate::AppState;
+use serde::Serialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+// Runtime diagnostics for `GET /_status`, refreshed in the background so requests never read `/proc`
+pub struct SystemStatus {
+    started: Instant,
+    // SHA-256 of the effective configuration as `/_config` shows it, with secrets redacted
+    config_hash: String,
+    snapshot: Mutex<Option<StatusSnapshot>>,
+    // S3 calls and errors at the previous refresh, to rate errors over the last interval
+    last_s3_counts: Mutex<(u64, u64)>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct StatusSnapshot {
+    uptime_secs: u64,
+    total_requests: u64,
+    active_connections: i64,
+    // Percent over the last minute
+    cache_hit_rate: Option<f64>,
+    // Percent of S3 calls that failed upstream since the previous refresh
+    s3_error_rate: Option<f64>,
+    // Numbers where the platform provides them, `"N/A"` elsewhere
+    memory_rss_bytes: Value,
+    tokio_worker_count: usize,
+    open_file_descriptors: Value,
+    effective_config_hash: String,
+}
+
+impl SystemStatus {
+    pub fn new(config: &Config) -> Self {
+        let config_json = serde_json::to_string(config).unwrap_or_default();
+        let config_hash = Sha256::digest(config_json.as_bytes())
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        Self {
+            started: Instant::now(),
+            config_hash,
+            snapshot: Mutex::new(None),
+            last_s3_counts: Mutex::new((0, 0)),
+        }
+    }
+
+    // The latest snapshot, taken when first asked for if the refresh task hasn't run yet
+    pub fn snapshot(&self, state: &AppState) -> StatusSnapshot {
+        if let Some(snapshot) = self.snapshot.lock().unwrap().clone() {
+            return snapshot;
+        }
+        self.update(state)
+    }
+
+    fn update(&self, state: &AppState) -> StatusSnapshot {
+        let (requests, errors) = (METRICS.s3_requests.get(), METRICS.s3_errors.get());
+        let s3_error_rate = {
+            let mut last = self.last_s3_counts.lock().unwrap();
+            let (last_requests, last_errors) = std::mem::replace(&mut *last, (requests, errors));
+            let requests = requests.saturating_sub(last_requests);
+            (requests > 0).then(|| errors.saturating_sub(last_errors) as f64 * 100.0 / requests as f64)
+        };
+
+        let snapshot = StatusSnapshot {
+            uptime_secs: self.started.elapsed().as_secs(),
+            total_requests: METRICS.requests.get(),
+            active_connections: METRICS.connections_active.get(),
+            cache_hit_rate: state.cache.hit_rate_percent(),
+            s3_error_rate,
+            memory_rss_bytes: or_not_available(memory_rss_bytes()),
+            tokio_worker_count: tokio::runtime::Handle::current().metrics().num_workers(),
+            open_file_descriptors: or_not_available(open_file_descriptors()),
+            effective_config_hash: self.config_hash.clone(),
+        };
+        *self.snapshot.lock().unwrap() = Some(snapshot.clone());
+        snapshot
+    }
+}
+
+// Take a new snapshot every REFRESH_INTERVAL
+pub async fn refresh(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(REFRESH_INTERVAL);
+    loop {
+        interval.tick().await;
+        state.status.update(&state);
+    }
+}
+
+fn or_not_available(value: Option<u64>) -> Value {
+    value.map_or_else(|| Value::from("N/A"), Value::from)
+}
+
+// `VmRSS` from `/proc/self/status`, which is reported in kB
+fn memory_rss_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kilobytes: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kilobytes * 1024)
+}
+
+fn open_file_descriptors() -> Option<u64> {
+    Some(std::fs::read_dir("/proc/self/fd").ok()?.count() as u64)
+}
@@ -0,0 +1,97 @@
+use hyper::HeaderMap;
+
+// W3C trace context, continued from an incoming `traceparent` or started fresh
+pub struct TraceContext {
+    pub trace_id: String,
+    // Span ID of the caller (e.g. the CDN edge), if the trace was continued
+    pub parent_id: Option<String>,
+    pub tracestate: Option<String>,
+}
+
+impl TraceContext {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let tracestate = headers
+            .get("tracestate")
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        match headers.get("traceparent").and_then(|v| v.to_str().ok()).and_then(parse) {
+            Some((trace_id, parent_id)) => Self {
+                trace_id: trace_id.to_string(),
+                parent_id: Some(parent_id.to_string()),
+                tracestate,
+            },
+            // `tracestate` is meaningless without the `traceparent` it belongs to
+            None => Self {
+                trace_id: format!("{:016x}{:016x}", fastrand::u64(1..), fastrand::u64(..)),
+                parent_id: None,
+                tracestate: None,
+            },
+        }
+    }
+}
+
+// Parse `{version}-{trace-id}-{parent-id}-{flags}`, rejecting malformed or all-zero IDs
+fn parse(traceparent: &str) -> Option<(&str, &str)> {
+    let mut parts = traceparent.trim().split('-');
+    let version = parts.next()?;
+    let trace_id = parts.next()?;
+    let parent_id = parts.next()?;
+    let flags = parts.next()?;
+    // Later versions may append fields, but version 00 has exactly four
+    if version == "ff" || (version == "00" && parts.next().is_some()) {
+        return None;
+    }
+
+    let is_hex = |s: &str, len: usize| s.len() == len && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'));
+    let is_zero = |s: &str| s.bytes().all(|b| b == b'0');
+    if !is_hex(version, 2) || !is_hex(flags, 2) {
+        return None;
+    }
+    if !is_hex(trace_id, 32) || is_zero(trace_id) || !is_hex(parent_id, 16) || is_zero(parent_id) {
+        return None;
+    }
+    Some((trace_id, parent_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn parses_valid_traceparents_only() {
+        assert_eq!(parse(TRACEPARENT), Some(("4bf92f3577b34da6a3ce929d0e0e4736", "00f067aa0ba902b7")));
+        // A later version may carry more fields
+        assert!(parse("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra").is_some());
+        for invalid in [
+            "",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+        ] {
+            assert_eq!(parse(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn continues_an_incoming_trace_or_starts_one() {
+        let mut headers = HeaderMap::new();
+        headers.insert("traceparent", HeaderValue::from_static(TRACEPARENT));
+        headers.insert("tracestate", HeaderValue::from_static("edge=1"));
+        let trace = TraceContext::from_headers(&headers);
+        assert_eq!(trace.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(trace.parent_id.as_deref(), Some("00f067aa0ba902b7"));
+        assert_eq!(trace.tracestate.as_deref(), Some("edge=1"));
+
+        headers.insert("traceparent", HeaderValue::from_static("garbage"));
+        let trace = TraceContext::from_headers(&headers);
+        assert_eq!(trace.trace_id.len(), 32);
+        assert_ne!(trace.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!((trace.parent_id, trace.tracestate), (None, None));
+    }
+}
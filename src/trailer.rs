@@ -0,0 +1,78 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::body::{Body, Frame, SizeHint};
+use hyper::header::HeaderValue;
+use hyper::{HeaderMap, Method, Request, Response};
+use sha2::{Digest, Sha256};
+use std::convert::Infallible;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+const TRAILER: &str = "x-content-sha256";
+
+// A response body that can end with an `X-Content-SHA256` trailer, hashed as the body is sent
+pub struct DigestBody {
+    data: Option<Bytes>,
+    // `None` once sent, or when no trailer was asked for
+    hasher: Option<Sha256>,
+}
+
+impl Body for DigestBody {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_frame(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, Infallible>>> {
+        if let Some(data) = self.data.take() {
+            if let Some(hasher) = &mut self.hasher {
+                hasher.update(&data);
+            }
+            return Poll::Ready(Some(Ok(Frame::data(data))));
+        }
+        let Some(hasher) = self.hasher.take() else {
+            return Poll::Ready(None);
+        };
+        let mut trailers = HeaderMap::new();
+        let digest = STANDARD.encode(hasher.finalize());
+        trailers.insert(TRAILER, HeaderValue::from_str(&digest).unwrap());
+        Poll::Ready(Some(Ok(Frame::trailers(trailers))))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.data.is_none() && self.hasher.is_none()
+    }
+
+    // A body with a trailer has no known length, so HTTP/1.1 sends it chunked
+    fn size_hint(&self) -> SizeHint {
+        match (&self.data, &self.hasher) {
+            (_, Some(_)) => SizeHint::default(),
+            (Some(data), None) => SizeHint::with_exact(data.len() as u64),
+            (None, None) => SizeHint::with_exact(0),
+        }
+    }
+}
+
+// Whether a client can receive trailers. HTTP/1.1 only delivers them to clients sending `TE: trailers`.
+pub fn accepted<B>(req: &Request<B>) -> bool {
+    req.method() == Method::GET
+        && req
+            .headers()
+            .get_all("te")
+            .iter()
+            .filter_map(|te| te.to_str().ok())
+            .flat_map(|te| te.split(','))
+            .any(|te| te.trim().eq_ignore_ascii_case("trailers"))
+}
+
+// Declare and attach the trailer to successful responses with a body
+pub async fn apply(response: Response<Full<Bytes>>, enabled: bool) -> Response<DigestBody> {
+    let (mut parts, body) = response.into_parts();
+    let data = body.collect().await.unwrap().to_bytes();
+    let hasher = (enabled && parts.status.is_success() && !data.is_empty()).then(Sha256::new);
+    if hasher.is_some() {
+        parts.headers.insert("trailer", HeaderValue::from_static(TRAILER));
+        parts.headers.remove("content-length");
+    }
+    Response::from_parts(parts, DigestBody { data: Some(data), hasher })
+}